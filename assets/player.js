@@ -78,6 +78,13 @@ class Player extends HTMLElement {
     this.timeupdate.subscribe(currentTime => {
       this.progressBar.setAttribute('value', currentTime / this.audio.duration * 100);
     });
+    // Seek by clicking on the progress bar. The server honors range requests so
+    // the browser fetches the song from the new position.
+    this.progressBar.addEventListener('click', event => {
+      if (!isFinite(this.audio.duration)) return;
+      const rect = this.progressBar.getBoundingClientRect();
+      this.audio.currentTime = (event.clientX - rect.left) / rect.width * this.audio.duration;
+    });
   }
 
   async play(id) {
@@ -39,9 +39,9 @@ async function hookSearchInput() {
   searchObservable.subscribe(async event => {
     const value = event.target.value;
     if (value.length >= 3) {
-      const results = await fetch(`/search?term=${value}`);
+      const results = await fetch(`/search?term=${encodeURIComponent(value)}`);
       if (((results.status / 100) | 0) === 2) { // Check this is a 2XX code
-        const songs = await results.json();
+        const songs = (await results.json()).items;
         const songsElements = songs.map(song => {
           const songElement = document.createElement('div');
           songElement.classList.add('song');
@@ -1,4 +1,4 @@
-// This file is a big mess but it works, at least for now.
+// Derives the persistence of a struct in an SQLite table, one column per field.
 
 // To debug the macro generation
 // cargo rustc --profile=check -- -Zunpretty=expanded
@@ -7,186 +7,491 @@ use proc_macro::TokenStream;
 use quote::quote;
 use syn::{parse_macro_input, Data, DataStruct, DeriveInput, Fields, PathArguments};
 
-#[proc_macro_derive(FieldList)]
+// How a field is stored in SQLite
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+  Integer,
+  Float,
+  // Stored as 0 or 1
+  Boolean,
+  Text,
+  // Vec<u8>
+  Blob,
+  // SystemTime, stored as seconds since epoch
+  Timestamp,
+}
+
+impl Kind {
+  fn sql_type(&self) -> &'static str {
+    match self {
+      Kind::Integer | Kind::Boolean | Kind::Timestamp => "INTEGER",
+      Kind::Float => "REAL",
+      Kind::Text => "TEXT",
+      Kind::Blob => "BLOB",
+    }
+  }
+}
+
+// A field stored in a column of the table
+struct Column {
+  ident: syn::Ident,
+  name: String,
+  kind: Kind,
+  // The type of the value, without the Option
+  value_type: syn::Type,
+  optional: bool,
+  primary_key: bool,
+  index: bool,
+  unique: bool,
+  default: Option<syn::Lit>,
+}
+
+// The last segment of a type path, to deal with std::blabla::what::Option
+fn last_segment(ty: &syn::Type) -> Option<&syn::PathSegment> {
+  match ty {
+    syn::Type::Path(typepath) if typepath.qself.is_none() => typepath.path.segments.last(),
+    _ => None,
+  }
+}
+
+// The first generic argument of a type, the T of Option<T>
+fn type_argument(segment: &syn::PathSegment) -> Option<&syn::Type> {
+  // All these shenanigans to fetch the Option's subtype
+  let PathArguments::AngleBracketed(arguments) = &segment.arguments else {
+    return None;
+  };
+  match arguments.args.first() {
+    Some(syn::GenericArgument::Type(sub_type)) => Some(sub_type),
+    _ => None,
+  }
+}
+
+// The storage of a type and the type of its value (the T of an Option<T>), or
+// None if it is not supported
+fn classify(ty: &syn::Type) -> Option<(Kind, syn::Type, bool)> {
+  let segment = last_segment(ty)?;
+  let kind = match segment.ident.to_string().as_str() {
+    // 128 bits integers are not supported as SQLite integers are 64 bits
+    "i8" | "i16" | "i32" | "i64" | "isize" | "u8" | "u16" | "u32" | "u64" | "usize" => {
+      Kind::Integer
+    }
+    "f32" | "f64" => Kind::Float,
+    "bool" => Kind::Boolean,
+    "String" => Kind::Text,
+    "SystemTime" => Kind::Timestamp,
+    "Vec" => {
+      let item = last_segment(type_argument(segment)?)?;
+      if item.ident != "u8" {
+        return None;
+      }
+      Kind::Blob
+    }
+    "Option" => {
+      return match classify(type_argument(segment)?)? {
+        (_, _, true) => None,
+        (kind, value_type, false) => Some((kind, value_type, true)),
+      };
+    }
+    _ => return None,
+  };
+  Some((kind, ty.clone(), false))
+}
+
+// The column of a field, None if the field is skipped
+fn parse_field(field: &syn::Field) -> syn::Result<Option<Column>> {
+  let ident = field.ident.clone().unwrap();
+  let mut name = ident.to_string();
+  let (mut skip, mut primary_key, mut index, mut unique, mut default) =
+    (false, false, false, false, None);
+  for attr in field
+    .attrs
+    .iter()
+    .filter(|attr| attr.path().is_ident("field_list"))
+  {
+    attr.parse_nested_meta(|meta| {
+      if meta.path.is_ident("skip") {
+        skip = true;
+      } else if meta.path.is_ident("primary_key") {
+        primary_key = true;
+      } else if meta.path.is_ident("index") {
+        index = true;
+      } else if meta.path.is_ident("unique") {
+        unique = true;
+      } else if meta.path.is_ident("rename") {
+        name = meta.value()?.parse::<syn::LitStr>()?.value();
+      } else if meta.path.is_ident("default") {
+        default = Some(meta.value()?.parse::<syn::Lit>()?);
+      } else {
+        return Err(meta.error(
+          "unsupported field_list attribute, expected one of `primary_key`, `rename`, `skip`, \
+           `index`, `unique` or `default`",
+        ));
+      }
+      Ok(())
+    })?;
+  }
+  if skip {
+    if primary_key || index || unique || default.is_some() {
+      return Err(syn::Error::new_spanned(
+        field,
+        "a skipped field cannot have other field_list attributes",
+      ));
+    }
+    return Ok(None);
+  }
+  let Some((kind, value_type, optional)) = classify(&field.ty) else {
+    return Err(syn::Error::new_spanned(
+      &field.ty,
+      "unsupported type, expected an integer, a float, bool, String, Vec<u8>, SystemTime or an \
+       Option of those, or use #[field_list(skip)]",
+    ));
+  };
+  if let Some(default) = &default {
+    if matches!(kind, Kind::Blob | Kind::Timestamp) {
+      return Err(syn::Error::new_spanned(
+        default,
+        "default values are not supported for this type",
+      ));
+    }
+  }
+  Ok(Some(Column {
+    ident,
+    name,
+    kind,
+    value_type,
+    optional,
+    primary_key,
+    index,
+    unique,
+    default,
+  }))
+}
+
+// A literal as it appears in SQL
+fn sql_literal(lit: &syn::Lit) -> syn::Result<String> {
+  match lit {
+    syn::Lit::Str(s) => Ok(format!("'{}'", s.value().replace('\'', "''"))),
+    syn::Lit::Int(i) => Ok(i.base10_digits().to_string()),
+    syn::Lit::Float(f) => Ok(f.base10_digits().to_string()),
+    syn::Lit::Bool(b) => Ok(if b.value { "1" } else { "0" }.to_string()),
+    _ => Err(syn::Error::new_spanned(lit, "unsupported default value")),
+  }
+}
+
+// The definition of the column in CREATE TABLE
+fn column_definition(column: &Column) -> syn::Result<String> {
+  let mut definition = format!("{} {}", column.name, column.kind.sql_type());
+  if !column.optional {
+    definition.push_str(" NOT NULL");
+  }
+  if column.primary_key {
+    definition.push_str(" PRIMARY KEY");
+  }
+  if column.unique {
+    definition.push_str(" UNIQUE");
+  }
+  if let Some(default) = &column.default {
+    definition.push_str(&format!(" DEFAULT {}", sql_literal(default)?));
+  }
+  Ok(definition)
+}
+
+// The code converting the field to an sqlite::Value, bound to the prepared
+// statements
+fn to_value(column: &Column) -> proc_macro2::TokenStream {
+  let ident = &column.ident;
+  let convert = |value: proc_macro2::TokenStream| match column.kind {
+    Kind::Integer | Kind::Boolean => quote!(::sqlite::Value::Integer(#value as i64)),
+    Kind::Float => quote!(::sqlite::Value::Float(#value as f64)),
+    Kind::Text => quote!(::sqlite::Value::String(#value.clone())),
+    Kind::Blob => quote!(::sqlite::Value::Binary(#value.clone())),
+    Kind::Timestamp => quote!(::sqlite::Value::Integer(
+      match #value.duration_since(::std::time::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+      }
+    )),
+  };
+  if column.optional {
+    let convert = convert(quote!((*v)));
+    quote!(self.#ident.as_ref().map_or(::sqlite::Value::Null, |v| #convert))
+  } else {
+    convert(quote!(self.#ident))
+  }
+}
+
+// The code converting the value read from sqlite to the field, a Result whose
+// error is a row error
+fn from_value(column: &Column, error_name: &syn::Ident) -> proc_macro2::TokenStream {
+  let name = &column.name;
+  let value_type = &column.value_type;
+  // An Option, None if the value has not the expected type
+  let convert = match column.kind {
+    Kind::Integer => quote!(match value {
+      ::sqlite::Value::Integer(i) => <#value_type>::try_from(*i).ok(),
+      ::sqlite::Value::String(s) => s.parse::<#value_type>().ok(),
+      _ => None,
+    }),
+    Kind::Float => quote!(match value {
+      ::sqlite::Value::Float(f) => Some(*f as #value_type),
+      ::sqlite::Value::Integer(i) => Some(*i as #value_type),
+      ::sqlite::Value::String(s) => s.parse::<#value_type>().ok(),
+      _ => None,
+    }),
+    Kind::Boolean => quote!(match value {
+      ::sqlite::Value::Integer(i) => Some(*i != 0),
+      _ => None,
+    }),
+    Kind::Text => quote!(match value {
+      ::sqlite::Value::String(s) => Some(s.clone()),
+      ::sqlite::Value::Integer(i) => Some(i.to_string()),
+      ::sqlite::Value::Float(f) => Some(f.to_string()),
+      _ => None,
+    }),
+    Kind::Blob => quote!(match value {
+      ::sqlite::Value::Binary(b) => Some(b.clone()),
+      _ => None,
+    }),
+    Kind::Timestamp => quote!(match value {
+      ::sqlite::Value::Integer(i) if *i >= 0 => {
+        Some(::std::time::UNIX_EPOCH + ::std::time::Duration::from_secs(i.unsigned_abs()))
+      }
+      ::sqlite::Value::Integer(i) => {
+        Some(::std::time::UNIX_EPOCH - ::std::time::Duration::from_secs(i.unsigned_abs()))
+      }
+      _ => None,
+    }),
+  };
+  let error = |value: proc_macro2::TokenStream| {
+    quote!(#error_name {
+      table: Self::TABLE,
+      column: #name,
+      value: #value,
+    })
+  };
+  let invalid = error(quote!(Some(value.clone())));
+  let absent = match (&column.default, column.optional) {
+    (_, true) => quote!(Ok(None)),
+    (Some(syn::Lit::Str(default)), false) => quote!(Ok(#default.to_string())),
+    (Some(default), false) => quote!(Ok(#default)),
+    (None, false) => {
+      let missing = error(quote!(hashmap.get(#name).cloned()));
+      quote!(Err(#missing))
+    }
+  };
+  let present = if column.optional { quote!(#convert.map(Some)) } else { convert };
+  quote!(match hashmap.get(#name) {
+    None | Some(::sqlite::Value::Null) => #absent,
+    Some(value) => #present.ok_or_else(|| #invalid),
+  })
+}
+
+// The table is named after the struct (`Song` is stored in `songs`) unless
+// specified with `#[field_list(table = "...")]`. Fields can be annotated with:
+// - `#[field_list(primary_key)]`, the key used by `add` and `get`, `id` by
+//   default
+// - `#[field_list(rename = "...")]`, the name of the column if not the field's
+// - `#[field_list(skip)]`, not stored, set to its default value when read
+// - `#[field_list(index)]` and `#[field_list(unique)]`, an index or a UNIQUE
+//   constraint on the column in the DDL of create_table()
+// - `#[field_list(default = ...)]`, the DEFAULT of the column, and the value of
+//   the field when its column is missing or NULL
+// Rows which cannot be converted to the struct are reported with a
+// `<Struct>RowError` naming the table, column and value.
+// Supported types are integers up to 64 bits, f32, f64, bool, String, Vec<u8>
+// (a BLOB), SystemTime (seconds since epoch) and Options of those. Any other
+// type is a compile error.
+// The generated code only uses fully qualified paths but expects the sqlite,
+// anyhow and tracing crates to be dependencies of the crate using it.
+#[proc_macro_derive(FieldList, attributes(field_list))]
 pub fn derive_field_list(input: TokenStream) -> TokenStream {
   let input = parse_macro_input!(input as DeriveInput);
+  match derive(&input) {
+    Ok(tokens) => tokens.into(),
+    Err(e) => e.to_compile_error().into(),
+  }
+}
+
+fn derive(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+  let struct_name = &input.ident;
+
+  let mut table_name = None;
+  for attr in input
+    .attrs
+    .iter()
+    .filter(|attr| attr.path().is_ident("field_list"))
+  {
+    attr.parse_nested_meta(|meta| {
+      if meta.path.is_ident("table") {
+        table_name = Some(meta.value()?.parse::<syn::LitStr>()?.value());
+        Ok(())
+      } else {
+        Err(meta.error("unsupported field_list attribute, expected `table`"))
+      }
+    })?;
+  }
+  let table_name =
+    table_name.unwrap_or_else(|| format!("{}s", struct_name.to_string().to_lowercase()));
 
   let fields = match &input.data {
     Data::Struct(DataStruct {
       fields: Fields::Named(fields),
       ..
     }) => &fields.named,
-    _ => panic!("expected a struct with named fields"),
+    _ => return Err(syn::Error::new_spanned(input, "expected a struct with named fields")),
   };
-  let field_name = fields.iter().map(|field| &field.ident);
-  let field_type = fields.iter().map(|field| &field.ty);
+  let mut columns = Vec::new();
+  let mut skipped = Vec::new();
+  // Report all the invalid fields at once
+  let mut errors: Option<syn::Error> = None;
+  for field in fields.iter() {
+    match parse_field(field) {
+      Ok(Some(column)) => columns.push(column),
+      Ok(None) => skipped.push(field.ident.clone().unwrap()),
+      Err(e) => match errors.as_mut() {
+        Some(errors) => errors.combine(e),
+        None => errors = Some(e),
+      },
+    }
+  }
+  if let Some(errors) = errors {
+    return Err(errors);
+  }
 
-  let struct_name = &input.ident;
+  // Without an explicit primary key, the id column is the key
+  if !columns.iter().any(|column| column.primary_key) {
+    if let Some(column) = columns.iter_mut().find(|column| column.ident == "id") {
+      column.primary_key = true;
+    }
+  }
+  let primary_keys = columns
+    .iter()
+    .filter(|column| column.primary_key)
+    .collect::<Vec<&Column>>();
+  let key = match primary_keys.as_slice() {
+    [key] => *key,
+    [] => {
+      return Err(syn::Error::new_spanned(
+        struct_name,
+        "FieldList requires an `id` field or a field with #[field_list(primary_key)]",
+      ))
+    }
+    _ => {
+      return Err(syn::Error::new_spanned(struct_name, "FieldList supports a single primary key"))
+    }
+  };
 
-  // This will contain the code to extracts the value in the proper format for SQL
-  // used in `fn value_list`:
-  // - quotes for String
-  // - no quotes for numbers
-  // - NULL for empty options
-  // Will silently ignore anything that is not an integer, a String, an Option
-  // on an integer or an Option on a String.
-  let values = fields.iter().filter_map(|field| {
-    let field_type = match field.ty {
-      syn::Type::Path(ref typepath) if typepath.qself.is_none() => &typepath.path,
-      _ => panic!("Could not extract type path"),
-    };
-    let type_name = field_type
-      .segments
-      .last() // last to deal with std::blabla::what::Option
-      .unwrap()
-      .ident
-      .to_string();
-    // eprintln!("typename {}", type_name);
-    match type_name.as_str() {
-      "i8" | "i16" | "i32" | "i64" | "i128" | "u8" | "u16" | "u32" | "u64" | "u128" => {
-        let field_name = field.ident.as_ref().unwrap();
-        Some(quote!(format!("{}", self.#field_name),))
-      }
-      "String" => {
-        let field_name = field.ident.as_ref().unwrap();
-        Some(quote!(format!("\"{}\"", self.#field_name),))
-      }
-      "Option<i8>" | "Option<i16>" | "Option<i32>" | "Option<i64>" | "Option<i128>"
-      | "Option<u8>" | "Option<u16>" | "Option<u32>" | "Option<u64>" | "Option<u128>" => {
-        let field_name = field.ident.as_ref().unwrap();
-        Some(quote!(self.#field_name.map_or("NULL", s => format!("{}", s)),))
-      }
-      "Option" => {
-        // All these shenanigans to fetch the Option's subtype
-        if let PathArguments::AngleBracketed(sub_type) = field_type.segments.last().unwrap().arguments.to_owned() {
-          if let syn::GenericArgument::Type(sub_type) = sub_type.args.first().unwrap() {
-            let sub_type = match sub_type {
-              syn::Type::Path(ref typepath) if typepath.qself.is_none() => &typepath.path,
-              _ => panic!("Could not extract sybtype path"),
-            };
-            let sub_type_name = sub_type.segments.last().unwrap().ident.to_string();
-              // eprintln!("sub typename {:?}", sub_type_name);
-              match sub_type_name.as_str() {
-                "i8" | "i16" | "i32" | "i64" | "i128" | "u8" | "u16" | "u32" | "u64" | "u128" => {
-                  let field_name = field.ident.as_ref().unwrap();
-                  Some(quote!(self.#field_name.as_ref().map_or("NULL".to_string(), |s| format!("{}", s)),))
-                }
-                "String" => {
-                  let field_name = field.ident.as_ref().unwrap();
-                  Some(quote!(self.#field_name.as_ref().map_or("NULL".to_string(), |s| format!("\"{}\"", s)),))
-                }
-                _ => None,
-              }
-            } else {
-              None
-          }
-        } else {
-          None
-        }
-      }
-      _ => None,
+  // The SQL is built once and for all here
+  let definitions = columns
+    .iter()
+    .map(column_definition)
+    .collect::<syn::Result<Vec<String>>>()?;
+  let indexes = columns
+    .iter()
+    .filter(|column| column.index)
+    .map(|column| {
+      format!(
+        "CREATE INDEX IF NOT EXISTS {table}_{column} ON {table}({column});",
+        table = table_name,
+        column = column.name,
+      )
+    })
+    .collect::<Vec<String>>();
+  let create_table = format!(
+    "CREATE TABLE IF NOT EXISTS {} ({});{}",
+    table_name,
+    definitions.join(", "),
+    indexes.join("")
+  );
+  let column_names = columns
+    .iter()
+    .map(|column| column.name.as_str())
+    .collect::<Vec<&str>>();
+  let key_name = &key.name;
+  let select_key = format!("SELECT {} FROM {} WHERE {} = ?;", key_name, table_name, key_name);
+  let select = format!("SELECT * FROM {} WHERE {} = ?;", table_name, key_name);
+  // Ordered by key for the pages to be stable
+  let select_all = format!("SELECT * FROM {} ORDER BY {} LIMIT ? OFFSET ?;", table_name, key_name);
+  let count = format!("SELECT COUNT(*) AS count FROM {};", table_name);
+  let update = format!(
+    "UPDATE {} SET {} WHERE {} = ?;",
+    table_name,
+    column_names
+      .iter()
+      .map(|name| format!("{} = ?", name))
+      .collect::<Vec<String>>()
+      .join(", "),
+    key_name
+  );
+  let insert = format!(
+    "INSERT INTO {} ({}) VALUES ({});",
+    table_name,
+    column_names.join(", "),
+    vec!["?"; column_names.len()].join(", ")
+  );
+
+  let column_type = columns.iter().map(|column| {
+    let ty = &column.value_type;
+    if column.optional {
+      quote!(Option<#ty>)
+    } else {
+      quote!(#ty)
     }
   });
+  let values = columns.iter().map(to_value);
+  let key_value = to_value(key);
+  let column_ident = columns.iter().map(|column| &column.ident);
+  let error_name = quote::format_ident!("{}RowError", struct_name);
+  let visibility = &input.vis;
+  let decoded = columns.iter().map(|column| from_value(column, &error_name));
 
-  // For add
-  // TODO: Find a more elegant solution but to clone the iterator
-  let field_name2 = field_name.clone();
-  let values2 = values.clone();
-  // For from_hash
-  let field_name3 = field_name.clone();
-  // let is_options = vec![
-  //   quote!(.unwrap().to_owned()),
-  //   quote!(.unwrap().to_owned()),
-  //   quote!(.map(|v| v.to_owned())),
-  //   quote!(.map(|v| v.to_owned())),
-  //   quote!(.map(|v| v.to_owned())),
-  //   quote!(.map(|v| v.parse::<i32>().unwrap())),
-  //   quote!(.map(|v| v.parse::<u32>().unwrap())),
-  //   quote!(.map(|v| v.parse::<u32>().unwrap())),
-  // ];
-  // let is_options_iter = is_options.iter();
-
-  // Create the specific code for each used in from_hash to convert from the
-  // sqlite String to the proper type.
-  // TODO: Rework all this duplicated and awful code.
-  let is_options_iter = fields.iter().filter_map(|field| {
-    let field_type = match field.ty {
-      syn::Type::Path(ref typepath) if typepath.qself.is_none() => &typepath.path,
-      _ => panic!("Could not extract type path"),
-    };
-    let type_name = field_type
-      .segments
-      .last() // last to deal with std::blabla::what::Option
-      .unwrap()
-      .ident
-      .to_string();
-    // eprintln!("typename {}", type_name);
-    match type_name.as_str() {
-      "u8" => Some(quote!(.unwrap().parse::<u8>().unwrap())),
-      "u16" => Some(quote!(.unwrap().parse::<u16>().unwrap())),
-      "u32" => Some(quote!(.unwrap().parse::<u32>().unwrap())),
-      "u64" => Some(quote!(.unwrap().parse::<u64>().unwrap())),
-      "u128" => Some(quote!(.unwrap().parse::<u128>().unwrap())),
-      "i8" => Some(quote!(.unwrap().parse::<i8>().unwrap())),
-      "i16" => Some(quote!(.unwrap().parse::<i16>().unwrap())),
-      "i32" => Some(quote!(.unwrap().parse::<i32>().unwrap())),
-      "i64" => Some(quote!(.unwrap().parse::<i64>().unwrap())),
-      "i128" => Some(quote!(.unwrap().parse::<i128>().unwrap())),
-      "String" => Some(quote!(.unwrap().to_owned())),
-      "Option" => {
-        // All these shenanigans to fetch the Option's subtype
-        if let PathArguments::AngleBracketed(sub_type) =
-          field_type.segments.last().unwrap().arguments.to_owned()
-        {
-          if let syn::GenericArgument::Type(sub_type) = sub_type.args.first().unwrap() {
-            let sub_type = match sub_type {
-              syn::Type::Path(ref typepath) if typepath.qself.is_none() => &typepath.path,
-              _ => panic!("Could not extract sybtype path"),
-            };
-            let sub_type_name = sub_type.segments.last().unwrap().ident.to_string();
-            // eprintln!("sub typename {:?}", sub_type_name);
-            match sub_type_name.as_str() {
-              "u8" => Some(quote!(.map(|v| v.parse::<u8>().unwrap()))),
-              "u16" => Some(quote!(.map(|v| v.parse::<u16>().unwrap()))),
-              "u32" => Some(quote!(.map(|v| v.parse::<u32>().unwrap()))),
-              "u64" => Some(quote!(.map(|v| v.parse::<u64>().unwrap()))),
-              "u128" => Some(quote!(.map(|v| v.parse::<u128>().unwrap()))),
-              "i8" => Some(quote!(.map(|v| v.parse::<i8>().unwrap()))),
-              "i16" => Some(quote!(.map(|v| v.parse::<i16>().unwrap()))),
-              "i32" => Some(quote!(.map(|v| v.parse::<i32>().unwrap()))),
-              "i64" => Some(quote!(.map(|v| v.parse::<i64>().unwrap()))),
-              "i128" => Some(quote!(.map(|v| v.parse::<i128>().unwrap()))),
-              "String" => Some(quote!(.map(|v| v.to_owned()))),
-              _ => None,
-            }
-          } else {
-            None
-          }
-        } else {
-          None
+  Ok(quote! {
+    // A row of the table which cannot be converted to the struct
+    #[derive(Debug)]
+    #visibility struct #error_name {
+      pub table: &'static str,
+      pub column: &'static str,
+      // The value of the column, None if the column is missing
+      pub value: Option<::sqlite::Value>,
+    }
+
+    impl ::std::fmt::Display for #error_name {
+      fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match &self.value {
+          None => write!(f, "missing column {} in table {}", self.column, self.table),
+          Some(value) => write!(f, "invalid value {:?} in column {} of table {}", value, self.column, self.table),
         }
       }
-      _ => None,
     }
-  });
 
-  TokenStream::from(quote! {
-    use std::collections::HashMap;
+    impl ::std::error::Error for #error_name {}
 
     impl #struct_name {
+      // The table the struct is stored in
+      pub const TABLE: &'static str = #table_name;
+      // The column identifying the rows
+      pub const PRIMARY_KEY: &'static str = #key_name;
+
       // TODO: Find a better place for this function. Here it will be replicated
       // in all struct deriving this macro.
-      // Performs an arbitrary query on the connection
-      fn execute_query(connection: &Connection, query: &str) -> Result<Vec<HashMap<String, String>>> {
-        tracing::debug!("query: {}", query);
-        let query = query;
+      // Performs an arbitrary query on the connection, binding the values to the
+      // `?` parameters of the query in order.
+      fn execute_query(
+        connection: &::sqlite::Connection,
+        query: &str,
+        values: &[::sqlite::Value],
+      ) -> ::anyhow::Result<::std::vec::Vec<::std::collections::HashMap<String, ::sqlite::Value>>> {
+        ::tracing::debug!("query: {} {:?}", query, values);
         let mut statement = connection.prepare(query)?;
-        let mut result: Vec<HashMap<String, String>> = Vec::new();
-        while let Ok(State::Row) = statement.next() {
+        for (index, value) in values.iter().enumerate() {
+          statement.bind((index + 1, value))?;
+        }
+        let mut result = ::std::vec::Vec::new();
+        while let ::sqlite::State::Row = statement.next()? {
           let column_names = statement.column_names();
-          let mut entries = HashMap::new();
+          let mut entries = ::std::collections::HashMap::new();
           for column_name in column_names {
-            if let Ok(value) = statement.read::<String, _>(&**column_name) {
+            if let Ok(value) = statement.read::<::sqlite::Value, _>(&**column_name) {
               entries.insert(column_name.to_owned(), value);
             }
           }
@@ -196,152 +501,113 @@ pub fn derive_field_list(input: TokenStream) -> TokenStream {
         Ok(result)
       }
 
-      pub fn field_list() -> Vec<(String, String)> {
+      // The stored columns with the rust type of their field
+      pub fn field_list() -> ::std::vec::Vec<(String, String)> {
         return vec![#(
           (
-            std::stringify!(#field_name).to_string(),
-            std::stringify!(#field_type).to_string().replace(" ", ""),
+            #column_names.to_string(),
+            ::std::stringify!(#column_type).to_string().replace(" ", ""),
           ),
         )*]
       }
 
-      // Convert rust types to sql types. A limited number of types are accepted.
-      fn to_sql_type(field_type: &str) -> Result<String> {
-        let result = match field_type {
-          "i8" | "i16" | "i32" | "i64" | "i128" | "u8" | "u16" | "u32" | "u64" | "u128" => {
-            "INTEGER NON NULL"
-          }
-          "Option<i8>" | "Option<i16>" | "Option<i32>" | "Option<i64>" | "Option<i128>"
-          | "Option<u8>" | "Option<u16>" | "Option<u32>" | "Option<u64>" | "Option<u128>" => "INTEGER",
-          "String" => "TEXT NON NULL",
-          "Option<String>" => "TEXT",
-          _ => anyhow::bail!("to_sql_type: {} unknown type conversion to sql", field_type),
-        };
-        Ok(result.into())
-      }
-
-      pub fn create_table(
-        connection: &Connection,
-        table_name: &str) -> Result<()> {
-        let table = #struct_name::field_list()
-          .iter()
-          .map(|(field_name, field_type)| match #struct_name::to_sql_type(field_type) {
-            Ok(field_type) => Ok((field_name.to_string(), field_type.to_string())),
-            Err(e) => Err(e),
-          })
-          // https://doc.rust-lang.org/rust-by-example/error/iter_result.html#fail-the-entire-operation-with-collect
-          .collect::<Result<Vec<(String, String)>>>()?
-          .iter()
-          .map(|(field_name, field_type)| {
-            format!("{}", field_name)
-            // if field_name == "id" {
-            //   format!("{} {} PRIMARY KEY", field_name, field_type)
-            // } else {
-            //   format!("{} {}", field_name, field_type)
-            // }
-          })
-          .collect::<Vec<String>>()
-          .join(",");
-        // #struct_name::execute_query(&connection,
-          // &format!("CREATE TABLE IF NOT EXISTS {} ({});", table_name, table))?;
-        // https://www.sqlite.org/fts3.html#termprefix
-        #struct_name::execute_query(&connection,
-          &format!("CREATE VIRTUAL TABLE IF NOT EXISTS {} USING fts5({});", table_name, table))?;
+      // Create the table and its indexes
+      pub fn create_table(connection: &::sqlite::Connection) -> ::anyhow::Result<()> {
+        connection.execute(#create_table)?;
         Ok(())
       }
 
-      pub fn value_list(&self) -> Vec<String> {
-        return vec![#(#values)*]
+      pub fn value_list(&self) -> ::std::vec::Vec<::sqlite::Value> {
+        return vec![#(#values,)*]
       }
 
       // Look for the entry in the DB, update it if present, create it otherwise. This makes
       // scan reentrant when using an SQL store.
-      // The entry type must be Identifiable (have an id field) and Iterable. We will
-      // then use struct_iterable to iterate over the field of the type and insert in
-      // the database.
-      pub fn add(&self, connection: &Connection, table_name: &str) -> Result<()> {
-        // Check if the UIDs are not already present in the database
-        let constraints = format!("id=\"{}\"", self.id());
+      pub fn add(&self, connection: &::sqlite::Connection) -> ::anyhow::Result<()> {
+        let key = #key_value;
         let already_present =
-          !Song::execute_query(connection, &format!("SELECT * FROM {} WHERE {};", "songs", constraints))?
-            .is_empty();
-
-        let column_names = vec![#(std::stringify!(#field_name2).to_string(),)*];
-        let values = vec![#(#values2)*];
+          !#struct_name::execute_query(connection, #select_key, ::std::slice::from_ref(&key))?.is_empty();
+        let mut values = self.value_list();
         if already_present {
-          let sets = column_names.iter().zip(values.iter())
-            .map(|(name, value)| format!("{}={}", name, value))
-            .collect::<Vec<String>>()
-            .join(",");
-          let query = &format!("UPDATE {} SET {} WHERE {};", table_name, sets, constraints);
-          Song::execute_query(connection, query)?;
+          values.push(key);
+          #struct_name::execute_query(connection, #update, &values)?;
         } else {
           // No entry, create a new one
-          let column_names = column_names.join(",");
-          let values = values.join(",");
-          let query = &format!("INSERT INTO {} ({}) VALUES ({});", table_name, column_names, values,);
-          Song::execute_query(connection, query)?;
+          #struct_name::execute_query(connection, #insert, &values)?;
         }
-
         Ok(())
       }
 
-      fn from_hash(hashmap: &HashMap<String, String>) -> Result<#struct_name> {
+      fn from_hash(
+        hashmap: &::std::collections::HashMap<String, ::sqlite::Value>,
+      ) -> ::std::result::Result<#struct_name, #error_name> {
         Ok(#struct_name {
-          #(
-            #field_name3: hashmap.get(std::stringify!(#field_name3))#is_options_iter,
-          )*
+          #(#column_ident: #decoded?,)*
+          #(#skipped: ::std::default::Default::default(),)*
         })
       }
 
-      pub fn from_sqlite_result(results: &Vec<HashMap<String, String>>) -> Vec<#struct_name> {
+      // The rows which cannot be converted are logged and skipped, so that a
+      // single bad row does not make the whole result fail
+      fn from_sqlite_result(
+        results: &[::std::collections::HashMap<String, ::sqlite::Value>],
+      ) -> ::std::vec::Vec<#struct_name> {
         results
           .iter()
-          .map(|r| #struct_name::from_hash(r))
-          .filter_map(|s| s.ok())
-          .collect::<Vec<Song>>()
+          .filter_map(|row| match #struct_name::from_hash(row) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+              ::tracing::warn!("skipping a row of {}: {}", Self::TABLE, e);
+              None
+            }
+          })
+          .collect()
       }
 
-      pub fn get(connection: &Connection, table_name: &str, id: &str) -> Result<Option<#struct_name>> {
-        let result =
-           #struct_name::execute_query(&connection, &format!(r#"SELECT * from {} WHERE id="{}";"#, table_name, id))?;
-        if !result.is_empty() {
-          Ok(Some(#struct_name::from_hash(result.first().unwrap())?))
-        } else {
-          Ok(None)
+      // The rows returned by an arbitrary query on the table, which must select
+      // all the columns
+      pub fn select(
+        connection: &::sqlite::Connection,
+        query: &str,
+        values: &[::sqlite::Value],
+      ) -> ::anyhow::Result<::std::vec::Vec<#struct_name>> {
+        Ok(#struct_name::from_sqlite_result(&#struct_name::execute_query(connection, query, values)?))
+      }
+
+      pub fn get(
+        connection: &::sqlite::Connection,
+        key: impl Into<::sqlite::Value>,
+      ) -> ::anyhow::Result<Option<#struct_name>> {
+        let result = #struct_name::execute_query(connection, #select, &[key.into()])?;
+        match result.first() {
+          Some(row) => Ok(Some(#struct_name::from_hash(row)?)),
+          None => Ok(None),
         }
       }
 
       pub fn get_all_with_pagination(
-        connection: &Connection,
-        table_name: &str,
-        page: Option<u32>,
-        per_page: Option<u32>,
-      ) -> Result<Vec<Song>> {
-        let offset: u32;
-        let limit: u32;
-        if page.is_none() || per_page.is_none() {
-          offset = 0;
-          limit = u32::MAX;
-        } else {
-          offset = page.unwrap() * per_page.unwrap();
-          limit = per_page.unwrap();
-        }
-        let result =
-           #struct_name::execute_query(
-             &connection,
-             &format!(r#"SELECT * from {} LIMIT {} OFFSET {};"#, table_name, limit, offset))?;
-        Ok(
-          result
-            .iter()
-            .map(|r| #struct_name::from_hash(r))
-            .filter_map(|s| s.ok())
-            .collect::<Vec<Song>>()
-        )
+        connection: &::sqlite::Connection,
+        offset: u32,
+        limit: u32,
+      ) -> ::anyhow::Result<::std::vec::Vec<#struct_name>> {
+        let result = #struct_name::execute_query(
+          connection,
+          #select_all,
+          &[::sqlite::Value::Integer(limit as i64), ::sqlite::Value::Integer(offset as i64)],
+        )?;
+        Ok(#struct_name::from_sqlite_result(&result))
       }
 
-      pub fn get_all(connection: &Connection, table_name: &str) -> Result<Vec<Song>> {
-        #struct_name::get_all_with_pagination(connection, table_name, None, None)
+      pub fn get_all(connection: &::sqlite::Connection) -> ::anyhow::Result<::std::vec::Vec<#struct_name>> {
+        #struct_name::get_all_with_pagination(connection, 0, u32::MAX)
+      }
+
+      pub fn count(connection: &::sqlite::Connection) -> ::anyhow::Result<u64> {
+        let result = #struct_name::execute_query(connection, #count, &[])?;
+        match result.first().and_then(|row| row.get("count")) {
+          Some(::sqlite::Value::Integer(count)) => Ok(*count as u64),
+          _ => ::anyhow::bail!("cannot count the rows of {}", Self::TABLE),
+        }
       }
     }
   })
@@ -0,0 +1,244 @@
+// Albums are the groups of songs sharing the same album id (see album_id()).
+// They are stored in the albums table, rebuilt from the songs after each scan,
+// so that the lists are sorted by its indexes.
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::Serialize;
+use sqlite::{Connection, Value};
+
+use crate::sorting::{SortBy, SortParams};
+use crate::{execute_query, pagination, parse_column, parse_optional_column, Song};
+
+// The columns of an album, in the order of the albums table, computed from its
+// songs. Its artist is the album
+// artist, or the artist of the songs when they all have the same.
+const ALBUM_COLUMNS: &str = "album_id, MIN(album), COALESCE(MIN(album_sort), MIN(album)), \
+  COALESCE(MAX(album_artist), CASE WHEN COUNT(DISTINCT artist) = 1 THEN MAX(artist) END), \
+  CASE WHEN COUNT(DISTINCT album_artist_id) = 1 THEN MAX(album_artist_id) END, \
+  MIN(year), COUNT(*), COUNT(DISTINCT COALESCE(disc, 1)), COALESCE(SUM(duration_ms), 0), \
+  MAX(cover_id), MIN(added_at)";
+
+// The ids of the albums of which an artist is an album artist
+pub const ARTIST_ALBUM_IDS: &str = "SELECT songs.album_id FROM song_artists \
+  JOIN songs ON songs.id = song_artists.song_id \
+  WHERE song_artists.artist_id = ? AND song_artists.role = 'album_artist' \
+  AND songs.album_id IS NOT NULL AND songs.missing_since IS NULL";
+
+#[derive(Debug, Serialize)]
+pub struct Album {
+  pub id: String,
+  pub title: String,
+  pub sort_title: Option<String>,
+  pub artist: Option<String>,
+  pub artist_id: Option<String>,
+  pub year: Option<i32>,
+  pub track_count: u32,
+  pub disc_count: u32,
+  // Of the songs whose duration is known
+  pub duration_ms: u64,
+  pub cover_url: Option<String>,
+}
+
+// An album with its songs, in disc and track order
+#[derive(Debug, Serialize)]
+pub struct AlbumWithSongs {
+  #[serde(flatten)]
+  pub album: Album,
+  pub songs: Vec<Song>,
+}
+
+// Rebuilds the albums from the songs which are not missing
+pub fn refresh(connection: &Connection) -> Result<()> {
+  let query = format!(
+    "INSERT INTO albums (id, title, sort_title, artist, artist_id, year, track_count, \
+     disc_count, duration_ms, cover_id, added_at) \
+     SELECT {} FROM songs WHERE album_id IS NOT NULL AND missing_since IS NULL \
+     GROUP BY album_id;",
+    ALBUM_COLUMNS
+  );
+  execute_query(connection, "DELETE FROM albums;", &[])?;
+  execute_query(connection, &query, &[])?;
+  Ok(())
+}
+
+impl Album {
+  fn from_row(row: &HashMap<String, String>) -> Result<Album> {
+    let id: String = parse_column(row, "albums", "id")?;
+    Ok(Album {
+      title: parse_column(row, "albums", "title")?,
+      sort_title: parse_optional_column(row, "albums", "sort_title")?,
+      artist: parse_optional_column(row, "albums", "artist")?,
+      artist_id: parse_optional_column(row, "albums", "artist_id")?,
+      year: parse_optional_column(row, "albums", "year")?,
+      track_count: parse_column(row, "albums", "track_count")?,
+      disc_count: parse_column(row, "albums", "disc_count")?,
+      duration_ms: parse_column(row, "albums", "duration_ms")?,
+      cover_url: row
+        .contains_key("cover_id")
+        .then(|| format!("/albums/{}/cover", id)),
+      id,
+    })
+  }
+
+  fn select(connection: &Connection, query: &str, values: &[Value]) -> Result<Vec<Album>> {
+    execute_query(connection, query, values)?
+      .iter()
+      .map(Album::from_row)
+      .collect()
+  }
+
+  pub fn get(connection: &Connection, id: &str) -> Result<Option<Album>> {
+    let query = "SELECT * FROM albums WHERE id = ?;";
+    Ok(Album::select(connection, query, &[Value::String(id.to_string())])?.pop())
+  }
+
+  pub fn count(connection: &Connection) -> Result<u64> {
+    pagination::count(connection, "SELECT COUNT(*) AS count FROM albums;", &[])
+  }
+
+  pub fn get_all_with_pagination(
+    connection: &Connection,
+    sort: &SortParams,
+    offset: u32,
+    limit: u32,
+  ) -> Result<Vec<Album>> {
+    Album::list(connection, "", &[], sort, offset, limit)
+  }
+
+  // The albums whose id is selected by the query `album_ids`, such as
+  // ARTIST_ALBUM_IDS
+  pub fn count_matching(connection: &Connection, album_ids: &str, values: &[Value]) -> Result<u64> {
+    let query = format!("SELECT COUNT(*) AS count FROM albums WHERE id IN ({});", album_ids);
+    pagination::count(connection, &query, values)
+  }
+
+  pub fn get_matching_with_pagination(
+    connection: &Connection,
+    album_ids: &str,
+    values: &[Value],
+    sort: &SortParams,
+    offset: u32,
+    limit: u32,
+  ) -> Result<Vec<Album>> {
+    let filter = format!("WHERE id IN ({})", album_ids);
+    Album::list(connection, &filter, values, sort, offset, limit)
+  }
+
+  // The albums selected by the WHERE clause `filter`, if any
+  fn list(
+    connection: &Connection,
+    filter: &str,
+    values: &[Value],
+    sort: &SortParams,
+    offset: u32,
+    limit: u32,
+  ) -> Result<Vec<Album>> {
+    let order_by = sort.order_by(
+      |sort| match sort {
+        SortBy::Name => "title",
+        SortBy::SortName => "sort_title",
+        SortBy::Year => "year",
+        SortBy::Added => "added_at",
+        SortBy::SongCount => "track_count",
+      },
+      SortBy::SortName,
+      "id",
+    );
+    let query = format!("SELECT * FROM albums {} ORDER BY {} LIMIT ? OFFSET ?;", filter, order_by);
+    let mut values = values.to_vec();
+    values.extend([Value::Integer(limit as i64), Value::Integer(offset as i64)]);
+    Album::select(connection, &query, &values)
+  }
+
+  // The albums of which the artist is an album artist, oldest first
+  pub fn get_by_artist(connection: &Connection, artist_id: &str) -> Result<Vec<Album>> {
+    let query = format!(
+      "SELECT * FROM albums WHERE id IN ({}) ORDER BY year, sort_title COLLATE NOCASE, id;",
+      ARTIST_ALBUM_IDS
+    );
+    Album::select(connection, &query, &[Value::String(artist_id.to_string())])
+  }
+
+  pub fn songs(&self, connection: &Connection) -> Result<Vec<Song>> {
+    Song::select(
+      connection,
+      "SELECT * FROM songs WHERE album_id = ? AND missing_since IS NULL \
+       ORDER BY disc, track, title;",
+      &[Value::String(self.id.clone())],
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::sorting::Order;
+
+  fn library() -> Connection {
+    let connection = Connection::open(":memory:").unwrap();
+    crate::migrations::run(&connection).unwrap();
+    connection
+      .execute(
+        r#"
+        INSERT INTO songs (id, path, size, mtime, album, album_id, album_sort, year, disc,
+          added_at, missing_since) VALUES
+          ('1', 'a', 0, 0, 'The Zoo', 'zoo', 'Zoo', 1979, 1, 3, NULL),
+          ('2', 'b', 0, 0, 'The Zoo', 'zoo', 'Zoo', 1979, 2, 3, NULL),
+          ('3', 'c', 0, 0, 'abbey road', 'abbey', 'abbey road', 1969, NULL, 2, NULL),
+          ('4', 'd', 0, 0, 'Ummagumma', 'umma', NULL, 1969, NULL, 5, NULL),
+          ('5', 'e', 0, 0, 'Demos', 'demos', 'Demos', NULL, NULL, 1, NULL),
+          ('6', 'f', 0, 0, 'The Zoo', 'zoo', 'Zoo', 1979, 1, 3, 10),
+          ('7', 'g', 0, 0, 'Lost', 'lost', 'Lost', 2000, NULL, 4, 10);
+        "#,
+      )
+      .unwrap();
+    refresh(&connection).unwrap();
+    connection
+  }
+
+  fn titles(connection: &Connection, sort: SortBy, order: Order) -> Vec<String> {
+    let sort = SortParams { sort, order };
+    Album::get_all_with_pagination(connection, &sort, 0, 10)
+      .unwrap()
+      .into_iter()
+      .map(|album| album.title)
+      .collect()
+  }
+
+  #[test]
+  fn missing_songs_are_left_out() {
+    let connection = library();
+    assert_eq!(Album::count(&connection).unwrap(), 4);
+    let album = Album::get(&connection, "zoo").unwrap().unwrap();
+    assert_eq!(album.sort_title.as_deref(), Some("Zoo"));
+    assert_eq!((album.track_count, album.disc_count), (2, 2));
+    assert!(Album::get(&connection, "lost").unwrap().is_none());
+    // Without a sort tag, the sort title is the title
+    let album = Album::get(&connection, "umma").unwrap().unwrap();
+    assert_eq!(album.sort_title.as_deref(), Some("Ummagumma"));
+  }
+
+  #[test]
+  fn sorted_lists() {
+    let connection = library();
+    let sorted = |sort, order| titles(&connection, sort, order);
+    assert_eq!(sorted(SortBy::Name, Order::Asc), ["abbey road", "Demos", "The Zoo", "Ummagumma"]);
+    assert_eq!(
+      sorted(SortBy::SortName, Order::Asc),
+      ["abbey road", "Demos", "Ummagumma", "The Zoo"]
+    );
+    assert_eq!(
+      sorted(SortBy::SortName, Order::Desc),
+      ["The Zoo", "Ummagumma", "Demos", "abbey road"]
+    );
+    // Albums without a year are last in both orders, ties are sorted by name
+    assert_eq!(sorted(SortBy::Year, Order::Asc), ["abbey road", "Ummagumma", "The Zoo", "Demos"]);
+    assert_eq!(sorted(SortBy::Year, Order::Desc), ["The Zoo", "Ummagumma", "abbey road", "Demos"]);
+    assert_eq!(sorted(SortBy::Added, Order::Asc), ["Demos", "abbey road", "The Zoo", "Ummagumma"]);
+    assert_eq!(
+      sorted(SortBy::SongCount, Order::Desc),
+      ["The Zoo", "Ummagumma", "Demos", "abbey road"]
+    );
+  }
+}
@@ -0,0 +1,134 @@
+// Artists are the names the songs are credited to (see credits), identified by
+// the hash of the name (see artist_id()). Like albums, they are stored in the
+// artists table, rebuilt from the credits after each scan.
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::Serialize;
+use sqlite::{Connection, Value};
+
+use crate::albums::{Album, ARTIST_ALBUM_IDS};
+use crate::sorting::{SortBy, SortParams};
+use crate::{execute_query, pagination, parse_column, parse_optional_column, Song};
+
+// Who is credited for what: an artist (featured or not) of a song, or the
+// artist of an album
+const CREDITS: &str = "WITH credits AS ( \
+  SELECT song_artists.artist_id AS id, name, sort_name, song_id, NULL AS album_id, year, added_at \
+  FROM song_artists JOIN songs ON songs.id = song_artists.song_id \
+  WHERE role != 'album_artist' AND missing_since IS NULL \
+  UNION ALL \
+  SELECT song_artists.artist_id, name, sort_name, NULL, album_id, year, added_at \
+  FROM song_artists JOIN songs ON songs.id = song_artists.song_id \
+  WHERE role = 'album_artist' AND missing_since IS NULL)";
+// The columns of an artist, in the order of the artists table. The year and the
+// time added are the earliest ones of the artist's songs.
+const ARTIST_COLUMNS: &str = "id, MIN(name), COALESCE(MIN(sort_name), MIN(name)), \
+  COUNT(DISTINCT song_id), COUNT(DISTINCT album_id), MIN(year), MIN(added_at)";
+
+#[derive(Debug, Serialize)]
+pub struct Artist {
+  pub id: String,
+  pub name: String,
+  pub sort_name: Option<String>,
+  // Songs the artist is credited on and albums where they are an album artist
+  pub song_count: u32,
+  pub album_count: u32,
+}
+
+// An artist with their albums, and the songs they appear on elsewhere
+// (compilations, other artists' albums, songs without album)
+#[derive(Debug, Serialize)]
+pub struct ArtistWithAlbums {
+  #[serde(flatten)]
+  pub artist: Artist,
+  pub albums: Vec<Album>,
+  pub appearances: Vec<Song>,
+}
+
+// Rebuilds the artists from the credits of the songs which are not missing
+pub fn refresh(connection: &Connection) -> Result<()> {
+  let query = format!(
+    "INSERT INTO artists (id, name, sort_name, song_count, album_count, year, added_at) \
+     {} SELECT {} FROM credits GROUP BY id;",
+    CREDITS, ARTIST_COLUMNS
+  );
+  execute_query(connection, "DELETE FROM artists;", &[])?;
+  execute_query(connection, &query, &[])?;
+  Ok(())
+}
+
+impl Artist {
+  fn from_row(row: &HashMap<String, String>) -> Result<Artist> {
+    Ok(Artist {
+      id: parse_column(row, "artists", "id")?,
+      name: parse_column(row, "artists", "name")?,
+      sort_name: parse_optional_column(row, "artists", "sort_name")?,
+      song_count: parse_column(row, "artists", "song_count")?,
+      album_count: parse_column(row, "artists", "album_count")?,
+    })
+  }
+
+  fn select(connection: &Connection, query: &str, values: &[Value]) -> Result<Vec<Artist>> {
+    execute_query(connection, query, values)?
+      .iter()
+      .map(Artist::from_row)
+      .collect()
+  }
+
+  pub fn get(connection: &Connection, id: &str) -> Result<Option<Artist>> {
+    let query = "SELECT * FROM artists WHERE id = ?;";
+    Ok(Artist::select(connection, query, &[Value::String(id.to_string())])?.pop())
+  }
+
+  pub fn count(connection: &Connection) -> Result<u64> {
+    pagination::count(connection, "SELECT COUNT(*) AS count FROM artists;", &[])
+  }
+
+  pub fn get_all_with_pagination(
+    connection: &Connection,
+    sort: &SortParams,
+    offset: u32,
+    limit: u32,
+  ) -> Result<Vec<Artist>> {
+    let order_by = sort.order_by(
+      |sort| match sort {
+        SortBy::Name => "name",
+        SortBy::SortName => "sort_name",
+        SortBy::Year => "year",
+        SortBy::Added => "added_at",
+        SortBy::SongCount => "song_count",
+      },
+      SortBy::SortName,
+      "id",
+    );
+    let query = format!("SELECT * FROM artists ORDER BY {} LIMIT ? OFFSET ?;", order_by);
+    Artist::select(
+      connection,
+      &query,
+      &[Value::Integer(limit as i64), Value::Integer(offset as i64)],
+    )
+  }
+
+  // The songs the artist is credited on which are not on one of their albums,
+  // collaborations included
+  pub fn appearances(&self, connection: &Connection) -> Result<Vec<Song>> {
+    let query = format!(
+      "SELECT DISTINCT songs.* FROM songs \
+       JOIN song_artists ON song_artists.song_id = songs.id \
+       WHERE song_artists.artist_id = ? AND song_artists.role != 'album_artist' \
+       AND songs.missing_since IS NULL \
+       AND (songs.album_id IS NULL OR songs.album_id NOT IN ({})) \
+       ORDER BY songs.album COLLATE NOCASE, songs.year, songs.disc, songs.track, songs.title;",
+      ARTIST_ALBUM_IDS
+    );
+    Song::select(
+      connection,
+      &query,
+      &[
+        Value::String(self.id.clone()),
+        Value::String(self.id.clone()),
+      ],
+    )
+  }
+}
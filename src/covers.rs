@@ -0,0 +1,272 @@
+// Cover art of the songs. Pictures embedded in the files are preferred, with a
+// fallback on the image files usually found next to them. Covers are stored
+// once in the database, identified by the hash of their content, and shared by
+// the songs using them (usually a whole album). The image file of a folder is
+// also its cover (see folders).
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::{
+  http::{header, HeaderMap, HeaderValue, StatusCode},
+  response::{IntoResponse, Response},
+};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use field_list::FieldList;
+use md5::Digest;
+use serde::Deserialize;
+use sqlite::{Connection, ConnectionThreadSafe, Value};
+
+use crate::{execute_query, folders, streaming, tags, thumbnails};
+
+// Image files looked for in the folder of a song without embedded picture, in
+// order of preference. Case is ignored.
+const FOLDER_COVERS: [&str; 6] = [
+  "cover.jpg",
+  "cover.png",
+  "folder.jpg",
+  "folder.png",
+  "front.jpg",
+  "front.png",
+];
+// Bigger pictures are most likely not covers
+const MAX_COVER_SIZE: u64 = 16 * 1024 * 1024;
+// A cover never changes but the cover of a song or an album may change on
+// rescan, so clients revalidate from time to time.
+const MAX_AGE: u32 = 24 * 60 * 60;
+
+#[derive(Debug, FieldList)]
+#[field_list(table = "covers")]
+pub struct Cover {
+  #[field_list(primary_key)]
+  pub id: String,
+  pub mime_type: String,
+  pub data: Vec<u8>,
+}
+
+// Finds the covers of the songs during a scan
+pub struct CoverStore {
+  // The ids of the covers in the database
+  known: HashSet<String>,
+  // The cover found in each folder, if any
+  folders: HashMap<PathBuf, Option<String>>,
+}
+
+impl CoverStore {
+  pub fn load(connection: &Connection) -> Result<CoverStore> {
+    let known = execute_query(connection, "SELECT id FROM covers;", &[])?
+      .iter()
+      .filter_map(|row| row.get("id").cloned())
+      .collect::<HashSet<String>>();
+    Ok(CoverStore {
+      known,
+      folders: HashMap::new(),
+    })
+  }
+
+  // The id of the cover of the song at `path`, stored in the database if new
+  pub fn cover_id(&mut self, connection: &Connection, path: &Path) -> Result<Option<String>> {
+    // Looked for anyway, it is the cover of the folder
+    let folder_cover_id = match path.parent() {
+      Some(folder) => self.folder_cover_id(connection, folder)?,
+      None => None,
+    };
+    let picture = tags::read_picture(path).unwrap_or_else(|e| {
+      tracing::debug!("cannot read the pictures of {} ({})", path.display(), e);
+      None
+    });
+    if let Some(picture) = picture.filter(|p| p.data.len() as u64 <= MAX_COVER_SIZE) {
+      return self.store(connection, picture).map(Some);
+    }
+    Ok(folder_cover_id)
+  }
+
+  // The id of the image file of `folder`, recorded in the folder_covers table
+  // the first time the folder is seen during the scan
+  fn folder_cover_id(&mut self, connection: &Connection, folder: &Path) -> Result<Option<String>> {
+    if let Some(cover_id) = self.folders.get(folder) {
+      return Ok(cover_id.clone());
+    }
+    let cover_id = match folder_cover(folder) {
+      Some(picture) => Some(self.store(connection, picture)?),
+      None => None,
+    };
+    let path = Value::String(folder.to_string_lossy().to_string());
+    match &cover_id {
+      Some(cover_id) => execute_query(
+        connection,
+        "INSERT OR REPLACE INTO folder_covers (path, cover_id) VALUES (?, ?);",
+        &[path, Value::String(cover_id.clone())],
+      )?,
+      None => execute_query(connection, "DELETE FROM folder_covers WHERE path = ?;", &[path])?,
+    };
+    self.folders.insert(folder.to_path_buf(), cover_id.clone());
+    Ok(cover_id)
+  }
+
+  fn store(&mut self, connection: &Connection, picture: tags::Picture) -> Result<String> {
+    let id = Base64UrlUnpadded::encode_string(&md5::Md5::digest(&picture.data));
+    if !self.known.contains(&id) {
+      let cover = Cover {
+        id: id.clone(),
+        mime_type: picture.mime_type,
+        data: picture.data,
+      };
+      cover.add(connection)?;
+      self.known.insert(id.clone());
+    }
+    Ok(id)
+  }
+}
+
+// The first image file of FOLDER_COVERS found in `folder`
+fn folder_cover(folder: &Path) -> Option<tags::Picture> {
+  let entries = match fs::read_dir(folder) {
+    Ok(entries) => entries,
+    Err(e) => {
+      tracing::debug!("cannot list {} ({})", folder.display(), e);
+      return None;
+    }
+  };
+  let files = entries
+    .filter_map(|entry| entry.ok())
+    .map(|entry| (entry.file_name().to_string_lossy().to_lowercase(), entry.path()))
+    .collect::<Vec<(String, PathBuf)>>();
+  for name in FOLDER_COVERS {
+    let Some((_, path)) = files.iter().find(|(file_name, _)| file_name == name) else {
+      continue;
+    };
+    match read_image(path) {
+      Ok(Some(picture)) => return Some(picture),
+      Ok(None) => tracing::debug!("{} is not an image or is too large", path.display()),
+      Err(e) => tracing::debug!("cannot read {} ({})", path.display(), e),
+    }
+  }
+  None
+}
+
+fn read_image(path: &Path) -> Result<Option<tags::Picture>> {
+  if fs::metadata(path)?.len() > MAX_COVER_SIZE {
+    return Ok(None);
+  }
+  let data = fs::read(path)?;
+  Ok(tags::sniff_mime_type(&data).map(|mime_type| tags::Picture {
+    mime_type: mime_type.to_string(),
+    data,
+  }))
+}
+
+// Remove the covers of the folders without songs, then the covers no song or
+// folder uses anymore
+pub fn remove_unused(connection: &Connection) -> Result<()> {
+  let (separator, after_separator) = folders::separators();
+  execute_query(
+    connection,
+    "DELETE FROM folder_covers WHERE NOT EXISTS (SELECT 1 FROM songs \
+     WHERE songs.path > folder_covers.path || ? AND songs.path < folder_covers.path || ?);",
+    &[
+      Value::String(separator.to_string()),
+      Value::String(after_separator.to_string()),
+    ],
+  )?;
+  execute_query(
+    connection,
+    "DELETE FROM covers WHERE id NOT IN (SELECT cover_id FROM songs WHERE cover_id IS NOT NULL \
+     UNION SELECT cover_id FROM folder_covers);",
+    &[],
+  )?;
+  Ok(())
+}
+
+// The cover is served as is unless a size is given, in which case a thumbnail
+// fitting in a square of that size is served instead, as JPEG by default
+#[derive(Debug, Deserialize)]
+pub struct CoverParams {
+  size: Option<u32>,
+  format: Option<thumbnails::Format>,
+}
+
+// Serve the cover `cover_id`. Its id being the hash of its content, it is also
+// its entity tag.
+pub async fn serve(
+  connection: &ConnectionThreadSafe,
+  thumbnails: Arc<thumbnails::Cache>,
+  cover_id: &str,
+  params: &CoverParams,
+  request_headers: &HeaderMap,
+) -> Response {
+  let thumbnail = match params.size {
+    Some(0) => return (StatusCode::BAD_REQUEST, "invalid size").into_response(),
+    Some(size) => Some((thumbnails::thumbnail_size(size), params.format.unwrap_or_default())),
+    None => None,
+  };
+  let etag = match thumbnail {
+    Some((size, format)) => format!("\"{}\"", thumbnails::thumbnail_name(cover_id, size, format)),
+    None => format!("\"{}\"", cover_id),
+  };
+  let mut headers = HeaderMap::new();
+  if let Ok(value) = HeaderValue::from_str(&etag) {
+    headers.insert(header::ETAG, value);
+  }
+  if let Ok(value) = HeaderValue::from_str(&format!("public, max-age={}", MAX_AGE)) {
+    headers.insert(header::CACHE_CONTROL, value);
+  }
+  let not_modified = streaming::header_str(request_headers, header::IF_NONE_MATCH)
+    .is_some_and(|value| streaming::etag_matches(value, &etag));
+  if not_modified {
+    return (StatusCode::NOT_MODIFIED, headers).into_response();
+  }
+  // A thumbnail already generated is served without reading the cover
+  if let Some((size, format)) = thumbnail {
+    let (cache, id) = (Arc::clone(&thumbnails), cover_id.to_string());
+    match tokio::task::spawn_blocking(move || cache.cached(&id, size, format)).await {
+      Ok(Ok(Some(data))) => return with_content_type(headers, format.mime_type(), data),
+      Ok(Ok(None)) => (),
+      Ok(Err(e)) => {
+        tracing::error!("cannot read the thumbnail of cover {}: {:#}", cover_id, e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+      }
+      Err(e) => {
+        tracing::error!("thumbnail lookup of cover {} failed: {}", cover_id, e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+      }
+    }
+  }
+  let cover = match Cover::get(connection, cover_id) {
+    Ok(Some(cover)) => cover,
+    Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+    Err(e) => {
+      tracing::error!("cannot read cover {}: {}", cover_id, e);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+  let (mime_type, data) = match thumbnail {
+    Some((size, format)) => {
+      // Resizing takes a while, keep it away from the async runtime
+      let generated =
+        tokio::task::spawn_blocking(move || thumbnails.thumbnail(&cover, size, format)).await;
+      match generated {
+        Ok(Ok(data)) => (format.mime_type().to_string(), data),
+        Ok(Err(e)) => {
+          tracing::error!("cannot generate the thumbnail of cover {}: {:#}", cover_id, e);
+          return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        Err(e) => {
+          tracing::error!("thumbnail generation of cover {} failed: {}", cover_id, e);
+          return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+      }
+    }
+    None => (cover.mime_type, cover.data),
+  };
+  with_content_type(headers, &mime_type, data)
+}
+
+fn with_content_type(mut headers: HeaderMap, mime_type: &str, data: Vec<u8>) -> Response {
+  if let Ok(value) = HeaderValue::from_str(mime_type) {
+    headers.insert(header::CONTENT_TYPE, value);
+  }
+  (headers, data).into_response()
+}
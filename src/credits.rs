@@ -0,0 +1,216 @@
+// The artists credited on a song: its performers, the artists it features and
+// its album artists. A tag value may name several artists, separated by one of
+// the configured separators ("A; B") or introduced by "feat." ("A feat. B").
+// Songs and artists are linked in the song_artists table, with the name as
+// credited.
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use sqlite::{Connection, Value};
+
+use crate::{artist_id, execute_query, sorting, tags::Tags};
+
+// Most tools use these to put several values in a single tag. "/" and "&" are
+// part of too many names (AC/DC, Simon & Garfunkel) to be split by default.
+pub const DEFAULT_SEPARATORS: [&str; 1] = [";"];
+// What introduces featured artists, case insensitively, in an artist or a title
+const FEATURING: [&str; 4] = ["featuring", "feat.", "feat", "ft."];
+// Featured artists are usually listed as "B, C & D"
+const FEATURED_SEPARATORS: [&str; 2] = [",", "&"];
+
+#[derive(Debug, Default)]
+pub struct Credits {
+  pub artists: Vec<String>,
+  pub featured: Vec<String>,
+  // The artists of the song when the album artist is not known
+  pub album_artists: Vec<String>,
+  // By artist id, the names are used when missing
+  pub sort_names: HashMap<String, String>,
+}
+
+// Split a tag value on the separators. Values are trimmed and the ones only
+// differing by case are kept once.
+pub fn split_values(value: &str, separators: &[String]) -> Vec<String> {
+  let mut values = vec![value.to_string()];
+  for separator in separators.iter().filter(|s| !s.is_empty()) {
+    values = values
+      .iter()
+      .flat_map(|value| value.split(separator.as_str()))
+      .map(|value| value.to_string())
+      .collect();
+  }
+  unique(values.iter().map(|value| value.trim().to_string()))
+}
+
+// The values which are not empty, once per artist id (see artist_id())
+fn unique(names: impl IntoIterator<Item = String>) -> Vec<String> {
+  let mut ids = HashSet::new();
+  names
+    .into_iter()
+    .filter(|name| artist_id(name).is_some_and(|id| ids.insert(id)))
+    .collect()
+}
+
+// Split "A feat. B" into "A" and "B". Featured artists are often between
+// parentheses or brackets, "Song (feat. B) [Remix]" gives "Song" and "B", which
+// is the only form looked for in titles (think of "A Feat of Strength").
+fn split_featuring(value: &str, bracketed_only: bool) -> (&str, Option<&str>) {
+  // ASCII lowercase keeps the byte offsets
+  let lowercase = value.to_ascii_lowercase();
+  for (start, previous) in value.char_indices() {
+    if !matches!(previous, '(' | '[') && (bracketed_only || previous != ' ') {
+      continue;
+    }
+    let at = start + 1;
+    let Some(pattern) = FEATURING.iter().find(|pattern| {
+      lowercase[at..].starts_with(*pattern)
+        && lowercase[at + pattern.len()..].starts_with(char::is_whitespace)
+    }) else {
+      continue;
+    };
+    let featured = &value[at + pattern.len()..];
+    let featured = match previous {
+      '(' => featured.split(')').next().unwrap_or(""),
+      '[' => featured.split(']').next().unwrap_or(""),
+      _ => featured,
+    };
+    return (value[..at - 1].trim_end(), Some(featured.trim()));
+  }
+  (value, None)
+}
+
+impl Credits {
+  pub fn new(
+    artists: &[String],
+    album_artists: &[String],
+    title: Option<&str>,
+    separators: &[String],
+  ) -> Credits {
+    let featured_separators = separators
+      .iter()
+      .cloned()
+      .chain(FEATURED_SEPARATORS.map(String::from))
+      .collect::<Vec<String>>();
+    let mut credits = Credits::default();
+    for value in artists {
+      let (main, featured) = split_featuring(value, false);
+      credits.artists.extend(split_values(main, separators));
+      if let Some(featured) = featured {
+        credits
+          .featured
+          .extend(split_values(featured, &featured_separators));
+      }
+    }
+    if let Some((_, Some(featured))) = title.map(|title| split_featuring(title, true)) {
+      credits
+        .featured
+        .extend(split_values(featured, &featured_separators));
+    }
+    for value in album_artists {
+      credits
+        .album_artists
+        .extend(split_values(split_featuring(value, false).0, separators));
+    }
+    if credits.album_artists.is_empty() {
+      credits.album_artists = credits.artists.clone();
+    }
+    // An artist is credited once per role, and is not featured on their songs
+    credits.artists = unique(credits.artists);
+    credits.album_artists = unique(credits.album_artists);
+    let artist_ids = credits
+      .artists
+      .iter()
+      .filter_map(|name| artist_id(name))
+      .collect::<HashSet<String>>();
+    credits.featured = unique(credits.featured)
+      .into_iter()
+      .filter(|name| artist_id(name).is_some_and(|id| !artist_ids.contains(&id)))
+      .collect();
+    credits
+  }
+
+  // The sort tags name the same artists as the artist tags, in the same order,
+  // so they are only used when they split into as many names. The names
+  // without their leading article are used otherwise.
+  pub fn from_tags(tags: &Tags, separators: &[String], articles: &[String]) -> Credits {
+    let mut credits =
+      Credits::new(&tags.artists, &tags.album_artists, tags.title.as_deref(), separators);
+    let sort_tags = [
+      (&credits.artists, &tags.artist_sorts),
+      (&credits.album_artists, &tags.album_artist_sorts),
+    ];
+    for (names, sorts) in sort_tags {
+      let sorts = sorts
+        .iter()
+        .flat_map(|sort| split_values(split_featuring(sort, false).0, separators))
+        .collect::<Vec<String>>();
+      if sorts.len() != names.len() {
+        continue;
+      }
+      for (name, sort) in names.iter().zip(sorts) {
+        if let Some(id) = artist_id(name) {
+          credits.sort_names.entry(id).or_insert(sort);
+        }
+      }
+    }
+    let names = credits
+      .artists
+      .iter()
+      .chain(&credits.featured)
+      .chain(&credits.album_artists);
+    for name in names {
+      if let Some(id) = artist_id(name) {
+        credits
+          .sort_names
+          .entry(id)
+          .or_insert_with(|| sorting::sort_name(name, articles));
+      }
+    }
+    credits
+  }
+
+  // Replace the credits of the song `song_id`
+  pub fn store(&self, connection: &Connection, song_id: &str) -> Result<()> {
+    execute_query(
+      connection,
+      "DELETE FROM song_artists WHERE song_id = ?;",
+      &[Value::String(song_id.to_string())],
+    )?;
+    let roles = [
+      ("artist", &self.artists),
+      ("featured", &self.featured),
+      ("album_artist", &self.album_artists),
+    ];
+    for (role, names) in roles {
+      for (position, name) in names.iter().enumerate() {
+        let Some(id) = artist_id(name) else {
+          continue;
+        };
+        execute_query(
+          connection,
+          "INSERT OR IGNORE INTO song_artists (song_id, artist_id, name, sort_name, role, position) \
+           VALUES (?, ?, ?, ?, ?, ?);",
+          &[
+            Value::String(song_id.to_string()),
+            Value::String(id.clone()),
+            Value::String(name.clone()),
+            Value::String(self.sort_names.get(&id).unwrap_or(name).clone()),
+            Value::String(role.to_string()),
+            Value::Integer(position as i64),
+          ],
+        )?;
+      }
+    }
+    Ok(())
+  }
+}
+
+// Remove the credits of the songs which are gone
+pub fn remove_unused(connection: &Connection) -> Result<()> {
+  execute_query(
+    connection,
+    "DELETE FROM song_artists WHERE song_id NOT IN (SELECT id FROM songs);",
+    &[],
+  )?;
+  Ok(())
+}
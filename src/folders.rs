@@ -0,0 +1,301 @@
+// The songs by folder, for the libraries organized by directory rather than by
+// tags. The folders are the ones under the scan roots, which every scan
+// records. A folder is identified by the id of its root and its path relative
+// to it, with / as separator, so the absolute paths of the server are not
+// exposed: the songs of a folder come with their path relative to the root.
+use std::path::{Path, MAIN_SEPARATOR};
+
+use anyhow::Result;
+use base64ct::{Base64UrlUnpadded, Encoding};
+use md5::Digest;
+use serde::{Deserialize, Serialize};
+use sqlite::{Connection, Value};
+
+use crate::{execute_query, Song};
+
+// A scan root, identified by the hash of its path
+struct Root {
+  id: String,
+  path: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RootFolder {
+  pub id: String,
+  // The last component of its path
+  pub name: String,
+  pub song_count: u32,
+  pub cover_url: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Subfolder {
+  pub name: String,
+  pub path: String,
+  // Of the whole subtree
+  pub song_count: u32,
+  pub cover_url: Option<String>,
+}
+
+// A folder, with its subfolders and songs by name
+#[derive(Debug, Serialize)]
+pub struct Folder {
+  pub root_id: String,
+  pub name: String,
+  // Empty for the root
+  pub path: String,
+  // None for the root, which has no parent
+  pub parent: Option<String>,
+  pub cover_url: Option<String>,
+  pub folders: Vec<Subfolder>,
+  pub songs: Vec<Song>,
+}
+
+// The folder is the root when there is no path
+#[derive(Debug, Deserialize)]
+pub struct FolderParams {
+  pub path: Option<String>,
+}
+
+// The separator of the paths on the server, and the character after it: the
+// paths under a folder are those between the folder followed by them, which
+// makes a range query on the index of the path column
+pub fn separators() -> (char, char) {
+  let after = char::from_u32(MAIN_SEPARATOR as u32 + 1).unwrap_or(char::MAX);
+  (MAIN_SEPARATOR, after)
+}
+
+fn subtree_bounds(folder: &str) -> [Value; 2] {
+  let (separator, after) = separators();
+  [
+    Value::String(format!("{}{}", folder, separator)),
+    Value::String(format!("{}{}", folder, after)),
+  ]
+}
+
+// The components of a relative path given by a client, None if it is invalid
+// or tries to get out of the root
+pub fn parse_path(path: Option<&str>) -> Option<Vec<&str>> {
+  let path = path.unwrap_or("").trim_matches('/');
+  if path.is_empty() {
+    return Some(Vec::new());
+  }
+  let components = path.split('/').collect::<Vec<&str>>();
+  let valid = components
+    .iter()
+    .all(|c| !c.is_empty() && *c != "." && *c != ".." && !c.contains(MAIN_SEPARATOR));
+  valid.then_some(components)
+}
+
+// Query strings only allow some characters as is
+fn encode_query_value(value: &str) -> String {
+  let mut encoded = String::new();
+  for byte in value.bytes() {
+    if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+      encoded.push(byte as char);
+    } else {
+      encoded.push_str(&format!("%{:02X}", byte));
+    }
+  }
+  encoded
+}
+
+fn cover_url(root_id: &str, path: &str) -> String {
+  if path.is_empty() {
+    format!("/folders/{}/cover", root_id)
+  } else {
+    format!("/folders/{}/cover?path={}", root_id, encode_query_value(path))
+  }
+}
+
+// Record the folder scanned as a root. Song paths start with it as given.
+pub fn add_root(connection: &Connection, path: &Path) -> Result<()> {
+  let path = path.to_string_lossy();
+  let path = path.trim_end_matches(MAIN_SEPARATOR);
+  let id = Base64UrlUnpadded::encode_string(&md5::Md5::digest(path.as_bytes()));
+  execute_query(
+    connection,
+    "INSERT OR IGNORE INTO scan_roots (id, path) VALUES (?, ?);",
+    &[Value::String(id), Value::String(path.to_string())],
+  )?;
+  Ok(())
+}
+
+fn roots(connection: &Connection, id: Option<&str>) -> Result<Vec<Root>> {
+  let rows = match id {
+    Some(id) => execute_query(
+      connection,
+      "SELECT id, path FROM scan_roots WHERE id = ?;",
+      &[Value::String(id.to_string())],
+    )?,
+    None => execute_query(connection, "SELECT id, path FROM scan_roots ORDER BY path;", &[])?,
+  };
+  Ok(
+    rows
+      .iter()
+      .filter_map(|row| {
+        Some(Root {
+          id: row.get("id")?.clone(),
+          path: row.get("path")?.clone(),
+        })
+      })
+      .collect(),
+  )
+}
+
+// The scan roots with songs
+pub fn get_roots(connection: &Connection) -> Result<Vec<RootFolder>> {
+  let mut folders = Vec::new();
+  for root in roots(connection, None)? {
+    let rows = execute_query(
+      connection,
+      "SELECT COUNT(*) AS song_count, MAX(cover_id) AS cover_id FROM songs \
+       WHERE path > ? AND path < ? AND missing_since IS NULL;",
+      &subtree_bounds(&root.path),
+    )?;
+    let Some(row) = rows.first() else {
+      continue;
+    };
+    let song_count = row
+      .get("song_count")
+      .and_then(|c| c.parse().ok())
+      .unwrap_or(0);
+    if song_count == 0 {
+      continue;
+    }
+    folders.push(RootFolder {
+      name: Path::new(&root.path)
+        .file_name()
+        .map_or(root.path.clone(), |name| name.to_string_lossy().to_string()),
+      song_count,
+      cover_url: row
+        .contains_key("cover_id")
+        .then(|| cover_url(&root.id, "")),
+      id: root.id,
+    });
+  }
+  Ok(folders)
+}
+
+// The absolute path of the folder and its relative path, with / as separator
+fn locate(
+  connection: &Connection,
+  root_id: &str,
+  components: &[&str],
+) -> Result<Option<(Root, String, String)>> {
+  let Some(root) = roots(connection, Some(root_id))?.pop() else {
+    return Ok(None);
+  };
+  let mut folder = root.path.clone();
+  for component in components {
+    folder = format!("{}{}{}", folder, MAIN_SEPARATOR, component);
+  }
+  Ok(Some((root, folder, components.join("/"))))
+}
+
+pub fn get(connection: &Connection, root_id: &str, components: &[&str]) -> Result<Option<Folder>> {
+  let Some((root, folder, path)) = locate(connection, root_id, components)? else {
+    return Ok(None);
+  };
+  let (separator, _) = separators();
+  let [lower, upper] = subtree_bounds(&folder);
+  // The paths under the folder, without the folder and the separator
+  let start = Value::Integer(folder.chars().count() as i64 + 2);
+  let separator = Value::String(separator.to_string());
+  let rows = execute_query(
+    connection,
+    "SELECT SUBSTR(rest, 1, INSTR(rest, ?) - 1) AS name, COUNT(*) AS song_count, \
+     MAX(cover_id) AS cover_id \
+     FROM (SELECT SUBSTR(path, ?) AS rest, cover_id FROM songs \
+     WHERE path > ? AND path < ? AND missing_since IS NULL) \
+     WHERE INSTR(rest, ?) > 0 GROUP BY name ORDER BY name COLLATE NOCASE;",
+    &[
+      separator.clone(),
+      start.clone(),
+      lower.clone(),
+      upper.clone(),
+      separator.clone(),
+    ],
+  )?;
+  let folders = rows
+    .iter()
+    .filter_map(|row| {
+      let name = row.get("name")?.clone();
+      let subfolder_path =
+        if path.is_empty() { name.clone() } else { format!("{}/{}", path, name) };
+      Some(Subfolder {
+        song_count: row.get("song_count")?.parse().ok()?,
+        cover_url: row
+          .contains_key("cover_id")
+          .then(|| cover_url(&root.id, &subfolder_path)),
+        path: subfolder_path,
+        name,
+      })
+    })
+    .collect::<Vec<Subfolder>>();
+  let mut songs = Song::select(
+    connection,
+    "SELECT * FROM songs WHERE path > ? AND path < ? AND INSTR(SUBSTR(path, ?), ?) = 0 \
+     AND missing_since IS NULL ORDER BY path;",
+    &[lower, upper, start, separator],
+  )?;
+  // A folder exists as long as it has songs, the root always does
+  if !path.is_empty() && folders.is_empty() && songs.is_empty() {
+    return Ok(None);
+  }
+  for song in songs.iter_mut() {
+    song.path = match song.path.get(folder.len() + 1..) {
+      Some(name) if path.is_empty() => name.to_string(),
+      Some(name) => format!("{}/{}", path, name),
+      None => String::new(),
+    };
+  }
+  let cover_url = cover_id(connection, &folder)?.map(|_| cover_url(&root.id, &path));
+  Ok(Some(Folder {
+    name: components
+      .last()
+      .map(|name| name.to_string())
+      .or_else(|| {
+        Path::new(&root.path)
+          .file_name()
+          .map(|n| n.to_string_lossy().to_string())
+      })
+      .unwrap_or_else(|| root.path.clone()),
+    parent: (!components.is_empty()).then(|| components[..components.len() - 1].join("/")),
+    root_id: root.id,
+    path,
+    cover_url,
+    folders,
+    songs,
+  }))
+}
+
+// The image file of the folder, or the cover of its first song having one
+fn cover_id(connection: &Connection, folder: &str) -> Result<Option<String>> {
+  let rows = execute_query(
+    connection,
+    "SELECT cover_id FROM folder_covers WHERE path = ?;",
+    &[Value::String(folder.to_string())],
+  )?;
+  if let Some(cover_id) = rows.first().and_then(|row| row.get("cover_id")) {
+    return Ok(Some(cover_id.clone()));
+  }
+  let rows = execute_query(
+    connection,
+    "SELECT cover_id FROM songs WHERE path > ? AND path < ? AND cover_id IS NOT NULL \
+     ORDER BY path LIMIT 1;",
+    &subtree_bounds(folder),
+  )?;
+  Ok(rows.first().and_then(|row| row.get("cover_id")).cloned())
+}
+
+pub fn get_cover_id(
+  connection: &Connection,
+  root_id: &str,
+  components: &[&str],
+) -> Result<Option<String>> {
+  match locate(connection, root_id, components)? {
+    Some((_, folder, _)) => cover_id(connection, &folder),
+    None => Ok(None),
+  }
+}
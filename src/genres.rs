@@ -0,0 +1,176 @@
+// Genres are not stored either, they are the values of the genre tags of the
+// songs (see Song::genre), identified like artists by the hash of their name.
+// Songs and genres are linked in the song_genres table.
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlite::{Connection, Value};
+
+use crate::albums::Album;
+use crate::sorting::{SortBy, SortKey, SortParams};
+use crate::{artist_id, credits, execute_query, pagination, parse_column};
+
+const GENRE_COLUMNS: &str = "genre_id AS id, MIN(name) AS name, \
+  COUNT(DISTINCT song_id) AS song_count, COUNT(DISTINCT album_id) AS album_count";
+const GENRE_SONGS: &str = "song_genres JOIN songs ON songs.id = song_genres.song_id";
+
+// The ids of the albums with songs of a genre
+const GENRE_ALBUM_IDS: &str = "SELECT songs.album_id FROM song_genres \
+  JOIN songs ON songs.id = song_genres.song_id \
+  WHERE song_genres.genre_id = ? AND songs.album_id IS NOT NULL AND songs.missing_since IS NULL";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GenreSort {
+  #[default]
+  Name,
+  SongCount,
+  AlbumCount,
+}
+
+impl SortKey for GenreSort {
+  fn is_name(self) -> bool {
+    self == GenreSort::Name
+  }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Genre {
+  pub id: String,
+  pub name: String,
+  pub song_count: u32,
+  // Albums with at least one song of the genre
+  pub album_count: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GenreWithAlbums {
+  #[serde(flatten)]
+  pub genre: Genre,
+  pub albums: pagination::Page<Album>,
+}
+
+pub fn genre_id(name: &str) -> Option<String> {
+  artist_id(name)
+}
+
+// The genres of a song, which Song::genre lists separated by ";"
+pub fn names(genre: &str) -> Vec<String> {
+  credits::split_values(genre, &[";".to_string()])
+}
+
+// Replace the genres of the song `song_id`
+pub fn store(connection: &Connection, song_id: &str, genre: Option<&str>) -> Result<()> {
+  execute_query(
+    connection,
+    "DELETE FROM song_genres WHERE song_id = ?;",
+    &[Value::String(song_id.to_string())],
+  )?;
+  for name in genre.map(names).unwrap_or_default() {
+    let Some(id) = genre_id(&name) else {
+      continue;
+    };
+    execute_query(
+      connection,
+      "INSERT OR IGNORE INTO song_genres (song_id, genre_id, name) VALUES (?, ?, ?);",
+      &[
+        Value::String(song_id.to_string()),
+        Value::String(id),
+        Value::String(name),
+      ],
+    )?;
+  }
+  Ok(())
+}
+
+// Remove the genres of the songs which are gone
+pub fn remove_unused(connection: &Connection) -> Result<()> {
+  execute_query(
+    connection,
+    "DELETE FROM song_genres WHERE song_id NOT IN (SELECT id FROM songs);",
+    &[],
+  )?;
+  Ok(())
+}
+
+impl Genre {
+  fn from_row(row: &HashMap<String, String>) -> Result<Genre> {
+    Ok(Genre {
+      id: parse_column(row, "song_genres", "id")?,
+      name: parse_column(row, "song_genres", "name")?,
+      song_count: parse_column(row, "song_genres", "song_count")?,
+      album_count: parse_column(row, "song_genres", "album_count")?,
+    })
+  }
+
+  fn select(connection: &Connection, query: &str, values: &[Value]) -> Result<Vec<Genre>> {
+    execute_query(connection, query, values)?
+      .iter()
+      .map(Genre::from_row)
+      .collect()
+  }
+
+  pub fn get(connection: &Connection, id: &str) -> Result<Option<Genre>> {
+    let query = format!(
+      "SELECT {} FROM {} WHERE genre_id = ? AND songs.missing_since IS NULL GROUP BY genre_id;",
+      GENRE_COLUMNS, GENRE_SONGS
+    );
+    Ok(Genre::select(connection, &query, &[Value::String(id.to_string())])?.pop())
+  }
+
+  pub fn count(connection: &Connection) -> Result<u64> {
+    let query = format!(
+      "SELECT COUNT(DISTINCT genre_id) AS count FROM {} WHERE songs.missing_since IS NULL;",
+      GENRE_SONGS
+    );
+    pagination::count(connection, &query, &[])
+  }
+
+  pub fn get_all_with_pagination(
+    connection: &Connection,
+    sort: &SortParams<GenreSort>,
+    offset: u32,
+    limit: u32,
+  ) -> Result<Vec<Genre>> {
+    let order_by = sort.order_by(
+      |sort| match sort {
+        GenreSort::Name => "name",
+        GenreSort::SongCount => "song_count",
+        GenreSort::AlbumCount => "album_count",
+      },
+      GenreSort::Name,
+      "id",
+    );
+    let query = format!(
+      "SELECT {} FROM {} WHERE songs.missing_since IS NULL \
+       GROUP BY genre_id ORDER BY {} LIMIT ? OFFSET ?;",
+      GENRE_COLUMNS, GENRE_SONGS, order_by
+    );
+    Genre::select(
+      connection,
+      &query,
+      &[Value::Integer(limit as i64), Value::Integer(offset as i64)],
+    )
+  }
+
+  // A page of the albums with songs of the genre, and their total
+  pub fn albums(
+    &self,
+    connection: &Connection,
+    sort: &SortParams<SortBy>,
+    offset: u32,
+    limit: u32,
+  ) -> Result<(Vec<Album>, u64)> {
+    let values = [Value::String(self.id.clone())];
+    let albums = Album::get_matching_with_pagination(
+      connection,
+      GENRE_ALBUM_IDS,
+      &values,
+      sort,
+      offset,
+      limit,
+    )?;
+    Ok((albums, Album::count_matching(connection, GENRE_ALBUM_IDS, &values)?))
+  }
+}
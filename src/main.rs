@@ -1,26 +1,26 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, Write};
 use std::net::Ipv4Addr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::Result;
 use axum::{
-  http::{header, StatusCode, Uri},
+  http::{header, HeaderMap, HeaderName, StatusCode, Uri},
   response::{IntoResponse, Redirect},
-  routing::get,
+  routing::{delete, get, post},
   Json, Router,
 };
 use axum_macros;
-use base64ct::{Base64, Encoding};
-use clap::Parser;
-use id3::{Tag, TagLike};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use clap::{Parser, Subcommand};
 use jwalk::WalkDir;
 use md5::Digest;
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
-use sqlite::{Connection, ConnectionThreadSafe, State};
+use sqlite::{Connection, ConnectionThreadSafe, State, Value};
 use struct_iterable::Iterable;
 use tower_http::{
   services::ServeDir,
@@ -29,12 +29,33 @@ use tower_http::{
 use tracing::Level;
 use tracing_subscriber;
 
+use albums::{Album, AlbumWithSongs};
+use artists::{Artist, ArtistWithAlbums};
 use field_list::FieldList;
+use genres::{Genre, GenreWithAlbums};
+use playlists::{Playlist, PlaylistError};
+use years::{Decade, DecadeWithAlbums, Year, YearWithAlbums};
+
+mod albums;
+mod artists;
+mod covers;
+mod credits;
+mod folders;
+mod genres;
+mod migrations;
+mod pagination;
+mod playlists;
+mod search;
+mod sorting;
+mod streaming;
+mod tags;
+mod thumbnails;
+mod years;
 
 #[derive(Parser, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Config {
-  /// Scan path for mp3s to be added to the database
+  /// Scan path for music files to be added to the database
   #[arg(short = 's', long, value_name = "PATH")]
   scan_path: Option<PathBuf>,
   /// Database
@@ -58,29 +79,112 @@ struct Config {
   /// Do not use a database transaction during scanning (slower)
   #[arg(short = 't', long, default_value = "false")]
   do_not_use_transaction: bool,
+  /// Mark the songs whose file disappeared as missing instead of removing them
+  #[arg(long, default_value = "false")]
+  keep_missing: bool,
+  /// Cover thumbnails cache folder
+  #[arg(long, default_value = PathBuf::from("rstream-cache").into_os_string(), value_name = "PATH")]
+  cache_folder: PathBuf,
+  /// Maximum size of the cover thumbnails cache in MiB
+  #[arg(long, default_value = "256", value_name = "MIB")]
+  cache_max_size: u64,
+  /// Separator of the artists or genres in a tag value, can be repeated
+  #[arg(long = "separator", value_name = "SEPARATOR", default_values_t = credits::DEFAULT_SEPARATORS.map(String::from))]
+  separators: Vec<String>,
+  /// Leading article ignored when sorting artists and albums, can be repeated
+  #[arg(long = "article", value_name = "ARTICLE", default_values_t = sorting::DEFAULT_ARTICLES.map(String::from))]
+  articles: Vec<String>,
+  /// Header naming the user of a request, such as X-Forwarded-User. Only to be
+  /// set behind an authenticating reverse proxy which sets it, as it is trusted
+  /// from any client. Without it, all the requests are from the same anonymous
+  /// user.
+  #[arg(long, value_name = "HEADER")]
+  user_header: Option<HeaderName>,
+  #[command(subcommand)]
+  command: Option<Command>,
+}
+
+#[derive(Subcommand, Clone)]
+enum Command {
+  /// Migrate the database to the latest schema version
+  Migrate {
+    /// Only show the pending migrations
+    #[arg(long, default_value = "false")]
+    dry_run: bool,
+  },
+  /// Manage the cover thumbnails cache
+  Cache {
+    #[command(subcommand)]
+    command: CacheCommand,
+  },
 }
 
-trait Identifiable {
-  fn id(&self) -> &String;
+#[derive(Subcommand, Clone)]
+enum CacheCommand {
+  /// Remove the thumbnails of covers which are gone, then the least recently
+  /// used ones above the maximum size of the cache
+  Prune,
 }
 
 #[derive(Debug, Iterable, Serialize, Deserialize, FieldList)]
+#[field_list(table = "songs")]
 struct Song {
+  #[field_list(primary_key)]
   id: String,
+  #[field_list(index)]
   path: String,
+  // Size and modification time (in seconds since epoch) of the file when it was
+  // scanned, to detect changes on rescan
+  size: u64,
+  mtime: i64,
+  // When the file was found missing (in seconds since epoch), if it was. Such
+  // songs are only kept for when the file comes back, they are left out of the
+  // lists, counts and searches.
+  missing_since: Option<i64>,
   title: Option<String>,
+  #[field_list(index)]
   artist: Option<String>,
+  #[field_list(index)]
   album: Option<String>,
+  #[field_list(index)]
   year: Option<i32>,
-  // comment: String,
   track: Option<u32>,
   disc: Option<u32>,
-}
-
-impl Identifiable for Song {
-  fn id(&self) -> &String {
-    return &self.id;
-  }
+  #[field_list(index)]
+  genre: Option<String>,
+  #[field_list(index)]
+  album_artist: Option<String>,
+  composer: Option<String>,
+  comment: Option<String>,
+  total_tracks: Option<u32>,
+  total_discs: Option<u32>,
+  bpm: Option<u32>,
+  original_date: Option<String>,
+  label: Option<String>,
+  isrc: Option<String>,
+  musicbrainz_album_id: Option<String>,
+  // Properties of the audio stream, bitrate being the average in kbit/s
+  duration_ms: Option<u64>,
+  bitrate: Option<u32>,
+  sample_rate: Option<u32>,
+  channels: Option<u32>,
+  codec: Option<String>,
+  // See album_id() and artist_id(). When a song has several artists or album
+  // artists (see credits), these are the ids of the first ones.
+  #[field_list(index)]
+  album_id: Option<String>,
+  #[field_list(index)]
+  artist_id: Option<String>,
+  #[field_list(index)]
+  album_artist_id: Option<String>,
+  #[field_list(index)]
+  cover_id: Option<String>,
+  // From the sort tag, or the album without its leading article
+  album_sort: Option<String>,
+  // When the song was first scanned, in seconds since epoch. It is kept when
+  // the file is moved or its content changes.
+  #[field_list(default = 0)]
+  added_at: i64,
 }
 
 impl Default for Song {
@@ -88,48 +192,181 @@ impl Default for Song {
     Song {
       id: "".to_string(),
       path: "".to_string(),
+      size: 0,
+      mtime: 0,
+      missing_since: None,
       title: None,
       artist: None,
       album: None,
       year: None,
-      // comment: "".to_string(),
       track: None,
       disc: None,
+      genre: None,
+      album_artist: None,
+      composer: None,
+      comment: None,
+      total_tracks: None,
+      total_discs: None,
+      bpm: None,
+      original_date: None,
+      label: None,
+      isrc: None,
+      musicbrainz_album_id: None,
+      duration_ms: None,
+      bitrate: None,
+      sample_rate: None,
+      channels: None,
+      codec: None,
+      album_id: None,
+      artist_id: None,
+      album_artist_id: None,
+      cover_id: None,
+      album_sort: None,
+      added_at: 0,
     }
   }
 }
 
-// There is some \0 in some songs tags, filter them
+// There is some \0 in some songs tags, filter them. Values are bound to the
+// SQL statements so quotes do not need escaping.
 fn clean_string(s: &str) -> String {
-  s.replace(|c: char| (c as u8) < 32, "")
-    // https://www.sqlite.org/faq.html#q14
-    .replace("\"", "\"\"")
-    .replace("\'", "\'\'")
-    .to_string()
+  s.replace(|c: char| c.is_control(), "")
 }
 
 impl Song {
-  pub fn from_tags(path: &Path, tag: &id3::Tag) -> Result<Song> {
-    Ok(Song {
+  pub fn from_tags(
+    path: &Path,
+    metadata: &fs::Metadata,
+    tags: &tags::Tags,
+    properties: &tags::AudioProperties,
+    credits: &credits::Credits,
+    config: &Config,
+  ) -> Result<Song> {
+    // The artists as found in the tags, the credits have them one by one
+    let join = |values: &[String]| (!values.is_empty()).then(|| clean_string(&values.join("; ")));
+    let mut song = Song {
       id: md5sum(&path)?,
       path: path.to_string_lossy().to_string(),
-      title: tag.title().map(|s| clean_string(s)),
-      artist: tag.artist().map(|s| clean_string(s)),
-      album: tag.album().map(|s| clean_string(s)),
-      year: tag.year(),
-      // comment,
-      track: tag.track(),
-      disc: tag.disc(),
+      size: metadata.len(),
+      mtime: mtime(metadata),
+      title: tags.title.as_ref().map(|s| clean_string(s)),
+      artist: join(&tags.artists),
+      album: tags.album.as_ref().map(|s| clean_string(s)),
+      year: tags.year,
+      track: tags.track,
+      disc: tags.disc,
+      genre: tags
+        .genre
+        .as_ref()
+        .map(|s| credits::split_values(&clean_string(s), &config.separators).join("; "))
+        .filter(|s| !s.is_empty()),
+      album_artist: join(&tags.album_artists),
+      composer: tags.composer.as_ref().map(|s| clean_string(s)),
+      // Comments can span several lines
+      comment: tags.comment.as_ref().map(|s| s.replace('\0', "")),
+      total_tracks: tags.total_tracks,
+      total_discs: tags.total_discs,
+      bpm: tags.bpm,
+      original_date: tags.original_date.as_ref().map(|s| clean_string(s)),
+      label: tags.label.as_ref().map(|s| clean_string(s)),
+      isrc: tags.isrc.as_ref().map(|s| clean_string(s)),
+      musicbrainz_album_id: tags.musicbrainz_album_id.as_ref().map(|s| clean_string(s)),
+      duration_ms: properties.duration_ms,
+      bitrate: properties.bitrate,
+      sample_rate: properties.sample_rate,
+      channels: properties.channels,
+      codec: properties.codec.clone(),
+      added_at: std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64),
       ..Default::default()
-    })
+    };
+    song.album_sort = match (&tags.album_sort, &song.album) {
+      (Some(album_sort), _) => Some(clean_string(album_sort)),
+      (None, Some(album)) => Some(sorting::sort_name(album, &config.articles)),
+      (None, None) => None,
+    };
+    song.album_id = song
+      .album
+      .as_deref()
+      .filter(|album| !album.trim().is_empty())
+      .map(|album| {
+        let artist = song.album_artist.as_deref().or(song.artist.as_deref());
+        album_id(artist, album, song.year, song.musicbrainz_album_id.as_deref())
+      });
+    song.artist_id = credits.artists.first().and_then(|name| artist_id(name));
+    song.album_artist_id = credits
+      .album_artists
+      .first()
+      .and_then(|name| artist_id(name));
+    Ok(song)
   }
 }
 
+// Hash of the audio content of the file, tags excluded, so that the id of a song
+// does not change when its tags are edited. Encoded in URL safe base64 to be
+// used in URLs as is.
 fn md5sum(path: &Path) -> Result<String> {
   let mut file = fs::File::open(&path)?;
   let mut hasher = md5::Md5::new();
-  let _n = io::copy(&mut file, &mut hasher)?;
-  return Ok(Base64::encode_string(&hasher.finalize()));
+  for range in tags::audio_ranges(path)? {
+    file.seek(io::SeekFrom::Start(range.start))?;
+    let _n = io::copy(&mut (&mut file).take(range.end - range.start), &mut hasher)?;
+  }
+  return Ok(Base64UrlUnpadded::encode_string(&hasher.finalize()));
+}
+
+// Albums are identified by their MusicBrainz id when known, otherwise by the
+// hash of their artist, title and year so that different albums with the same
+// title are not merged. The album artist is preferred so that compilations are
+// not split by song artist.
+fn album_id(
+  artist: Option<&str>,
+  album: &str,
+  year: Option<i32>,
+  musicbrainz_album_id: Option<&str>,
+) -> String {
+  let mut hasher = md5::Md5::new();
+  if let Some(musicbrainz_album_id) = musicbrainz_album_id {
+    hasher.update(b"musicbrainz\0");
+    hasher.update(musicbrainz_album_id.trim().to_lowercase().as_bytes());
+  } else {
+    hasher.update(artist.unwrap_or("").trim().to_lowercase().as_bytes());
+    hasher.update(b"\0");
+    hasher.update(album.trim().to_lowercase().as_bytes());
+    if let Some(year) = year {
+      hasher.update(format!("\0{}", year).as_bytes());
+    }
+  }
+  Base64UrlUnpadded::encode_string(&hasher.finalize())
+}
+
+// Artists are identified by the hash of their name, case insensitively
+fn artist_id(name: &str) -> Option<String> {
+  let name = name.trim().to_lowercase();
+  if name.is_empty() {
+    return None;
+  }
+  Some(Base64UrlUnpadded::encode_string(&md5::Md5::digest(name.as_bytes())))
+}
+
+// The new id of a song known by an id from before the migration
+fn resolve_song_alias(connection: &Connection, id: &str) -> Result<Option<String>> {
+  let query = "SELECT new_id FROM song_aliases WHERE old_id = ?;";
+  Ok(
+    execute_query(connection, query, &[Value::String(id.to_string())])?
+      .first()
+      .and_then(|row| row.get("new_id").cloned()),
+  )
+}
+
+// Modification time of a file in seconds since epoch, 0 if not available
+fn mtime(metadata: &fs::Metadata) -> i64 {
+  metadata
+    .modified()
+    .ok()
+    .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+    .map_or(0, |d| d.as_secs() as i64)
 }
 
 fn truncate(s: &str, max_chars: usize) -> String {
@@ -139,48 +376,196 @@ fn truncate(s: &str, max_chars: usize) -> String {
   }
 }
 
-// Scans the provided folder for all files and for every file which has id3 tags
-// compute a md5 hash and create (or update, or do nothing) en entry in the
-// database
+// Scans the provided folder for all files and for every file which has tags
+// (ID3, Vorbis comments, MP4 or APE) compute a md5 hash and create (or update,
+// or do nothing) en entry in the database.
+// Files whose size and modification time did not change since the last scan
+// are skipped without being read. Files which moved keep their entry (the id
+// being the hash of the content) and entries of files which disappeared from
+// the scanned folder are removed, or marked as missing.
 fn scan(data_path: &Path, config: &Config) -> Result<()> {
   let connection = Connection::open(&config.database)?;
 
-  // We create a table containing all the fields of the struct we want to store.
-  // The type we iterate on must be struct_iterable::Iterable.
-  Song::create_table(&connection, "songs")?;
+  migrations::run(&connection)?;
 
-  let on_a_tty = atty::is(atty::Stream::Stdout);
-  let mut file_count = 0;
   if !config.do_not_use_transaction {
     connection.execute("BEGIN TRANSACTION;")?;
   }
+  folders::add_root(&connection, data_path)?;
+
+  // What we know of the files from the previous scans, indexed by path
+  struct KnownFile {
+    id: String,
+    size: u64,
+    mtime: i64,
+    missing: bool,
+    added_at: i64,
+  }
+  let known_files = execute_query(
+    &connection,
+    "SELECT id, path, size, mtime, missing_since, added_at FROM songs;",
+    &[],
+  )?
+  .iter()
+  .filter_map(|row| {
+    let known_file = KnownFile {
+      id: row.get("id")?.to_owned(),
+      size: row.get("size")?.parse::<u64>().ok()?,
+      mtime: row.get("mtime")?.parse::<i64>().ok()?,
+      missing: row.contains_key("missing_since"),
+      added_at: row.get("added_at")?.parse::<i64>().ok()?,
+    };
+    Some((row.get("path")?.to_owned(), known_file))
+  })
+  .collect::<HashMap<String, KnownFile>>();
+  let paths_by_id = known_files
+    .iter()
+    .map(|(path, known_file)| (known_file.id.as_str(), path.as_str()))
+    .collect::<HashMap<&str, &str>>();
+  // The ids of the songs found during this scan. Anything else under the
+  // scanned folder is gone.
+  let mut seen_ids: HashSet<String> = HashSet::new();
+  let mut covers = covers::CoverStore::load(&connection)?;
+
+  let on_a_tty = atty::is(atty::Stream::Stdout);
+  let mut file_count = 0;
+  let (mut added, mut updated, mut moved, mut unchanged) = (0, 0, 0, 0);
   for entry in WalkDir::new(data_path) {
     let entry = entry?;
     let path = entry.path();
-    if !path.is_dir() {
-      match Tag::read_from_path(&path) {
-        Ok(tag) => {
-          file_count += 1;
-          if on_a_tty {
-            let filename = path
-              .file_name()
-              .ok_or(anyhow::anyhow!("Not a file"))?
-              .to_string_lossy();
-            print!("{}{} {}", "\r\x1b[2K", file_count, truncate(&filename, 80));
-            std::io::stdout().flush()?;
+    let metadata = match fs::metadata(&path) {
+      Ok(metadata) => metadata,
+      Err(e) => {
+        tracing::debug!("error reading {} metadata ({})", path.display(), e);
+        continue;
+      }
+    };
+    if metadata.is_dir() {
+      continue;
+    }
+    let path_string = path.to_string_lossy().to_string();
+    let known_file = known_files.get(&path_string);
+    if let Some(known_file) = known_file {
+      if (known_file.size, known_file.mtime) == (metadata.len(), mtime(&metadata)) {
+        if known_file.missing {
+          // The file is back
+          execute_query(
+            &connection,
+            "UPDATE songs SET missing_since = NULL WHERE id = ?;",
+            &[Value::String(known_file.id.clone())],
+          )?;
+        }
+        seen_ids.insert(known_file.id.clone());
+        unchanged += 1;
+        continue;
+      }
+    }
+    match tags::read_from_path(&path) {
+      Ok(tags) => {
+        file_count += 1;
+        if on_a_tty {
+          let filename = path
+            .file_name()
+            .ok_or(anyhow::anyhow!("Not a file"))?
+            .to_string_lossy();
+          print!("{}{} {}", "\r\x1b[2K", file_count, truncate(&filename, 80));
+          std::io::stdout().flush()?;
+        }
+        let properties = tags::read_properties(&path).unwrap_or_else(|e| {
+          tracing::debug!("cannot read the audio properties of {} ({})", path.display(), e);
+          tags::AudioProperties::default()
+        });
+        let credits = credits::Credits::from_tags(&tags, &config.separators, &config.articles);
+        let mut song = Song::from_tags(&path, &metadata, &tags, &properties, &credits, config)?;
+        // The entry of the same audio at another place, if any
+        let previous_path = paths_by_id
+          .get(song.id.as_str())
+          .filter(|previous_path| **previous_path != path_string);
+        if let Some(known_file) = known_file {
+          song.added_at = known_file.added_at;
+          // The content changed, and so did the id. Remove the previous entry.
+          playlists::replace_song(&connection, &known_file.id, &song.id)?;
+          execute_query(
+            &connection,
+            "DELETE FROM songs WHERE path = ? AND id != ?;",
+            &[Value::String(path_string), Value::String(song.id.clone())],
+          )?;
+          // The file was seen, its previous entry is not missing
+          seen_ids.insert(known_file.id.clone());
+          updated += 1;
+          if let Some(previous_path) = previous_path.filter(|p| Path::new(p).exists()) {
+            // The file now has the same audio as another one, which keeps its
+            // entry as for a duplicate found when adding
+            tracing::debug!("{} is now a duplicate of {}", path.display(), previous_path);
+            continue;
+          }
+        } else if let Some(previous_path) = previous_path {
+          if Path::new(previous_path).exists() {
+            // The same audio is at two places, we keep the one we know
+            tracing::debug!("{} is a duplicate of {}", path.display(), previous_path);
+            continue;
           }
-          let song = Song::from_tags(&path, &tag)?;
-          song.add(&connection, "songs")?;
+          // Same audio at another place: the file was moved (or renamed).
+          // Adding the song updates the path of the existing entry.
+          if let Some(known_file) = known_files.get(*previous_path) {
+            song.added_at = known_file.added_at;
+          }
+          moved += 1;
+        } else {
+          added += 1;
         }
-        Err(e) => tracing::debug!("error reading {} id3 tags ({})", path.display(), e),
+        song.cover_id = covers.cover_id(&connection, &path)?;
+        song.add(&connection)?;
+        credits.store(&connection, &song.id)?;
+        genres::store(&connection, &song.id, song.genre.as_deref())?;
+        seen_ids.insert(song.id);
       }
+      Err(e) => tracing::debug!("error reading {} tags ({})", path.display(), e),
+    }
+  }
+
+  // Deal with the entries of the scanned folder whose file was not found
+  let now = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs());
+  let mut missing = 0;
+  for (path, known_file) in known_files.iter() {
+    if seen_ids.contains(&known_file.id) || !Path::new(path).starts_with(data_path) {
+      continue;
+    }
+    tracing::debug!("{} is missing", path);
+    let id = Value::String(known_file.id.clone());
+    if config.keep_missing {
+      execute_query(
+        &connection,
+        "UPDATE songs SET missing_since = ? WHERE id = ? AND missing_since IS NULL;",
+        &[Value::Integer(now as i64), id],
+      )?;
+    } else {
+      execute_query(&connection, "DELETE FROM songs WHERE id = ?;", &[id])?;
     }
+    missing += 1;
   }
+  covers::remove_unused(&connection)?;
+  credits::remove_unused(&connection)?;
+  genres::remove_unused(&connection)?;
+  playlists::remove_unused(&connection)?;
+  albums::refresh(&connection)?;
+  artists::refresh(&connection)?;
+
   if !config.do_not_use_transaction {
     connection.execute("END TRANSACTION;")?;
   }
 
-  println!("{}{} file(s) parsed", "\r\x1b[2K", file_count);
+  println!(
+    "\r\x1b[2K{} file(s) added, {} updated, {} moved, {} unchanged, {} {}",
+    added,
+    updated,
+    moved,
+    unchanged,
+    missing,
+    if config.keep_missing { "missing" } else { "removed" }
+  );
   Ok(())
 }
 
@@ -188,21 +573,25 @@ async fn version() -> &'static str {
   concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"))
 }
 
-#[derive(Debug, Deserialize)]
-struct Pagination {
-  page: Option<u32>,
-  per_page: Option<u32>,
-}
-
 #[axum_macros::debug_handler]
 async fn get_song(
   axum::extract::Path(song_id): axum::extract::Path<String>,
   axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
 ) -> impl IntoResponse {
-  match Song::get(&connection, "songs", &song_id) {
+  match Song::get(&connection, song_id.as_str()) {
     Ok(Some(song)) => Json(song).into_response(),
-    Ok(None) => StatusCode::NOT_FOUND.into_response(),
-    Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    Ok(None) => match resolve_song_alias(&connection, &song_id) {
+      Ok(Some(new_id)) => Redirect::permanent(&format!("/songs/{}", new_id)).into_response(),
+      Ok(None) => StatusCode::NOT_FOUND.into_response(),
+      Err(e) => {
+        tracing::error!("cannot resolve song {}: {}", song_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+      }
+    },
+    Err(e) => {
+      tracing::error!("cannot read song {}: {}", song_id, e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
   }
 }
 
@@ -210,35 +599,96 @@ async fn get_song(
 async fn get_song_file(
   axum::extract::Path(song_id): axum::extract::Path<String>,
   axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  headers: HeaderMap,
 ) -> impl IntoResponse {
-  match Song::get(&connection, "songs", &song_id) {
-    Ok(Some(song)) => match fs::File::open(&song.path) {
-      Ok(f) => {
-        let mut reader = std::io::BufReader::new(f);
-        let mut buffer = Vec::new();
-        let _ = reader.read_to_end(&mut buffer);
-
-        let mime = mime_guess::from_path(song.path).first_or_octet_stream();
-        ([(header::CONTENT_TYPE, mime.as_ref())], Into::<axum::body::Body>::into(buffer))
-          .into_response()
+  match Song::get(&connection, song_id.as_str()) {
+    Ok(Some(song)) => streaming::serve_file(Path::new(&song.path), &headers).await,
+    Ok(None) => match resolve_song_alias(&connection, &song_id) {
+      Ok(Some(new_id)) => Redirect::permanent(&format!("/song/{}", new_id)).into_response(),
+      Ok(None) => StatusCode::NOT_FOUND.into_response(),
+      Err(e) => {
+        tracing::error!("cannot resolve song {}: {}", song_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
       }
-      Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     },
-    Ok(None) => StatusCode::NOT_FOUND.into_response(),
-    Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    Err(e) => {
+      tracing::error!("cannot read song {}: {}", song_id, e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn get_song_cover(
+  axum::extract::Path(song_id): axum::extract::Path<String>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  axum::Extension(thumbnails): axum::Extension<Arc<thumbnails::Cache>>,
+  params: axum::extract::Query<covers::CoverParams>,
+  uri: Uri,
+  headers: HeaderMap,
+) -> impl IntoResponse {
+  match Song::get(&connection, song_id.as_str()) {
+    Ok(Some(Song {
+      cover_id: Some(cover_id),
+      ..
+    })) => covers::serve(&connection, thumbnails, &cover_id, &params, &headers).await,
+    Ok(Some(_)) => StatusCode::NOT_FOUND.into_response(),
+    Ok(None) => match resolve_song_alias(&connection, &song_id) {
+      // Keep the size and format of the thumbnail
+      Ok(Some(new_id)) => {
+        let query = uri
+          .query()
+          .map_or(String::new(), |query| format!("?{}", query));
+        Redirect::permanent(&format!("/songs/{}/cover{}", new_id, query)).into_response()
+      }
+      Ok(None) => StatusCode::NOT_FOUND.into_response(),
+      Err(e) => {
+        tracing::error!("cannot resolve song {}: {}", song_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+      }
+    },
+    Err(e) => {
+      tracing::error!("cannot read song {}: {}", song_id, e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
   }
 }
 
 #[axum_macros::debug_handler]
 async fn get_songs(
   axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
-  pagination: axum::extract::Query<Pagination>,
+  pagination: axum::extract::Query<pagination::Pagination>,
 ) -> impl IntoResponse {
-  let page = pagination.0.page;
-  let per_page = pagination.0.per_page;
-  match Song::get_all_with_pagination(&connection, "songs", page, per_page) {
-    Ok(songs) => Json(songs).into_response(),
-    Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+  let (cursor, offset, limit) = match pagination.cursor_offset_and_limit() {
+    Ok(page) => page,
+    Err(e) => return e.into_response(),
+  };
+  // Songs are ordered by id, the cursor is the id of the last song of a page
+  let songs = match cursor {
+    Some(cursor) => Song::select(
+      &connection,
+      "SELECT * FROM songs WHERE id > ? AND missing_since IS NULL ORDER BY id LIMIT ?;",
+      &[
+        Value::String(cursor.to_string()),
+        Value::Integer(limit as i64),
+      ],
+    ),
+    None => Song::select(
+      &connection,
+      "SELECT * FROM songs WHERE missing_since IS NULL ORDER BY id LIMIT ? OFFSET ?;",
+      &[Value::Integer(limit as i64), Value::Integer(offset as i64)],
+    ),
+  };
+  let count = "SELECT COUNT(*) AS count FROM songs WHERE missing_since IS NULL;";
+  match songs.and_then(|songs| Ok((songs, pagination::count(&connection, count, &[])?))) {
+    Ok((songs, total)) => {
+      Json(pagination::Page::with_cursor(songs, total, &pagination, |song| song.id.clone()))
+        .into_response()
+    }
+    Err(e) => {
+      tracing::error!("cannot read songs: {}", e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
   }
 }
 
@@ -247,13 +697,21 @@ struct SearchParams {
   term: String,
 }
 
-// Performs an arbitrary query on the connection
-fn execute_query(connection: &Connection, query: &str) -> Result<Vec<HashMap<String, String>>> {
-  tracing::debug!("query: {:?}", query);
-  let query = query;
+// Performs an arbitrary query on the connection, binding the values to the `?`
+// parameters of the query in order.
+fn execute_query(
+  connection: &Connection,
+  query: &str,
+  values: &[Value],
+) -> Result<Vec<HashMap<String, String>>> {
+  tracing::debug!("query: {:?} {:?}", query, values);
   let mut statement = connection.prepare(query)?;
+  for (index, value) in values.iter().enumerate() {
+    statement.bind((index + 1, value))?;
+  }
   let mut result: Vec<HashMap<String, String>> = Vec::new();
-  while let Ok(State::Row) = statement.next() {
+  // Errors (constraint violations, busy database...) are reported to the caller
+  while let State::Row = statement.next()? {
     let column_names = statement.column_names();
     let mut entries = HashMap::new();
     for column_name in column_names {
@@ -266,85 +724,530 @@ fn execute_query(connection: &Connection, query: &str) -> Result<Vec<HashMap<Str
   Ok(result)
 }
 
-fn get_offset_and_limit(pagination: &Pagination) -> (u32, u32) {
-  let page = pagination.page;
-  let per_page = pagination.per_page;
-  // TODO: This logic is already in field_list::lib.rs
-  let offset: u32;
-  let limit: u32;
-  if page.is_none() || per_page.is_none() {
-    offset = 0;
-    limit = u32::MAX;
-  } else {
-    offset = page.unwrap() * per_page.unwrap();
-    limit = per_page.unwrap();
-  }
-  (offset, limit)
+// The value of a column in a row of execute_query(). A missing (NULL) or invalid
+// value is an error naming the table and the column, as for FieldList rows.
+fn parse_column<T: std::str::FromStr>(
+  row: &HashMap<String, String>,
+  table: &str,
+  column: &str,
+) -> Result<T> {
+  parse_optional_column(row, table, column)?
+    .ok_or_else(|| anyhow::anyhow!("missing column {} in table {}", column, table))
+}
+
+// Likewise for a column which can be NULL
+fn parse_optional_column<T: std::str::FromStr>(
+  row: &HashMap<String, String>,
+  table: &str,
+  column: &str,
+) -> Result<Option<T>> {
+  let parse = |value: &String| {
+    value.parse::<T>().map_err(|_| {
+      anyhow::anyhow!("invalid value {:?} in column {} of table {}", value, column, table)
+    })
+  };
+  row.get(column).map(parse).transpose()
 }
 
 #[axum_macros::debug_handler]
 async fn get_albums(
   axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
-  pagination: axum::extract::Query<Pagination>,
+  pagination: axum::extract::Query<pagination::Pagination>,
+  sort: axum::extract::Query<sorting::SortParams>,
 ) -> impl IntoResponse {
-  let (offset, limit) = get_offset_and_limit(&pagination.0);
-  match execute_query(
-    &connection,
-    &format!(
-      r#"SELECT DISTINCT(album), artist, year, COUNT(*) as nbsongs FROM songs WHERE LENGTH(album) > 0 GROUP BY album;"#
-    ),
-  ) {
-    Ok(results) => {
-      let offset: usize = std::cmp::min(offset as usize, results.len());
-      let limit: usize = std::cmp::min(limit as usize, results.len() - offset as usize);
-      return Json(&results[offset..offset + limit]).into_response();
+  let (offset, limit) = match pagination.offset_and_limit() {
+    Ok(page) => page,
+    Err(e) => return e.into_response(),
+  };
+  let albums = Album::get_all_with_pagination(&connection, &sort, offset, limit);
+  match albums.and_then(|albums| Ok((albums, Album::count(&connection)?))) {
+    Ok((albums, total)) => Json(pagination::Page::new(albums, total, &pagination)).into_response(),
+    Err(e) => {
+      tracing::error!("cannot read albums: {}", e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
     }
-    Err(e) => tracing::error!("search failed with {}", e),
   }
-  return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+}
+
+#[axum_macros::debug_handler]
+async fn get_album(
+  axum::extract::Path(album_id): axum::extract::Path<String>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+) -> impl IntoResponse {
+  let album = match Album::get(&connection, &album_id) {
+    Ok(Some(album)) => album,
+    Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+    Err(e) => {
+      tracing::error!("cannot read album {}: {}", album_id, e);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+  match album.songs(&connection) {
+    Ok(songs) => Json(AlbumWithSongs { album, songs }).into_response(),
+    Err(e) => {
+      tracing::error!("cannot read the songs of album {}: {}", album_id, e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// The cover of an album is the one of its first song having one
+#[axum_macros::debug_handler]
+async fn get_album_cover(
+  axum::extract::Path(album_id): axum::extract::Path<String>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  axum::Extension(thumbnails): axum::Extension<Arc<thumbnails::Cache>>,
+  params: axum::extract::Query<covers::CoverParams>,
+  headers: HeaderMap,
+) -> impl IntoResponse {
+  let query = "SELECT cover_id FROM songs WHERE album_id = ? AND cover_id IS NOT NULL \
+    ORDER BY disc, track LIMIT 1;";
+  let cover_id = match execute_query(&connection, query, &[Value::String(album_id.clone())]) {
+    Ok(rows) => rows.first().and_then(|row| row.get("cover_id").cloned()),
+    Err(e) => {
+      tracing::error!("cannot read the cover of album {}: {}", album_id, e);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+  match cover_id {
+    Some(cover_id) => covers::serve(&connection, thumbnails, &cover_id, &params, &headers).await,
+    None => StatusCode::NOT_FOUND.into_response(),
+  }
 }
 
 #[axum_macros::debug_handler]
 async fn get_artists(
   axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
-  pagination: axum::extract::Query<Pagination>,
+  pagination: axum::extract::Query<pagination::Pagination>,
+  sort: axum::extract::Query<sorting::SortParams>,
 ) -> impl IntoResponse {
-  let (offset, limit) = get_offset_and_limit(&pagination.0);
-  match execute_query(
-    &connection,
-    &format!(
-      r#"SELECT artist, COUNT(*) AS nbsongs FROM songs WHERE LENGTH(artist) > 0 GROUP BY artist;"#
-    ),
-  ) {
-    Ok(results) => {
-      let offset: usize = std::cmp::min(offset as usize, results.len());
-      let limit: usize = std::cmp::min(limit as usize, results.len() - offset as usize);
-      return Json(&results[offset..offset + limit]).into_response();
+  let (offset, limit) = match pagination.offset_and_limit() {
+    Ok(page) => page,
+    Err(e) => return e.into_response(),
+  };
+  let artists = Artist::get_all_with_pagination(&connection, &sort, offset, limit);
+  match artists.and_then(|artists| Ok((artists, Artist::count(&connection)?))) {
+    Ok((artists, total)) => {
+      Json(pagination::Page::new(artists, total, &pagination)).into_response()
+    }
+    Err(e) => {
+      tracing::error!("cannot read artists: {}", e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn get_artist(
+  axum::extract::Path(artist_id): axum::extract::Path<String>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+) -> impl IntoResponse {
+  let artist = match Artist::get(&connection, &artist_id) {
+    Ok(Some(artist)) => artist,
+    Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+    Err(e) => {
+      tracing::error!("cannot read artist {}: {}", artist_id, e);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+  let albums = Album::get_by_artist(&connection, &artist.id);
+  let appearances = artist.appearances(&connection);
+  match (albums, appearances) {
+    (Ok(albums), Ok(appearances)) => Json(ArtistWithAlbums {
+      artist,
+      albums,
+      appearances,
+    })
+    .into_response(),
+    (Err(e), _) | (_, Err(e)) => {
+      tracing::error!("cannot read the songs of artist {}: {}", artist_id, e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn get_genres(
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  pagination: axum::extract::Query<pagination::Pagination>,
+  sort: axum::extract::Query<sorting::SortParams<genres::GenreSort>>,
+) -> impl IntoResponse {
+  let (offset, limit) = match pagination.offset_and_limit() {
+    Ok(page) => page,
+    Err(e) => return e.into_response(),
+  };
+  let genres = Genre::get_all_with_pagination(&connection, &sort, offset, limit);
+  match genres.and_then(|genres| Ok((genres, Genre::count(&connection)?))) {
+    Ok((genres, total)) => Json(pagination::Page::new(genres, total, &pagination)).into_response(),
+    Err(e) => {
+      tracing::error!("cannot read genres: {}", e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// A genre with a page of its albums, which the page parameters apply to
+#[axum_macros::debug_handler]
+async fn get_genre(
+  axum::extract::Path(genre_id): axum::extract::Path<String>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  pagination: axum::extract::Query<pagination::Pagination>,
+  sort: axum::extract::Query<sorting::SortParams>,
+) -> impl IntoResponse {
+  let (offset, limit) = match pagination.offset_and_limit() {
+    Ok(page) => page,
+    Err(e) => return e.into_response(),
+  };
+  let genre = match Genre::get(&connection, &genre_id) {
+    Ok(Some(genre)) => genre,
+    Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+    Err(e) => {
+      tracing::error!("cannot read genre {}: {}", genre_id, e);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+  match genre.albums(&connection, &sort, offset, limit) {
+    Ok((albums, total)) => Json(GenreWithAlbums {
+      genre,
+      albums: pagination::Page::new(albums, total, &pagination),
+    })
+    .into_response(),
+    Err(e) => {
+      tracing::error!("cannot read the albums of genre {}: {}", genre_id, e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn get_years(
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  pagination: axum::extract::Query<pagination::Pagination>,
+  sort: axum::extract::Query<sorting::SortParams<years::YearSort>>,
+) -> impl IntoResponse {
+  let (offset, limit) = match pagination.offset_and_limit() {
+    Ok(page) => page,
+    Err(e) => return e.into_response(),
+  };
+  let years = Year::get_all_with_pagination(&connection, &sort, offset, limit);
+  match years.and_then(|years| Ok((years, Year::count(&connection)?))) {
+    Ok((years, total)) => Json(pagination::Page::new(years, total, &pagination)).into_response(),
+    Err(e) => {
+      tracing::error!("cannot read years: {}", e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// A year with a page of the albums having songs from it
+#[axum_macros::debug_handler]
+async fn get_year(
+  axum::extract::Path(year): axum::extract::Path<i32>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  pagination: axum::extract::Query<pagination::Pagination>,
+  sort: axum::extract::Query<sorting::SortParams>,
+) -> impl IntoResponse {
+  let (offset, limit) = match pagination.offset_and_limit() {
+    Ok(page) => page,
+    Err(e) => return e.into_response(),
+  };
+  let year = match Year::get(&connection, year) {
+    Ok(Some(year)) => year,
+    Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+    Err(e) => {
+      tracing::error!("cannot read year {}: {}", year, e);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+  match years::Period::Year.albums(&connection, year.year, &sort, offset, limit) {
+    Ok((albums, total)) => Json(YearWithAlbums {
+      year,
+      albums: pagination::Page::new(albums, total, &pagination),
+    })
+    .into_response(),
+    Err(e) => {
+      tracing::error!("cannot read the albums of {}: {}", year.year, e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn get_decades(
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  pagination: axum::extract::Query<pagination::Pagination>,
+  sort: axum::extract::Query<sorting::SortParams<years::YearSort>>,
+) -> impl IntoResponse {
+  let (offset, limit) = match pagination.offset_and_limit() {
+    Ok(page) => page,
+    Err(e) => return e.into_response(),
+  };
+  let decades = Decade::get_all_with_pagination(&connection, &sort, offset, limit);
+  match decades.and_then(|decades| Ok((decades, Decade::count(&connection)?))) {
+    Ok((decades, total)) => {
+      Json(pagination::Page::new(decades, total, &pagination)).into_response()
+    }
+    Err(e) => {
+      tracing::error!("cannot read decades: {}", e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// A decade, by its first year, with a page of the albums having songs from it
+#[axum_macros::debug_handler]
+async fn get_decade(
+  axum::extract::Path(decade): axum::extract::Path<i32>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  pagination: axum::extract::Query<pagination::Pagination>,
+  sort: axum::extract::Query<sorting::SortParams>,
+) -> impl IntoResponse {
+  let (offset, limit) = match pagination.offset_and_limit() {
+    Ok(page) => page,
+    Err(e) => return e.into_response(),
+  };
+  let decade = match Decade::get(&connection, decade) {
+    Ok(Some(decade)) => decade,
+    Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+    Err(e) => {
+      tracing::error!("cannot read the {}s: {}", decade, e);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+  match years::Period::Decade.albums(&connection, decade.decade, &sort, offset, limit) {
+    Ok((albums, total)) => Json(DecadeWithAlbums {
+      decade,
+      albums: pagination::Page::new(albums, total, &pagination),
+    })
+    .into_response(),
+    Err(e) => {
+      tracing::error!("cannot read the albums of the {}s: {}", decade.decade, e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn get_playlists(
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  owner: playlists::Owner,
+  pagination: axum::extract::Query<pagination::Pagination>,
+) -> impl IntoResponse {
+  let (offset, limit) = match pagination.offset_and_limit() {
+    Ok(page) => page,
+    Err(e) => return e.into_response(),
+  };
+  let playlists = Playlist::get_all_with_pagination(&connection, &owner, offset, limit);
+  match playlists.and_then(|playlists| Ok((playlists, Playlist::count(&connection, &owner)?))) {
+    Ok((playlists, total)) => {
+      Json(pagination::Page::new(playlists, total, &pagination)).into_response()
+    }
+    Err(e) => PlaylistError::from(e).into_response(),
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn create_playlist(
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  owner: playlists::Owner,
+  Json(params): Json<playlists::PlaylistParams>,
+) -> impl IntoResponse {
+  match Playlist::create(&connection, &owner, &params) {
+    Ok(playlist) => (StatusCode::CREATED, Json(playlist)).into_response(),
+    Err(e) => e.into_response(),
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn get_playlist(
+  axum::extract::Path(playlist_id): axum::extract::Path<i64>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  owner: playlists::Owner,
+) -> impl IntoResponse {
+  match Playlist::get(&connection, &owner, playlist_id) {
+    Ok(playlist) => Json(playlist).into_response(),
+    Err(e) => e.into_response(),
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn replace_playlist(
+  axum::extract::Path(playlist_id): axum::extract::Path<i64>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  owner: playlists::Owner,
+  Json(params): Json<playlists::PlaylistParams>,
+) -> impl IntoResponse {
+  match Playlist::replace(&connection, &owner, playlist_id, &params) {
+    Ok(playlist) => Json(playlist).into_response(),
+    Err(e) => e.into_response(),
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn update_playlist(
+  axum::extract::Path(playlist_id): axum::extract::Path<i64>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  owner: playlists::Owner,
+  Json(changes): Json<playlists::PlaylistChanges>,
+) -> impl IntoResponse {
+  match Playlist::update(&connection, &owner, playlist_id, &changes) {
+    Ok(playlist) => Json(playlist).into_response(),
+    Err(e) => e.into_response(),
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn delete_playlist(
+  axum::extract::Path(playlist_id): axum::extract::Path<i64>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  owner: playlists::Owner,
+) -> impl IntoResponse {
+  match Playlist::delete(&connection, &owner, playlist_id) {
+    Ok(()) => StatusCode::NO_CONTENT.into_response(),
+    Err(e) => e.into_response(),
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn append_to_playlist(
+  axum::extract::Path(playlist_id): axum::extract::Path<i64>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  owner: playlists::Owner,
+  Json(params): Json<playlists::SongIds>,
+) -> impl IntoResponse {
+  match Playlist::append(&connection, &owner, playlist_id, &params.song_ids) {
+    Ok(playlist) => Json(playlist).into_response(),
+    Err(e) => e.into_response(),
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn reorder_playlist(
+  axum::extract::Path(playlist_id): axum::extract::Path<i64>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  owner: playlists::Owner,
+  Json(params): Json<playlists::SongIds>,
+) -> impl IntoResponse {
+  match Playlist::reorder(&connection, &owner, playlist_id, &params.song_ids) {
+    Ok(playlist) => Json(playlist).into_response(),
+    Err(e) => e.into_response(),
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn remove_from_playlist(
+  axum::extract::Path((playlist_id, song_id)): axum::extract::Path<(i64, String)>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  owner: playlists::Owner,
+) -> impl IntoResponse {
+  match Playlist::remove(&connection, &owner, playlist_id, &song_id) {
+    Ok(playlist) => Json(playlist).into_response(),
+    Err(e) => e.into_response(),
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn get_folders(
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+) -> impl IntoResponse {
+  match folders::get_roots(&connection) {
+    Ok(roots) => Json(roots).into_response(),
+    Err(e) => {
+      tracing::error!("cannot read the scan roots: {}", e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn get_folder(
+  axum::extract::Path(root_id): axum::extract::Path<String>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  params: axum::extract::Query<folders::FolderParams>,
+) -> impl IntoResponse {
+  let Some(components) = folders::parse_path(params.path.as_deref()) else {
+    return (StatusCode::BAD_REQUEST, "invalid path").into_response();
+  };
+  match folders::get(&connection, &root_id, &components) {
+    Ok(Some(folder)) => Json(folder).into_response(),
+    Ok(None) => StatusCode::NOT_FOUND.into_response(),
+    Err(e) => {
+      tracing::error!("cannot read folder {:?} of {}: {}", params.path, root_id, e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+#[axum_macros::debug_handler]
+async fn get_folder_cover(
+  axum::extract::Path(root_id): axum::extract::Path<String>,
+  axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
+  axum::Extension(thumbnails): axum::Extension<Arc<thumbnails::Cache>>,
+  params: axum::extract::Query<folders::FolderParams>,
+  cover_params: axum::extract::Query<covers::CoverParams>,
+  headers: HeaderMap,
+) -> impl IntoResponse {
+  let Some(components) = folders::parse_path(params.path.as_deref()) else {
+    return (StatusCode::BAD_REQUEST, "invalid path").into_response();
+  };
+  match folders::get_cover_id(&connection, &root_id, &components) {
+    Ok(Some(cover_id)) => {
+      covers::serve(&connection, thumbnails, &cover_id, &cover_params, &headers).await
+    }
+    Ok(None) => StatusCode::NOT_FOUND.into_response(),
+    Err(e) => {
+      tracing::error!("cannot read the cover of folder {:?} of {}: {}", params.path, root_id, e);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
     }
-    Err(e) => tracing::error!("search failed with {}", e),
   }
-  return StatusCode::INTERNAL_SERVER_ERROR.into_response();
 }
 
 #[axum_macros::debug_handler]
 async fn search(
   axum::extract::State(connection): axum::extract::State<Arc<ConnectionThreadSafe>>,
   search_params: axum::extract::Query<SearchParams>,
-  pagination: axum::extract::Query<Pagination>,
+  pagination: axum::extract::Query<pagination::Pagination>,
 ) -> impl IntoResponse {
-  let term = search_params.0.term;
-  let (offset, limit) = get_offset_and_limit(&pagination.0);
-  match execute_query(
+  let query = match search::parse(&search_params.0.term) {
+    Ok(query) => query,
+    Err(e) => {
+      let error = serde_json::json!({ "error": e.to_string() });
+      return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+    }
+  };
+  let (offset, limit) = match pagination.offset_and_limit() {
+    Ok(page) => page,
+    Err(e) => return e.into_response(),
+  };
+  // The rank of a match is only known when joining with the full text index.
+  // The id keeps the order of the pages stable.
+  let (join, order) = if query.full_text {
+    ("JOIN songs_fts ON songs_fts.rowid = songs.rowid ", "songs_fts.rank, songs.id")
+  } else {
+    ("", "songs.id")
+  };
+  let total = pagination::count(
     &connection,
     &format!(
-      r#"SELECT * FROM songs WHERE songs MATCH "{}" ORDER BY rank LIMIT {} OFFSET {};"#,
-      term, limit, offset
+      "SELECT COUNT(*) AS count FROM songs {}WHERE songs.missing_since IS NULL AND {};",
+      join, query.condition
     ),
-  ) {
-    Ok(results) => {
-      println!("{:?}", results);
-      let songs = Song::from_sqlite_result(&results);
-      return Json(songs).into_response();
+    &query.values,
+  );
+  let mut values = query.values;
+  values.extend([Value::Integer(limit as i64), Value::Integer(offset as i64)]);
+  let songs = Song::select(
+    &connection,
+    &format!(
+      "SELECT songs.* FROM songs {}WHERE songs.missing_since IS NULL AND {} \
+       ORDER BY {} LIMIT ? OFFSET ?;",
+      join, query.condition, order
+    ),
+    &values,
+  );
+  match songs.and_then(|songs| Ok((songs, total?))) {
+    Ok((songs, total)) => {
+      return Json(pagination::Page::new(songs, total, &pagination)).into_response()
     }
     Err(e) => tracing::error!("search failed with {}", e),
   }
@@ -379,15 +1282,11 @@ async fn static_handler(uri: Uri) -> impl IntoResponse {
 
 async fn serve(config: &Config) -> Result<()> {
   let connection = Arc::new(Connection::open_thread_safe(&config.database)?);
-  let nb_songs = match Song::get_all(&connection, "songs") {
-    Ok(result) => result.len(),
-    Err(e) => {
-      eprintln!("error: {} database is not properly formatted ({:?})", config.database, e);
-      eprintln!("rescan you music folder with:");
-      eprintln!("  rstream --scan-path /path/to/music");
-      anyhow::bail!("Incorrectly formatted database")
-    }
-  };
+  if let Err(e) = migrations::run(&connection) {
+    eprintln!("error: cannot migrate {} ({:#})", config.database, e);
+    anyhow::bail!("Incorrectly formatted database")
+  }
+  let nb_songs = Song::count(&connection)?;
 
   // Build our application with a route
   let mut app = Router::new()
@@ -397,12 +1296,40 @@ async fn serve(config: &Config) -> Result<()> {
     .route("/version", get(version))
     .route("/songs", get(get_songs))
     .route("/songs/:song_id", get(get_song))
+    .route("/songs/:song_id/cover", get(get_song_cover))
     // FIXME: find better URL
     .route("/song/:song_id", get(get_song_file))
     .route("/artists", get(get_artists))
+    .route("/artists/:artist_id", get(get_artist))
     .route("/albums", get(get_albums))
+    .route("/albums/:album_id", get(get_album))
+    .route("/albums/:album_id/cover", get(get_album_cover))
+    .route("/genres", get(get_genres))
+    .route("/genres/:genre_id", get(get_genre))
+    .route("/years", get(get_years))
+    .route("/years/:year", get(get_year))
+    .route("/decades", get(get_decades))
+    .route("/decades/:decade", get(get_decade))
+    .route("/playlists", get(get_playlists).post(create_playlist))
+    .route(
+      "/playlists/:playlist_id",
+      get(get_playlist)
+        .put(replace_playlist)
+        .patch(update_playlist)
+        .delete(delete_playlist),
+    )
+    .route("/playlists/:playlist_id/songs", post(append_to_playlist).put(reorder_playlist))
+    .route("/playlists/:playlist_id/songs/:song_id", delete(remove_from_playlist))
+    .route("/folders", get(get_folders))
+    .route("/folders/:root_id", get(get_folder))
+    .route("/folders/:root_id/cover", get(get_folder_cover))
     .route("/search", get(search))
-    .with_state(Arc::clone(&connection));
+    .with_state(Arc::clone(&connection))
+    .layer(axum::Extension(playlists::UserHeader(config.user_header.clone())))
+    .layer(axum::Extension(Arc::new(thumbnails::Cache::new(
+      config.cache_folder.clone(),
+      config.cache_max_size * 1024 * 1024,
+    ))));
 
   // Either serve static files from a provided folder or from the embedded
   // static files from the assets folder
@@ -420,7 +1347,11 @@ async fn serve(config: &Config) -> Result<()> {
   app = app.layer(
     TraceLayer::new_for_http()
       .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
-      .on_response(trace::DefaultOnResponse::new().level(Level::INFO).include_headers(true)),
+      .on_response(
+        trace::DefaultOnResponse::new()
+          .level(Level::INFO)
+          .include_headers(true),
+      ),
   );
 
   // run our app with hyper
@@ -438,6 +1369,45 @@ async fn serve(config: &Config) -> Result<()> {
   Ok(())
 }
 
+fn migrate(config: &Config, dry_run: bool) -> Result<()> {
+  if !Path::new(&config.database).exists() {
+    anyhow::bail!("{} does not exist", config.database);
+  }
+  let connection = Connection::open(&config.database)?;
+  let pending = migrations::pending(&connection)?;
+  if pending.is_empty() {
+    println!("{} is up to date (version {})", config.database, migrations::version(&connection)?);
+    return Ok(());
+  }
+  if dry_run {
+    println!(
+      "{} is at version {}, pending migrations:",
+      config.database,
+      migrations::version(&connection)?
+    );
+    for (version, description) in pending {
+      println!("  {}: {}", version, description);
+    }
+    return Ok(());
+  }
+  migrations::run(&connection)?;
+  println!("{} migrated to version {}", config.database, migrations::latest_version());
+  Ok(())
+}
+
+fn prune_cache(config: &Config) -> Result<()> {
+  let connection = Connection::open(&config.database)?;
+  let cover_ids = execute_query(&connection, "SELECT id FROM covers;", &[])?
+    .iter()
+    .filter_map(|row| row.get("id").cloned())
+    .collect::<HashSet<String>>();
+  let cache =
+    thumbnails::Cache::new(config.cache_folder.clone(), config.cache_max_size * 1024 * 1024);
+  let (count, freed) = cache.prune(|cover_id| cover_ids.contains(cover_id))?;
+  println!("{} thumbnail(s) removed, {} KiB freed", count, freed / 1024);
+  Ok(())
+}
+
 #[tokio::main]
 async fn main() {
   let config = Config::parse();
@@ -453,6 +1423,19 @@ async fn main() {
     .finish();
   tracing::subscriber::set_global_default(subscriber).unwrap();
 
+  if let Some(ref command) = config.command {
+    let result = match command {
+      Command::Migrate { dry_run } => migrate(&config, *dry_run),
+      Command::Cache {
+        command: CacheCommand::Prune,
+      } => prune_cache(&config),
+    };
+    if let Err(e) = result {
+      eprintln!("error: {:#}", e);
+      std::process::exit(1);
+    }
+    return;
+  }
   if let Some(ref scan_path) = config.scan_path {
     scan(&scan_path, &config).unwrap();
   }
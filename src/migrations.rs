@@ -0,0 +1,808 @@
+// Database schema versioning. The version of the schema is stored in
+// `PRAGMA user_version` and every migration brings the database from one
+// version to the next. Migrations are applied in order, each in its own
+// transaction, when the database is opened.
+//
+// Migrations are never modified once released: changes to the schema are made
+// by appending a new migration to MIGRATIONS.
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use sqlite::{Connection, Value};
+
+use crate::{album_id, artist_id, credits, execute_query, genres, md5sum, sorting};
+
+struct Migration {
+  description: &'static str,
+  apply: fn(&Connection) -> Result<()>,
+}
+
+// The version of a database is the number of migrations applied to it
+const MIGRATIONS: [Migration; 14] = [
+  Migration {
+    description: "create the songs table and its full text index",
+    apply: create_songs_table,
+  },
+  Migration {
+    description: "identify songs by the hash of their audio data",
+    apply: migrate_song_ids,
+  },
+  Migration {
+    description: "store the genre, album artist, composer, comment and more tags of the songs",
+    apply: add_extended_tags,
+  },
+  Migration {
+    description: "store the duration, bitrate, sample rate, channels and codec of the songs",
+    apply: add_audio_properties,
+  },
+  Migration {
+    description: "store the cover art of the songs and identify their album",
+    apply: add_covers_and_album_ids,
+  },
+  Migration {
+    description: "identify albums by their artist, title and year, or their MusicBrainz id",
+    apply: add_musicbrainz_album_ids,
+  },
+  Migration {
+    description: "identify the artists and album artists of the songs",
+    apply: add_artist_ids,
+  },
+  Migration {
+    description: "credit every artist of the songs, including the featured ones",
+    apply: add_song_artists,
+  },
+  Migration {
+    description: "store the sort names of the artists and albums, and when songs were added",
+    apply: add_sort_names,
+  },
+  Migration {
+    description: "record the scan roots and the cover of the folders",
+    apply: add_folders,
+  },
+  Migration {
+    description: "link the songs to each of their genres",
+    apply: add_song_genres,
+  },
+  Migration {
+    description: "store the playlists of the users",
+    apply: add_playlists,
+  },
+  Migration {
+    description: "index the sort names case insensitively, as they are sorted",
+    apply: index_sort_names_nocase,
+  },
+  Migration {
+    description: "store the albums and artists to sort their lists with indexes",
+    apply: add_albums_and_artists,
+  },
+];
+
+// The version the database must be at to be used by this version of rstream
+pub fn latest_version() -> i64 {
+  MIGRATIONS.len() as i64
+}
+
+pub fn version(connection: &Connection) -> Result<i64> {
+  let rows = execute_query(connection, "PRAGMA user_version;", &[])?;
+  let version = rows
+    .first()
+    .and_then(|row| row.get("user_version"))
+    .ok_or_else(|| anyhow::anyhow!("cannot read the database version"))?;
+  Ok(version.parse::<i64>()?)
+}
+
+// The description of the migrations not applied yet, with the version they
+// bring the database to
+pub fn pending(connection: &Connection) -> Result<Vec<(i64, &'static str)>> {
+  let version = version(connection)?;
+  if version > latest_version() {
+    anyhow::bail!(
+      "the database is at version {} which is newer than the version supported by this \
+       version of rstream ({}), upgrade rstream",
+      version,
+      latest_version()
+    );
+  }
+  Ok(
+    MIGRATIONS
+      .iter()
+      .enumerate()
+      .skip(version as usize)
+      .map(|(index, migration)| (index as i64 + 1, migration.description))
+      .collect(),
+  )
+}
+
+// Bring the database to the latest version
+pub fn run(connection: &Connection) -> Result<()> {
+  for (version, description) in pending(connection)? {
+    tracing::info!("migrating the database to version {} ({})", version, description);
+    let migration = &MIGRATIONS[version as usize - 1];
+    connection.execute("BEGIN TRANSACTION;")?;
+    let result = (migration.apply)(connection).and_then(|()| {
+      let set_version = format!("PRAGMA user_version = {};", version);
+      connection.execute(set_version).map_err(Into::into)
+    });
+    match result {
+      Ok(()) => connection.execute("COMMIT;")?,
+      Err(e) => {
+        connection.execute("ROLLBACK;")?;
+        return Err(e).with_context(|| format!("migration to version {} failed", version));
+      }
+    }
+  }
+  Ok(())
+}
+
+// (Re)create the full text index of the songs on the given columns. This is an
+// external content table which only holds the index, kept in sync with songs by
+// triggers.
+// https://www.sqlite.org/fts5.html#external_content_tables
+fn create_full_text_index(connection: &Connection, columns: &[&str]) -> Result<()> {
+  let prefixed = |prefix: &str| {
+    columns
+      .iter()
+      .map(|column| format!("{}.{}", prefix, column))
+      .collect::<Vec<String>>()
+      .join(", ")
+  };
+  let columns_list = columns.join(", ");
+  let insert = format!(
+    "INSERT INTO songs_fts(rowid, {}) VALUES (new.rowid, {});",
+    columns_list,
+    prefixed("new")
+  );
+  let delete = format!(
+    "INSERT INTO songs_fts(songs_fts, rowid, {}) VALUES ('delete', old.rowid, {});",
+    columns_list,
+    prefixed("old")
+  );
+  connection.execute(format!(
+    r#"
+    DROP TRIGGER IF EXISTS songs_fts_insert;
+    DROP TRIGGER IF EXISTS songs_fts_delete;
+    DROP TRIGGER IF EXISTS songs_fts_update;
+    DROP TABLE IF EXISTS songs_fts;
+    CREATE VIRTUAL TABLE songs_fts USING fts5({columns}, content='songs', content_rowid='rowid');
+    CREATE TRIGGER songs_fts_insert AFTER INSERT ON songs BEGIN
+      {insert}
+    END;
+    CREATE TRIGGER songs_fts_delete AFTER DELETE ON songs BEGIN
+      {delete}
+    END;
+    CREATE TRIGGER songs_fts_update AFTER UPDATE ON songs BEGIN
+      {delete}
+      {insert}
+    END;
+    INSERT INTO songs_fts(songs_fts) VALUES ('rebuild');
+    "#,
+    columns = columns_list,
+    insert = insert,
+    delete = delete,
+  ))?;
+  Ok(())
+}
+
+// The columns of the songs table, with the expression converting the value of
+// the fts5 table used by the first versions of rstream, where everything was
+// stored as text.
+const SONGS_COLUMNS: [(&str, &str, &str); 11] = [
+  ("id", "TEXT NOT NULL PRIMARY KEY", "id"),
+  ("path", "TEXT NOT NULL", "path"),
+  ("size", "INTEGER NOT NULL", "CAST(size AS INTEGER)"),
+  ("mtime", "INTEGER NOT NULL", "CAST(mtime AS INTEGER)"),
+  ("missing_since", "INTEGER", "CAST(NULLIF(missing_since, '') AS INTEGER)"),
+  ("title", "TEXT", "NULLIF(title, '')"),
+  ("artist", "TEXT", "NULLIF(artist, '')"),
+  ("album", "TEXT", "NULLIF(album, '')"),
+  ("year", "INTEGER", "CAST(NULLIF(year, '') AS INTEGER)"),
+  ("track", "INTEGER", "CAST(NULLIF(track, '') AS INTEGER)"),
+  ("disc", "INTEGER", "CAST(NULLIF(disc, '') AS INTEGER)"),
+];
+
+fn create_songs_table(connection: &Connection) -> Result<()> {
+  let legacy = execute_query(
+    connection,
+    "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'songs';",
+    &[],
+  )?
+  .first()
+  .and_then(|row| {
+    row
+      .get("sql")
+      .map(|sql| sql.to_uppercase().starts_with("CREATE VIRTUAL TABLE"))
+  })
+  .unwrap_or(false);
+  let definition = SONGS_COLUMNS
+    .iter()
+    .map(|(name, kind, _)| format!("{} {}", name, kind))
+    .collect::<Vec<String>>()
+    .join(", ");
+  if legacy {
+    // The songs used to be stored in a fts5 table directly. Copy them to a
+    // regular table, with the columns the fts5 table had. The missing ones are
+    // the size and modification time of the files, set to 0 so that the files
+    // are read again on the next scan.
+    let legacy_columns = execute_query(connection, "PRAGMA table_info(songs);", &[])?
+      .iter()
+      .filter_map(|row| row.get("name").cloned())
+      .collect::<HashSet<String>>();
+    if !legacy_columns.contains("id") || !legacy_columns.contains("path") {
+      anyhow::bail!("unknown songs table format");
+    }
+    let (columns, values): (Vec<&str>, Vec<String>) = SONGS_COLUMNS
+      .iter()
+      .map(|(name, kind, conversion)| {
+        if legacy_columns.contains(*name) {
+          (*name, conversion.to_string())
+        } else if kind.contains("NOT NULL") {
+          (*name, "0".to_string())
+        } else {
+          (*name, "NULL".to_string())
+        }
+      })
+      .unzip();
+    connection.execute(format!(
+      r#"
+      CREATE TABLE songs_v1 ({definition});
+      INSERT OR IGNORE INTO songs_v1 ({columns}) SELECT {values} FROM songs;
+      DROP TABLE songs;
+      ALTER TABLE songs_v1 RENAME TO songs;
+      "#,
+      definition = definition,
+      columns = columns.join(", "),
+      values = values.join(", "),
+    ))?;
+  } else {
+    // Databases created before versioning may already have the table
+    connection.execute(format!("CREATE TABLE IF NOT EXISTS songs ({});", definition))?;
+  }
+  connection.execute(
+    r#"
+    CREATE INDEX IF NOT EXISTS songs_path ON songs(path);
+    CREATE INDEX IF NOT EXISTS songs_artist ON songs(artist);
+    CREATE INDEX IF NOT EXISTS songs_album ON songs(album);
+    CREATE INDEX IF NOT EXISTS songs_year ON songs(year);
+    "#,
+  )?;
+  create_full_text_index(connection, &["title", "artist", "album"])
+}
+
+// Song ids used to be the base64 md5 of the whole file, so editing a tag changed
+// them. This recomputes the id of the songs already in the database and keeps
+// the old ids as aliases so that links using them keep working.
+fn migrate_song_ids(connection: &Connection) -> Result<()> {
+  let migrated = !execute_query(
+    connection,
+    "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'song_aliases';",
+    &[],
+  )?
+  .is_empty();
+  // Databases created before versioning may already have been migrated
+  if migrated {
+    return Ok(());
+  }
+  execute_query(
+    connection,
+    "CREATE TABLE song_aliases (old_id TEXT PRIMARY KEY, new_id TEXT NOT NULL);",
+    &[],
+  )?;
+  let rows = execute_query(connection, "SELECT id, path FROM songs;", &[])?;
+  let mut ids = rows
+    .iter()
+    .filter_map(|row| row.get("id").cloned())
+    .collect::<HashSet<String>>();
+  let mut count = 0;
+  for row in rows.iter() {
+    let (Some(old_id), Some(path)) = (row.get("id"), row.get("path")) else {
+      continue;
+    };
+    let new_id = match md5sum(Path::new(path)) {
+      Ok(new_id) => new_id,
+      Err(e) => {
+        tracing::debug!("cannot compute the new id of {} ({})", path, e);
+        continue;
+      }
+    };
+    if &new_id == old_id {
+      continue;
+    }
+    let (old_value, new_value) = (Value::String(old_id.clone()), Value::String(new_id.clone()));
+    execute_query(
+      connection,
+      "INSERT INTO song_aliases VALUES (?, ?);",
+      &[old_value.clone(), new_value.clone()],
+    )?;
+    if ids.contains(&new_id) {
+      // Another file has the same audio content, keep only one of them
+      execute_query(connection, "DELETE FROM songs WHERE id = ?;", &[old_value])?;
+    } else {
+      execute_query(connection, "UPDATE songs SET id = ? WHERE id = ?;", &[new_value, old_value])?;
+      ids.insert(new_id);
+    }
+    count += 1;
+  }
+  if count > 0 {
+    println!("{} song id(s) migrated", count);
+  }
+  Ok(())
+}
+
+fn add_extended_tags(connection: &Connection) -> Result<()> {
+  connection.execute(
+    r#"
+    ALTER TABLE songs ADD COLUMN genre TEXT;
+    ALTER TABLE songs ADD COLUMN album_artist TEXT;
+    ALTER TABLE songs ADD COLUMN composer TEXT;
+    ALTER TABLE songs ADD COLUMN comment TEXT;
+    ALTER TABLE songs ADD COLUMN total_tracks INTEGER;
+    ALTER TABLE songs ADD COLUMN total_discs INTEGER;
+    ALTER TABLE songs ADD COLUMN bpm INTEGER;
+    ALTER TABLE songs ADD COLUMN original_date TEXT;
+    ALTER TABLE songs ADD COLUMN label TEXT;
+    ALTER TABLE songs ADD COLUMN isrc TEXT;
+    CREATE INDEX songs_genre ON songs(genre);
+    CREATE INDEX songs_album_artist ON songs(album_artist);
+    -- Files are only read again when they change, this makes the next scan
+    -- read the new tags of all the files
+    UPDATE songs SET mtime = 0;
+    "#,
+  )?;
+  create_full_text_index(
+    connection,
+    &[
+      "title",
+      "artist",
+      "album",
+      "genre",
+      "album_artist",
+      "composer",
+      "comment",
+      "label",
+    ],
+  )
+}
+
+fn add_audio_properties(connection: &Connection) -> Result<()> {
+  connection.execute(
+    r#"
+    ALTER TABLE songs ADD COLUMN duration_ms INTEGER;
+    ALTER TABLE songs ADD COLUMN bitrate INTEGER;
+    ALTER TABLE songs ADD COLUMN sample_rate INTEGER;
+    ALTER TABLE songs ADD COLUMN channels INTEGER;
+    ALTER TABLE songs ADD COLUMN codec TEXT;
+    -- Make the next scan read all the files again
+    UPDATE songs SET mtime = 0;
+    "#,
+  )?;
+  Ok(())
+}
+
+fn add_covers_and_album_ids(connection: &Connection) -> Result<()> {
+  connection.execute(
+    r#"
+    CREATE TABLE covers (id TEXT NOT NULL PRIMARY KEY, mime_type TEXT NOT NULL, data BLOB NOT NULL);
+    ALTER TABLE songs ADD COLUMN album_id TEXT;
+    ALTER TABLE songs ADD COLUMN cover_id TEXT;
+    CREATE INDEX songs_album_id ON songs(album_id);
+    CREATE INDEX songs_cover_id ON songs(cover_id);
+    -- Make the next scan read the pictures of all the files
+    UPDATE songs SET mtime = 0;
+    "#,
+  )?;
+  // The albums are usable without waiting for the next scan
+  let rows = execute_query(
+    connection,
+    "SELECT id, artist, album, album_artist FROM songs WHERE LENGTH(TRIM(album)) > 0;",
+    &[],
+  )?;
+  for row in rows.iter() {
+    let (Some(id), Some(album)) = (row.get("id"), row.get("album")) else {
+      continue;
+    };
+    let artist = row
+      .get("album_artist")
+      .or(row.get("artist"))
+      .map(|a| a.as_str());
+    let album_id = Value::String(album_id(artist, album, None, None));
+    execute_query(
+      connection,
+      "UPDATE songs SET album_id = ? WHERE id = ?;",
+      &[album_id, Value::String(id.clone())],
+    )?;
+  }
+  Ok(())
+}
+
+fn add_musicbrainz_album_ids(connection: &Connection) -> Result<()> {
+  connection.execute(
+    r#"
+    ALTER TABLE songs ADD COLUMN musicbrainz_album_id TEXT;
+    -- Make the next scan read the MusicBrainz ids
+    UPDATE songs SET mtime = 0;
+    "#,
+  )?;
+  let rows = execute_query(
+    connection,
+    "SELECT id, artist, album, album_artist, year FROM songs WHERE album_id IS NOT NULL;",
+    &[],
+  )?;
+  for row in rows.iter() {
+    let (Some(id), Some(album)) = (row.get("id"), row.get("album")) else {
+      continue;
+    };
+    let artist = row
+      .get("album_artist")
+      .or(row.get("artist"))
+      .map(|a| a.as_str());
+    let year = row.get("year").and_then(|year| year.parse::<i32>().ok());
+    let album_id = Value::String(album_id(artist, album, year, None));
+    execute_query(
+      connection,
+      "UPDATE songs SET album_id = ? WHERE id = ?;",
+      &[album_id, Value::String(id.clone())],
+    )?;
+  }
+  Ok(())
+}
+
+fn add_artist_ids(connection: &Connection) -> Result<()> {
+  connection.execute(
+    r#"
+    ALTER TABLE songs ADD COLUMN artist_id TEXT;
+    ALTER TABLE songs ADD COLUMN album_artist_id TEXT;
+    CREATE INDEX songs_artist_id ON songs(artist_id);
+    CREATE INDEX songs_album_artist_id ON songs(album_artist_id);
+    "#,
+  )?;
+  let rows = execute_query(connection, "SELECT id, artist, album_artist FROM songs;", &[])?;
+  for row in rows.iter() {
+    let Some(id) = row.get("id") else {
+      continue;
+    };
+    let artist = row.get("artist").and_then(|artist| artist_id(artist));
+    let album_artist = row
+      .get("album_artist")
+      .and_then(|artist| artist_id(artist))
+      .or(artist.clone());
+    let value = |id: Option<String>| id.map_or(Value::Null, Value::String);
+    execute_query(
+      connection,
+      "UPDATE songs SET artist_id = ?, album_artist_id = ? WHERE id = ?;",
+      &[
+        value(artist),
+        value(album_artist),
+        Value::String(id.clone()),
+      ],
+    )?;
+  }
+  Ok(())
+}
+
+// The artists are split with the default separators until the next scan, which
+// reads the tags again: ID3v2.4 multiple values used to be glued together.
+fn add_song_artists(connection: &Connection) -> Result<()> {
+  connection.execute(
+    r#"
+    CREATE TABLE song_artists (
+      song_id TEXT NOT NULL,
+      artist_id TEXT NOT NULL,
+      name TEXT NOT NULL,
+      role TEXT NOT NULL,
+      position INTEGER NOT NULL,
+      PRIMARY KEY (song_id, artist_id, role)
+    );
+    CREATE INDEX song_artists_artist_id ON song_artists(artist_id);
+    UPDATE songs SET mtime = 0;
+    "#,
+  )?;
+  let separators = credits::DEFAULT_SEPARATORS.map(String::from);
+  let rows = execute_query(connection, "SELECT id, title, artist, album_artist FROM songs;", &[])?;
+  for row in rows.iter() {
+    let Some(id) = row.get("id") else {
+      continue;
+    };
+    let values = |column: &str| {
+      row
+        .get(column)
+        .cloned()
+        .into_iter()
+        .collect::<Vec<String>>()
+    };
+    let credits = credits::Credits::new(
+      &values("artist"),
+      &values("album_artist"),
+      row.get("title").map(|title| title.as_str()),
+      &separators,
+    );
+    // Credits::store() writes the columns of the latest schema
+    let roles = [
+      ("artist", &credits.artists),
+      ("featured", &credits.featured),
+      ("album_artist", &credits.album_artists),
+    ];
+    for (role, names) in roles {
+      for (position, name) in names.iter().enumerate() {
+        let Some(artist_id) = artist_id(name) else {
+          continue;
+        };
+        execute_query(
+          connection,
+          "INSERT OR IGNORE INTO song_artists (song_id, artist_id, name, role, position) \
+           VALUES (?, ?, ?, ?, ?);",
+          &[
+            Value::String(id.clone()),
+            Value::String(artist_id),
+            Value::String(name.clone()),
+            Value::String(role.to_string()),
+            Value::Integer(position as i64),
+          ],
+        )?;
+      }
+    }
+    let value = |name: Option<&String>| {
+      name
+        .and_then(|name| artist_id(name))
+        .map_or(Value::Null, Value::String)
+    };
+    execute_query(
+      connection,
+      "UPDATE songs SET artist_id = ?, album_artist_id = ? WHERE id = ?;",
+      &[
+        value(credits.artists.first()),
+        value(credits.album_artists.first()),
+        Value::String(id.clone()),
+      ],
+    )?;
+  }
+  Ok(())
+}
+
+// The songs already known are considered added now. Sort names are derived from
+// the names with the default articles until the next scan reads the sort tags.
+fn add_sort_names(connection: &Connection) -> Result<()> {
+  connection.execute(
+    r#"
+    ALTER TABLE songs ADD COLUMN album_sort TEXT;
+    ALTER TABLE songs ADD COLUMN added_at INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE song_artists ADD COLUMN sort_name TEXT;
+    CREATE INDEX songs_album_sort ON songs(album_sort);
+    CREATE INDEX songs_added_at ON songs(added_at);
+    CREATE INDEX song_artists_sort_name ON song_artists(sort_name);
+    UPDATE songs SET added_at = CAST(strftime('%s', 'now') AS INTEGER), mtime = 0;
+    "#,
+  )?;
+  let articles = sorting::DEFAULT_ARTICLES.map(String::from);
+  let rows =
+    execute_query(connection, "SELECT id, album FROM songs WHERE album IS NOT NULL;", &[])?;
+  for row in rows.iter() {
+    let (Some(id), Some(album)) = (row.get("id"), row.get("album")) else {
+      continue;
+    };
+    execute_query(
+      connection,
+      "UPDATE songs SET album_sort = ? WHERE id = ?;",
+      &[
+        Value::String(sorting::sort_name(album, &articles)),
+        Value::String(id.clone()),
+      ],
+    )?;
+  }
+  let rows = execute_query(connection, "SELECT DISTINCT name FROM song_artists;", &[])?;
+  for name in rows.iter().filter_map(|row| row.get("name")) {
+    execute_query(
+      connection,
+      "UPDATE song_artists SET sort_name = ? WHERE name = ?;",
+      &[
+        Value::String(sorting::sort_name(name, &articles)),
+        Value::String(name.clone()),
+      ],
+    )?;
+  }
+  Ok(())
+}
+
+// The roots are recorded by the next scans, which also look for the covers of
+// all the folders
+fn add_folders(connection: &Connection) -> Result<()> {
+  connection.execute(
+    r#"
+    CREATE TABLE scan_roots (id TEXT NOT NULL PRIMARY KEY, path TEXT NOT NULL);
+    CREATE TABLE folder_covers (path TEXT NOT NULL PRIMARY KEY, cover_id TEXT NOT NULL);
+    UPDATE songs SET mtime = 0;
+    "#,
+  )?;
+  Ok(())
+}
+
+fn add_song_genres(connection: &Connection) -> Result<()> {
+  connection.execute(
+    r#"
+    CREATE TABLE song_genres (
+      song_id TEXT NOT NULL,
+      genre_id TEXT NOT NULL,
+      name TEXT NOT NULL,
+      PRIMARY KEY (song_id, genre_id)
+    );
+    CREATE INDEX song_genres_genre_id ON song_genres(genre_id);
+    "#,
+  )?;
+  let rows =
+    execute_query(connection, "SELECT id, genre FROM songs WHERE genre IS NOT NULL;", &[])?;
+  for row in rows.iter() {
+    let (Some(id), Some(genre)) = (row.get("id"), row.get("genre")) else {
+      continue;
+    };
+    // genres::store() writes the columns of the latest schema
+    for name in genres::names(genre) {
+      let Some(genre_id) = genres::genre_id(&name) else {
+        continue;
+      };
+      execute_query(
+        connection,
+        "INSERT OR IGNORE INTO song_genres (song_id, genre_id, name) VALUES (?, ?, ?);",
+        &[
+          Value::String(id.clone()),
+          Value::String(genre_id),
+          Value::String(name),
+        ],
+      )?;
+    }
+  }
+  Ok(())
+}
+
+fn add_playlists(connection: &Connection) -> Result<()> {
+  connection.execute(
+    r#"
+    CREATE TABLE playlists (
+      id INTEGER PRIMARY KEY AUTOINCREMENT,
+      owner TEXT NOT NULL,
+      name TEXT NOT NULL,
+      description TEXT,
+      created_at INTEGER NOT NULL,
+      updated_at INTEGER NOT NULL
+    );
+    CREATE INDEX playlists_owner ON playlists(owner);
+    CREATE TABLE playlist_entries (
+      playlist_id INTEGER NOT NULL,
+      position INTEGER NOT NULL,
+      song_id TEXT NOT NULL,
+      PRIMARY KEY (playlist_id, position)
+    );
+    CREATE INDEX playlist_entries_song_id ON playlist_entries(song_id);
+    "#,
+  )?;
+  Ok(())
+}
+
+// The lists sort names with COLLATE NOCASE, which the indexes of add_sort_names
+// with the default BINARY collation cannot serve
+fn index_sort_names_nocase(connection: &Connection) -> Result<()> {
+  connection.execute(
+    r#"
+    DROP INDEX songs_album_sort;
+    DROP INDEX song_artists_sort_name;
+    CREATE INDEX songs_album_sort ON songs(album_sort COLLATE NOCASE);
+    CREATE INDEX song_artists_sort_name ON song_artists(sort_name COLLATE NOCASE);
+    "#,
+  )?;
+  Ok(())
+}
+
+// The lists were sorted on aggregates of the songs, which no index can serve.
+// Every sort key has an index, which ends with the sort name and the id to
+// break ties, and a descending one when missing years must stay last.
+// albums::refresh() and artists::refresh() write the columns of the latest
+// schema, they are filled here as of this version.
+fn add_albums_and_artists(connection: &Connection) -> Result<()> {
+  connection.execute(
+    r#"
+    CREATE TABLE albums (
+      id TEXT NOT NULL PRIMARY KEY,
+      title TEXT NOT NULL,
+      sort_title TEXT NOT NULL,
+      artist TEXT,
+      artist_id TEXT,
+      year INTEGER,
+      track_count INTEGER NOT NULL,
+      disc_count INTEGER NOT NULL,
+      duration_ms INTEGER NOT NULL,
+      cover_id TEXT,
+      added_at INTEGER NOT NULL
+    );
+    CREATE INDEX albums_title ON albums(title COLLATE NOCASE, sort_title COLLATE NOCASE, id);
+    CREATE INDEX albums_sort_title ON albums(sort_title COLLATE NOCASE, id);
+    CREATE INDEX albums_year ON albums(year IS NULL, year, sort_title COLLATE NOCASE, id);
+    CREATE INDEX albums_year_desc
+      ON albums(year IS NULL, year DESC, sort_title COLLATE NOCASE DESC, id DESC);
+    CREATE INDEX albums_added_at ON albums(added_at, sort_title COLLATE NOCASE, id);
+    CREATE INDEX albums_track_count ON albums(track_count, sort_title COLLATE NOCASE, id);
+    CREATE TABLE artists (
+      id TEXT NOT NULL PRIMARY KEY,
+      name TEXT NOT NULL,
+      sort_name TEXT NOT NULL,
+      song_count INTEGER NOT NULL,
+      album_count INTEGER NOT NULL,
+      year INTEGER,
+      added_at INTEGER NOT NULL
+    );
+    CREATE INDEX artists_name ON artists(name COLLATE NOCASE, sort_name COLLATE NOCASE, id);
+    CREATE INDEX artists_sort_name ON artists(sort_name COLLATE NOCASE, id);
+    CREATE INDEX artists_year ON artists(year IS NULL, year, sort_name COLLATE NOCASE, id);
+    CREATE INDEX artists_year_desc
+      ON artists(year IS NULL, year DESC, sort_name COLLATE NOCASE DESC, id DESC);
+    CREATE INDEX artists_added_at ON artists(added_at, sort_name COLLATE NOCASE, id);
+    CREATE INDEX artists_song_count ON artists(song_count, sort_name COLLATE NOCASE, id);
+    DROP INDEX songs_album_sort;
+    DROP INDEX song_artists_sort_name;
+    DROP INDEX songs_added_at;
+    INSERT INTO albums (id, title, sort_title, artist, artist_id, year, track_count,
+      disc_count, duration_ms, cover_id, added_at)
+    SELECT album_id, MIN(album), COALESCE(MIN(album_sort), MIN(album)),
+      COALESCE(MAX(album_artist), CASE WHEN COUNT(DISTINCT artist) = 1 THEN MAX(artist) END),
+      CASE WHEN COUNT(DISTINCT album_artist_id) = 1 THEN MAX(album_artist_id) END,
+      MIN(year), COUNT(*), COUNT(DISTINCT COALESCE(disc, 1)), COALESCE(SUM(duration_ms), 0),
+      MAX(cover_id), MIN(added_at)
+    FROM songs WHERE album_id IS NOT NULL AND missing_since IS NULL GROUP BY album_id;
+    INSERT INTO artists (id, name, sort_name, song_count, album_count, year, added_at)
+    SELECT song_artists.artist_id, MIN(name), COALESCE(MIN(sort_name), MIN(name)),
+      COUNT(DISTINCT CASE WHEN role != 'album_artist' THEN song_id END),
+      COUNT(DISTINCT CASE WHEN role = 'album_artist' THEN album_id END),
+      MIN(year), MIN(added_at)
+    FROM song_artists JOIN songs ON songs.id = song_artists.song_id
+    WHERE missing_since IS NULL GROUP BY song_artists.artist_id;
+    "#,
+  )?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // The columns of a table and its indexes, sorted by name
+  fn schema(connection: &Connection, table: &str) -> (Vec<String>, Vec<String>) {
+    let rows = |query: String| execute_query(connection, &query, &[]).unwrap();
+    let mut columns = rows(format!("SELECT * FROM pragma_table_info('{}');", table))
+      .iter()
+      .map(|row| {
+        format!(
+          "{} {} notnull={} pk={} default={:?}",
+          row["name"],
+          row["type"],
+          row["notnull"],
+          row["pk"],
+          row.get("dflt_value")
+        )
+      })
+      .collect::<Vec<String>>();
+    columns.sort();
+    let query = format!("SELECT name FROM pragma_index_list('{}') WHERE origin = 'c';", table);
+    let mut indexes = rows(query)
+      .iter()
+      .map(|row| {
+        let query = format!("SELECT * FROM pragma_index_xinfo('{}') WHERE key = 1;", row["name"]);
+        let columns = rows(query)
+          .iter()
+          .map(|column| format!("{:?} {}", column.get("name"), column["coll"]))
+          .collect::<Vec<String>>();
+        format!("{}({})", row["name"], columns.join(", "))
+      })
+      .collect::<Vec<String>>();
+    indexes.sort();
+    (columns, indexes)
+  }
+
+  // The migrations are the schema of the database, the DDL derived from the
+  // fields of Song must describe the same table
+  #[test]
+  fn songs_table() {
+    let migrated = Connection::open(":memory:").unwrap();
+    run(&migrated).unwrap();
+    let created = Connection::open(":memory:").unwrap();
+    crate::Song::create_table(&created).unwrap();
+    assert_eq!(schema(&created, "songs"), schema(&migrated, "songs"));
+  }
+}
@@ -0,0 +1,133 @@
+// Paging of the list endpoints. Pages are numbered from 0 and cut in SQL with
+// LIMIT and OFFSET. A list comes with the total number of items:
+//
+//   {"items": [...], "total": 1234, "page": 2, "per_page": 50}
+//
+// Without per_page the whole list is returned, and per_page is null.
+//
+// Offsets shift when items are added or removed between two requests, which
+// shows as duplicated or skipped items in an infinite scroll. Lists ordered by
+// a unique key can be paged with a cursor instead of a page number: each page
+// then has a next_cursor, to pass as cursor to get the next page, until the
+// last one.
+use std::fmt;
+
+use anyhow::Result;
+use axum::{
+  http::StatusCode,
+  response::{IntoResponse, Response},
+  Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlite::{Connection, Value};
+
+use crate::execute_query;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Pagination {
+  pub page: Option<u32>,
+  pub per_page: Option<u32>,
+  pub cursor: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PaginationError(&'static str);
+
+impl fmt::Display for PaginationError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl std::error::Error for PaginationError {}
+
+impl IntoResponse for PaginationError {
+  fn into_response(self) -> Response {
+    let error = serde_json::json!({ "error": self.to_string() });
+    (StatusCode::BAD_REQUEST, Json(error)).into_response()
+  }
+}
+
+impl Pagination {
+  // The OFFSET and LIMIT of the page, for the lists which cannot be paged with
+  // a cursor
+  pub fn offset_and_limit(&self) -> Result<(u32, u32), PaginationError> {
+    if self.cursor.is_some() {
+      return Err(PaginationError("this list cannot be paged with a cursor"));
+    }
+    self
+      .cursor_offset_and_limit()
+      .map(|(_, offset, limit)| (offset, limit))
+  }
+
+  // The cursor the page starts after, if any, and its OFFSET and LIMIT
+  pub fn cursor_offset_and_limit(&self) -> Result<(Option<&str>, u32, u32), PaginationError> {
+    let limit = match self.per_page {
+      Some(0) => return Err(PaginationError("per_page must be positive")),
+      Some(per_page) => per_page,
+      None => u32::MAX,
+    };
+    match (self.cursor.as_deref(), self.page.unwrap_or(0), self.per_page) {
+      (Some(_), _, _) if self.page.is_some() => {
+        Err(PaginationError("page and cursor cannot be used together"))
+      }
+      (Some(_), _, None) => Err(PaginationError("cursor requires per_page")),
+      (cursor, 0, _) => Ok((cursor, 0, limit)),
+      (_, _, None) => Err(PaginationError("page requires per_page")),
+      (_, page, Some(per_page)) => match page.checked_mul(per_page) {
+        Some(offset) => Ok((None, offset, limit)),
+        None => Err(PaginationError("page is out of range")),
+      },
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+  pub items: Vec<T>,
+  pub total: u64,
+  pub page: u32,
+  pub per_page: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+  pub fn new(items: Vec<T>, total: u64, pagination: &Pagination) -> Page<T> {
+    Page {
+      items,
+      total,
+      page: pagination.page.unwrap_or(0),
+      per_page: pagination.per_page,
+      next_cursor: None,
+    }
+  }
+
+  // A page of a list paged with a cursor. There is a next page unless this one
+  // is not full, `cursor` giving the cursor of its last item.
+  pub fn with_cursor(
+    items: Vec<T>,
+    total: u64,
+    pagination: &Pagination,
+    cursor: impl Fn(&T) -> String,
+  ) -> Page<T> {
+    let full = pagination
+      .per_page
+      .is_some_and(|per_page| items.len() as u64 >= per_page as u64);
+    let next_cursor = items.last().filter(|_| full).map(cursor);
+    Page {
+      next_cursor,
+      ..Page::new(items, total, pagination)
+    }
+  }
+}
+
+// The total of a list, from a query selecting it as count
+pub fn count(connection: &Connection, query: &str, values: &[Value]) -> Result<u64> {
+  let rows = execute_query(connection, query, values)?;
+  let count = rows
+    .first()
+    .and_then(|row| row.get("count"))
+    .ok_or_else(|| anyhow::anyhow!("cannot count the items"))?;
+  Ok(count.parse::<u64>()?)
+}
@@ -0,0 +1,584 @@
+// Playlists, stored in the database. A playlist belongs to the user who created
+// it and only they can see or change it. Users are named by the header given
+// with --user-header, which an authenticating reverse proxy sets. There is no
+// such header by default, every request is then from a single anonymous user,
+// as are the requests without the header.
+//
+// A playlist is an ordered list of songs, where a song may appear several
+// times. Entries are added and removed by song id.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use axum::{
+  extract::FromRequestParts,
+  http::{request::Parts, HeaderName, StatusCode},
+  response::{IntoResponse, Response},
+  Json,
+};
+use field_list::FieldList;
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlite::{Connection, Value};
+
+use crate::{execute_query, pagination, Song};
+
+const PLAYLIST_COLUMNS: &str = "playlists.*, COUNT(songs.id) AS song_count, \
+  SUM(songs.duration_ms) AS duration_ms";
+// The songs of the entries, those which are gone are left out
+const PLAYLIST_SONGS: &str = "playlists \
+  LEFT JOIN playlist_entries ON playlist_entries.playlist_id = playlists.id \
+  LEFT JOIN songs ON songs.id = playlist_entries.song_id";
+
+// Held by the request having a transaction on the connection
+static TRANSACTION: Mutex<()> = Mutex::new(());
+
+// The header naming the user if one is configured, added to the requests as an
+// extension
+#[derive(Debug, Clone)]
+pub struct UserHeader(pub Option<HeaderName>);
+
+// The user a request is from, "" for the anonymous user
+#[derive(Debug)]
+pub struct Owner(pub String);
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Owner {
+  type Rejection = Infallible;
+
+  async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Owner, Infallible> {
+    let name = parts
+      .extensions
+      .get::<UserHeader>()
+      .and_then(|header| header.0.as_ref())
+      .and_then(|header| parts.headers.get(header))
+      .and_then(|value| value.to_str().ok())
+      .unwrap_or("");
+    Ok(Owner(name.trim().to_string()))
+  }
+}
+
+#[derive(Debug)]
+pub enum PlaylistError {
+  // Also when the playlist belongs to someone else
+  NotFound,
+  Invalid(&'static str),
+  Database(anyhow::Error),
+}
+
+impl From<anyhow::Error> for PlaylistError {
+  fn from(e: anyhow::Error) -> PlaylistError {
+    PlaylistError::Database(e)
+  }
+}
+
+impl From<sqlite::Error> for PlaylistError {
+  fn from(e: sqlite::Error) -> PlaylistError {
+    PlaylistError::Database(e.into())
+  }
+}
+
+impl IntoResponse for PlaylistError {
+  fn into_response(self) -> Response {
+    match self {
+      PlaylistError::NotFound => StatusCode::NOT_FOUND.into_response(),
+      PlaylistError::Invalid(error) => {
+        let error = serde_json::json!({ "error": error });
+        (StatusCode::BAD_REQUEST, Json(error)).into_response()
+      }
+      PlaylistError::Database(e) => {
+        tracing::error!("cannot access playlists: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+      }
+    }
+  }
+}
+
+// A row of the playlists table
+#[derive(Debug, FieldList)]
+#[field_list(table = "playlists")]
+struct StoredPlaylist {
+  #[field_list(primary_key)]
+  id: i64,
+  #[field_list(index)]
+  owner: String,
+  name: String,
+  description: Option<String>,
+  created_at: i64,
+  updated_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Playlist {
+  pub id: i64,
+  #[serde(skip)]
+  pub owner: String,
+  pub name: String,
+  pub description: Option<String>,
+  // Seconds since the epoch
+  pub created_at: i64,
+  pub updated_at: i64,
+  pub song_count: u32,
+  // Of the songs whose duration is known
+  pub duration_ms: u64,
+}
+
+// A playlist with its songs, in order
+#[derive(Debug, Serialize)]
+pub struct PlaylistWithSongs {
+  #[serde(flatten)]
+  pub playlist: Playlist,
+  pub songs: Vec<Song>,
+}
+
+// A new playlist, or the replacement of one
+#[derive(Debug, Deserialize)]
+pub struct PlaylistParams {
+  pub name: String,
+  #[serde(default)]
+  pub description: Option<String>,
+  #[serde(default)]
+  pub song_ids: Vec<String>,
+}
+
+// The fields to change, a null description removes it
+#[derive(Debug, Deserialize)]
+pub struct PlaylistChanges {
+  #[serde(default)]
+  pub name: Option<String>,
+  #[serde(default, deserialize_with = "present")]
+  pub description: Option<Option<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SongIds {
+  pub song_ids: Vec<String>,
+}
+
+// Tells a null value, Some(None), from a missing one, None
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<String>>, D::Error> {
+  Option::<String>::deserialize(deserializer).map(Some)
+}
+
+fn now() -> i64 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn valid_name(name: &str) -> Result<String, PlaylistError> {
+  match name.trim() {
+    "" => Err(PlaylistError::Invalid("name cannot be empty")),
+    name => Ok(name.to_string()),
+  }
+}
+
+fn check_songs_exist(connection: &Connection, song_ids: &[String]) -> Result<(), PlaylistError> {
+  for song_id in song_ids {
+    let rows = execute_query(
+      connection,
+      "SELECT id FROM songs WHERE id = ?;",
+      &[Value::String(song_id.clone())],
+    )?;
+    if rows.is_empty() {
+      return Err(PlaylistError::Invalid("unknown song id"));
+    }
+  }
+  Ok(())
+}
+
+// The song ids of the entries, in order
+fn entries(connection: &Connection, id: i64) -> Result<Vec<String>> {
+  let rows = execute_query(
+    connection,
+    "SELECT song_id FROM playlist_entries WHERE playlist_id = ? ORDER BY position;",
+    &[Value::Integer(id)],
+  )?;
+  Ok(
+    rows
+      .iter()
+      .filter_map(|row| row.get("song_id").cloned())
+      .collect(),
+  )
+}
+
+fn set_entries(connection: &Connection, id: i64, song_ids: &[String]) -> Result<()> {
+  execute_query(
+    connection,
+    "DELETE FROM playlist_entries WHERE playlist_id = ?;",
+    &[Value::Integer(id)],
+  )?;
+  for (position, song_id) in song_ids.iter().enumerate() {
+    execute_query(
+      connection,
+      "INSERT INTO playlist_entries (playlist_id, position, song_id) VALUES (?, ?, ?);",
+      &[
+        Value::Integer(id),
+        Value::Integer(position as i64),
+        Value::String(song_id.clone()),
+      ],
+    )?;
+  }
+  Ok(())
+}
+
+// Run `change` in a transaction, rolled back if it fails. The connection is
+// shared by the requests, which take turns to have a transaction on it.
+fn in_transaction<T>(
+  connection: &Connection,
+  change: impl FnOnce() -> Result<T, PlaylistError>,
+) -> Result<T, PlaylistError> {
+  let _turn = TRANSACTION.lock().unwrap_or_else(|e| e.into_inner());
+  connection.execute("BEGIN TRANSACTION;")?;
+  match change() {
+    Ok(value) => {
+      connection.execute("COMMIT;")?;
+      Ok(value)
+    }
+    Err(e) => {
+      if let Err(rollback) = connection.execute("ROLLBACK;") {
+        tracing::error!("cannot roll back the playlist change: {}", rollback);
+      }
+      Err(e)
+    }
+  }
+}
+
+fn touch(connection: &Connection, id: i64) -> Result<()> {
+  execute_query(
+    connection,
+    "UPDATE playlists SET updated_at = ? WHERE id = ?;",
+    &[Value::Integer(now()), Value::Integer(id)],
+  )?;
+  Ok(())
+}
+
+impl Playlist {
+  // A row of PLAYLIST_COLUMNS, the stored columns and the totals of the songs
+  fn from_row(row: &HashMap<String, Value>) -> Result<Playlist> {
+    let playlist = StoredPlaylist::from_hash(row)?;
+    // The sum of no durations is NULL
+    let total = |column: &str| match row.get(column) {
+      None | Some(Value::Null) => Ok(0),
+      Some(Value::Integer(total)) => Ok(u64::try_from(*total)?),
+      Some(value) => bail!("invalid {} {:?} of playlist {}", column, value, playlist.id),
+    };
+    Ok(Playlist {
+      song_count: u32::try_from(total("song_count")?)?,
+      duration_ms: total("duration_ms")?,
+      id: playlist.id,
+      owner: playlist.owner,
+      name: playlist.name,
+      description: playlist.description,
+      created_at: playlist.created_at,
+      updated_at: playlist.updated_at,
+    })
+  }
+
+  fn select(connection: &Connection, query: &str, values: &[Value]) -> Result<Vec<Playlist>> {
+    StoredPlaylist::execute_query(connection, query, values)?
+      .iter()
+      .map(Playlist::from_row)
+      .collect()
+  }
+
+  fn find(connection: &Connection, owner: &Owner, id: i64) -> Result<Playlist, PlaylistError> {
+    let query = format!(
+      "SELECT {} FROM {} WHERE playlists.id = ? GROUP BY playlists.id;",
+      PLAYLIST_COLUMNS, PLAYLIST_SONGS
+    );
+    Playlist::select(connection, &query, &[Value::Integer(id)])?
+      .pop()
+      .filter(|playlist| playlist.owner == owner.0)
+      .ok_or(PlaylistError::NotFound)
+  }
+
+  pub fn count(connection: &Connection, owner: &Owner) -> Result<u64> {
+    pagination::count(
+      connection,
+      "SELECT COUNT(*) AS count FROM playlists WHERE owner = ?;",
+      &[Value::String(owner.0.clone())],
+    )
+  }
+
+  // The playlists of the user, by name
+  pub fn get_all_with_pagination(
+    connection: &Connection,
+    owner: &Owner,
+    offset: u32,
+    limit: u32,
+  ) -> Result<Vec<Playlist>> {
+    let query = format!(
+      "SELECT {} FROM {} WHERE playlists.owner = ? GROUP BY playlists.id \
+       ORDER BY playlists.name COLLATE NOCASE, playlists.id LIMIT ? OFFSET ?;",
+      PLAYLIST_COLUMNS, PLAYLIST_SONGS
+    );
+    Playlist::select(
+      connection,
+      &query,
+      &[
+        Value::String(owner.0.clone()),
+        Value::Integer(limit as i64),
+        Value::Integer(offset as i64),
+      ],
+    )
+  }
+
+  pub fn get(
+    connection: &Connection,
+    owner: &Owner,
+    id: i64,
+  ) -> Result<PlaylistWithSongs, PlaylistError> {
+    let playlist = Playlist::find(connection, owner, id)?;
+    let songs = Song::select(
+      connection,
+      "SELECT songs.* FROM playlist_entries JOIN songs ON songs.id = playlist_entries.song_id \
+       WHERE playlist_entries.playlist_id = ? ORDER BY playlist_entries.position;",
+      &[Value::Integer(id)],
+    )?;
+    Ok(PlaylistWithSongs { playlist, songs })
+  }
+
+  pub fn create(
+    connection: &Connection,
+    owner: &Owner,
+    params: &PlaylistParams,
+  ) -> Result<PlaylistWithSongs, PlaylistError> {
+    let name = valid_name(&params.name)?;
+    let id = in_transaction(connection, || {
+      check_songs_exist(connection, &params.song_ids)?;
+      let now = now();
+      let rows = execute_query(
+        connection,
+        "INSERT INTO playlists (owner, name, description, created_at, updated_at) \
+         VALUES (?, ?, ?, ?, ?) RETURNING id;",
+        &[
+          Value::String(owner.0.clone()),
+          Value::String(name),
+          params
+            .description
+            .clone()
+            .map_or(Value::Null, Value::String),
+          Value::Integer(now),
+          Value::Integer(now),
+        ],
+      )?;
+      let id = rows
+        .first()
+        .and_then(|row| row.get("id"))
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("cannot read the id of the new playlist"))?;
+      set_entries(connection, id, &params.song_ids)?;
+      Ok(id)
+    })?;
+    Playlist::get(connection, owner, id)
+  }
+
+  // Replace the name, the description and the songs of the playlist
+  pub fn replace(
+    connection: &Connection,
+    owner: &Owner,
+    id: i64,
+    params: &PlaylistParams,
+  ) -> Result<PlaylistWithSongs, PlaylistError> {
+    let name = valid_name(&params.name)?;
+    in_transaction(connection, || {
+      Playlist::find(connection, owner, id)?;
+      check_songs_exist(connection, &params.song_ids)?;
+      execute_query(
+        connection,
+        "UPDATE playlists SET name = ?, description = ? WHERE id = ?;",
+        &[
+          Value::String(name),
+          params
+            .description
+            .clone()
+            .map_or(Value::Null, Value::String),
+          Value::Integer(id),
+        ],
+      )?;
+      set_entries(connection, id, &params.song_ids)?;
+      Ok(touch(connection, id)?)
+    })?;
+    Playlist::get(connection, owner, id)
+  }
+
+  pub fn update(
+    connection: &Connection,
+    owner: &Owner,
+    id: i64,
+    changes: &PlaylistChanges,
+  ) -> Result<PlaylistWithSongs, PlaylistError> {
+    let name = changes.name.as_deref().map(valid_name).transpose()?;
+    in_transaction(connection, || {
+      Playlist::find(connection, owner, id)?;
+      if let Some(name) = name {
+        execute_query(
+          connection,
+          "UPDATE playlists SET name = ? WHERE id = ?;",
+          &[Value::String(name), Value::Integer(id)],
+        )?;
+      }
+      if let Some(description) = &changes.description {
+        execute_query(
+          connection,
+          "UPDATE playlists SET description = ? WHERE id = ?;",
+          &[
+            description.clone().map_or(Value::Null, Value::String),
+            Value::Integer(id),
+          ],
+        )?;
+      }
+      Ok(touch(connection, id)?)
+    })?;
+    Playlist::get(connection, owner, id)
+  }
+
+  pub fn delete(connection: &Connection, owner: &Owner, id: i64) -> Result<(), PlaylistError> {
+    in_transaction(connection, || {
+      Playlist::find(connection, owner, id)?;
+      execute_query(
+        connection,
+        "DELETE FROM playlist_entries WHERE playlist_id = ?;",
+        &[Value::Integer(id)],
+      )?;
+      execute_query(connection, "DELETE FROM playlists WHERE id = ?;", &[Value::Integer(id)])?;
+      Ok(())
+    })
+  }
+
+  // Add the songs at the end of the playlist
+  pub fn append(
+    connection: &Connection,
+    owner: &Owner,
+    id: i64,
+    song_ids: &[String],
+  ) -> Result<PlaylistWithSongs, PlaylistError> {
+    in_transaction(connection, || {
+      Playlist::find(connection, owner, id)?;
+      check_songs_exist(connection, song_ids)?;
+      for song_id in song_ids {
+        execute_query(
+          connection,
+          "INSERT INTO playlist_entries (playlist_id, position, song_id) \
+           SELECT ?, COALESCE(MAX(position) + 1, 0), ? FROM playlist_entries \
+           WHERE playlist_id = ?;",
+          &[
+            Value::Integer(id),
+            Value::String(song_id.clone()),
+            Value::Integer(id),
+          ],
+        )?;
+      }
+      Ok(touch(connection, id)?)
+    })?;
+    Playlist::get(connection, owner, id)
+  }
+
+  // Remove every entry of the song
+  pub fn remove(
+    connection: &Connection,
+    owner: &Owner,
+    id: i64,
+    song_id: &str,
+  ) -> Result<PlaylistWithSongs, PlaylistError> {
+    in_transaction(connection, || {
+      Playlist::find(connection, owner, id)?;
+      if !entries(connection, id)?
+        .iter()
+        .any(|entry| entry == song_id)
+      {
+        return Err(PlaylistError::NotFound);
+      }
+      execute_query(
+        connection,
+        "DELETE FROM playlist_entries WHERE playlist_id = ? AND song_id = ?;",
+        &[Value::Integer(id), Value::String(song_id.to_string())],
+      )?;
+      Ok(touch(connection, id)?)
+    })?;
+    Playlist::get(connection, owner, id)
+  }
+
+  // Put the entries in the order of `song_ids`, which must list the songs of
+  // the playlist as many times as they are in it
+  pub fn reorder(
+    connection: &Connection,
+    owner: &Owner,
+    id: i64,
+    song_ids: &[String],
+  ) -> Result<PlaylistWithSongs, PlaylistError> {
+    in_transaction(connection, || {
+      Playlist::find(connection, owner, id)?;
+      let mut current = entries(connection, id)?;
+      let mut requested = song_ids.to_vec();
+      current.sort();
+      requested.sort();
+      if current != requested {
+        return Err(PlaylistError::Invalid("song_ids must be the songs of the playlist"));
+      }
+      set_entries(connection, id, song_ids)?;
+      Ok(touch(connection, id)?)
+    })?;
+    Playlist::get(connection, owner, id)
+  }
+}
+
+// Keep the playlist entries of a song whose content changed, and so its id
+pub fn replace_song(connection: &Connection, previous_id: &str, id: &str) -> Result<()> {
+  execute_query(
+    connection,
+    "UPDATE playlist_entries SET song_id = ? WHERE song_id = ?;",
+    &[
+      Value::String(id.to_string()),
+      Value::String(previous_id.to_string()),
+    ],
+  )?;
+  Ok(())
+}
+
+// Remove the entries of the songs which are gone
+pub fn remove_unused(connection: &Connection) -> Result<()> {
+  execute_query(
+    connection,
+    "DELETE FROM playlist_entries WHERE song_id NOT IN (SELECT id FROM songs);",
+    &[],
+  )?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn undecodable_rows() {
+    let connection = Connection::open(":memory:").unwrap();
+    crate::migrations::run(&connection).unwrap();
+    connection
+      .execute(
+        r#"
+        INSERT INTO playlists (owner, name, created_at, updated_at) VALUES
+          ('', 'Road trip', 1, 2),
+          ('', 'Broken', 'yesterday', 2);
+        "#,
+      )
+      .unwrap();
+    let owner = Owner(String::new());
+    let playlist = Playlist::get(&connection, &owner, 1).unwrap().playlist;
+    assert_eq!(
+      (playlist.name.as_str(), playlist.song_count, playlist.duration_ms),
+      ("Road trip", 0, 0)
+    );
+    // Reported rather than left out of the list
+    let error = Playlist::get_all_with_pagination(&connection, &owner, 0, 10).unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "invalid value String(\"yesterday\") in column created_at of table playlists"
+    );
+    assert!(matches!(
+      Playlist::get(&connection, &Owner("someone".to_string()), 1),
+      Err(PlaylistError::NotFound)
+    ));
+  }
+}
@@ -0,0 +1,461 @@
+// The query language of /search, compiled into an SQL condition on the songs
+// table and its full text index, songs_fts. The user input never ends up in the
+// SQL itself, only in bound values.
+//
+//   beatles                 free text, matched against all the indexed columns
+//   "abbey road"            a phrase
+//   beat*                   a prefix
+//   artist:beatles          a free text restricted to a field (artist, album,
+//   album:"abbey road"      title, genre, album_artist, composer, comment, label)
+//   year:1969               a year, or a range of years (bounds included, any of
+//   year:1960..1969         them can be omitted)
+//   -live  -year:..1960     negation of any of the above
+//
+// Terms are combined with AND.
+use std::fmt;
+
+use sqlite::Value;
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl std::error::Error for ParseError {}
+
+macro_rules! parse_error {
+  ($($arg:tt)*) => {
+    ParseError(format!($($arg)*))
+  };
+}
+
+#[derive(Debug, PartialEq)]
+enum Clause {
+  // A full text search, possibly restricted to a column
+  Text {
+    column: Option<&'static str>,
+    text: String,
+    prefix: bool,
+    negated: bool,
+  },
+  Year {
+    from: Option<i32>,
+    to: Option<i32>,
+    negated: bool,
+  },
+}
+
+// The columns which can be used as field filters
+const TEXT_FIELDS: [&str; 8] = [
+  "artist",
+  "album",
+  "title",
+  "genre",
+  "album_artist",
+  "composer",
+  "comment",
+  "label",
+];
+
+// A compiled query, ready to be used in a `WHERE` clause
+#[derive(Debug)]
+pub struct Query {
+  pub condition: String,
+  pub values: Vec<Value>,
+  // Whether the condition matches songs_fts, which must then be joined with songs
+  // on rowid. This also gives the rank of the songs.
+  pub full_text: bool,
+}
+
+pub fn parse(input: &str) -> Result<Query, ParseError> {
+  compile(tokenize(input)?)
+}
+
+fn parse_year(value: &str) -> Result<i32, ParseError> {
+  value
+    .parse::<i32>()
+    .map_err(|_| parse_error!("invalid year '{}'", value))
+}
+
+// Parse the value of a year: filter, `1990`, `1990..1999`, `1990..` or `..1999`
+fn parse_years(value: &str) -> Result<(Option<i32>, Option<i32>), ParseError> {
+  let (from, to) = match value.split_once("..") {
+    Some((from, to)) => {
+      let from = if from.is_empty() { None } else { Some(parse_year(from)?) };
+      let to = if to.is_empty() { None } else { Some(parse_year(to)?) };
+      (from, to)
+    }
+    None => {
+      let year = parse_year(value)?;
+      (Some(year), Some(year))
+    }
+  };
+  match (from, to) {
+    (None, None) => Err(parse_error!("empty year range")),
+    (Some(from), Some(to)) if from > to => Err(parse_error!("invalid year range {}", value)),
+    range => Ok(range),
+  }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Clause>, ParseError> {
+  let mut clauses = Vec::new();
+  let mut chars = input.chars().peekable();
+  loop {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    if chars.peek().is_none() {
+      break;
+    }
+    let negated = chars.next_if_eq(&'-').is_some();
+    // A field name is a word followed by ':'
+    let mut word = String::new();
+    let mut field = None;
+    let mut quoted = false;
+    while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '"') {
+      if c == ':' && field.is_none() && !word.is_empty() {
+        field = Some(std::mem::take(&mut word).to_lowercase());
+      } else {
+        word.push(c);
+      }
+    }
+    if word.is_empty() && chars.next_if_eq(&'"').is_some() {
+      quoted = true;
+      loop {
+        match chars.next() {
+          Some('"') => break,
+          Some(c) => word.push(c),
+          None => return Err(parse_error!("unterminated quote")),
+        }
+      }
+    }
+    let prefix = if quoted {
+      chars.next_if_eq(&'*').is_some()
+    } else {
+      word.ends_with('*') && word.pop().is_some()
+    };
+    if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+      return Err(parse_error!("unexpected character after '{}'", word));
+    }
+    let clause = match field.as_deref() {
+      Some("year") if word.is_empty() => return Err(parse_error!("missing value for year")),
+      Some("year") => {
+        let (from, to) = parse_years(&word)?;
+        Clause::Year { from, to, negated }
+      }
+      Some(name) => {
+        let Some(column) = TEXT_FIELDS.iter().find(|f| **f == name) else {
+          return Err(parse_error!("unknown field '{}'", name));
+        };
+        Clause::Text {
+          column: Some(column),
+          text: word,
+          prefix,
+          negated,
+        }
+      }
+      None => Clause::Text {
+        column: None,
+        text: word,
+        prefix,
+        negated,
+      },
+    };
+    if let Clause::Text { ref text, .. } = clause {
+      if text.trim().is_empty() {
+        return Err(match field {
+          Some(name) => parse_error!("missing value for {}", name),
+          None if negated => parse_error!("missing term after '-'"),
+          None => parse_error!("empty term"),
+        });
+      }
+    }
+    clauses.push(clause);
+  }
+  Ok(clauses)
+}
+
+// Quote a string for FTS5, where it becomes a phrase whatever it contains
+// https://www.sqlite.org/fts5.html#fts5_strings
+fn fts5_string(text: &str) -> String {
+  format!("\"{}\"", text.replace('"', "\"\""))
+}
+
+fn compile(clauses: Vec<Clause>) -> Result<Query, ParseError> {
+  let mut matches = Vec::new();
+  let mut excluded = Vec::new();
+  let mut conditions = Vec::new();
+  let mut values = Vec::new();
+  for clause in clauses {
+    match clause {
+      Clause::Text {
+        column,
+        text,
+        prefix,
+        negated,
+      } => {
+        let mut expression = fts5_string(&text);
+        if prefix {
+          expression.push_str(" *");
+        }
+        if let Some(column) = column {
+          expression = format!("{} : {}", column, expression);
+        }
+        if negated {
+          excluded.push(expression);
+        } else {
+          matches.push(expression);
+        }
+      }
+      Clause::Year { from, to, negated } => {
+        let condition = match (from, to) {
+          (Some(from), Some(to)) => {
+            values.extend([Value::Integer(from as i64), Value::Integer(to as i64)]);
+            "songs.year BETWEEN ? AND ?"
+          }
+          (Some(from), None) => {
+            values.push(Value::Integer(from as i64));
+            "songs.year >= ?"
+          }
+          (None, Some(to)) => {
+            values.push(Value::Integer(to as i64));
+            "songs.year <= ?"
+          }
+          (None, None) => unreachable!("parse_years rejects empty ranges"),
+        };
+        // Songs without a year are not in any range, so they are not excluded
+        conditions.push(if negated {
+          format!("(songs.year IS NULL OR NOT ({}))", condition)
+        } else {
+          condition.to_string()
+        });
+      }
+    }
+  }
+
+  // FTS5 only knows NOT as a binary operator, so negated terms alone are
+  // expressed in SQL.
+  let full_text = !matches.is_empty();
+  let excluded = (!excluded.is_empty()).then(|| excluded.join(" OR "));
+  let mut fts_values = Vec::new();
+  if full_text {
+    let mut expression = matches.join(" AND ");
+    if let Some(excluded) = excluded {
+      expression = format!("({}) NOT ({})", expression, excluded);
+    }
+    conditions.insert(0, "songs_fts MATCH ?".to_string());
+    fts_values.push(Value::String(expression));
+  } else if let Some(excluded) = excluded {
+    let condition = "songs.rowid NOT IN (SELECT rowid FROM songs_fts WHERE songs_fts MATCH ?)";
+    conditions.insert(0, condition.to_string());
+    fts_values.push(Value::String(excluded));
+  }
+  if conditions.is_empty() {
+    return Err(parse_error!("empty query"));
+  }
+  fts_values.extend(values);
+  Ok(Query {
+    condition: conditions.join(" AND "),
+    values: fts_values,
+    full_text,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn text(query: &Query) -> &str {
+    match &query.values[0] {
+      Value::String(text) => text,
+      value => panic!("not a full text expression: {:?}", value),
+    }
+  }
+
+  fn error(input: &str) -> String {
+    parse(input).unwrap_err().to_string()
+  }
+
+  #[test]
+  fn words() {
+    let query = parse("  abbey   road ").unwrap();
+    assert_eq!(query.condition, "songs_fts MATCH ?");
+    assert_eq!(query.values, [Value::String("\"abbey\" AND \"road\"".to_string())]);
+    assert!(query.full_text);
+  }
+
+  #[test]
+  fn quotes() {
+    assert_eq!(text(&parse("\"abbey road\"").unwrap()), "\"abbey road\"");
+    assert_eq!(text(&parse("\"abbey road\" beatles").unwrap()), "\"abbey road\" AND \"beatles\"");
+    // Operators and special characters in a phrase are only text
+    assert_eq!(text(&parse("\"a:b - c* (OR)\"").unwrap()), "\"a:b - c* (OR)\"");
+  }
+
+  #[test]
+  fn fts5_escaping() {
+    assert_eq!(fts5_string("say \"hi\""), "\"say \"\"hi\"\"\"");
+    assert_eq!(text(&parse("AND NOT").unwrap()), "\"AND\" AND \"NOT\"");
+    assert_eq!(text(&parse("(x)^{y}").unwrap()), "\"(x)^{y}\"");
+  }
+
+  #[test]
+  fn prefixes() {
+    assert_eq!(text(&parse("beat*").unwrap()), "\"beat\" *");
+    assert_eq!(text(&parse("\"abbey ro\"*").unwrap()), "\"abbey ro\" *");
+    assert_eq!(text(&parse("artist:beat*").unwrap()), "artist : \"beat\" *");
+    // Only a trailing '*' makes a prefix
+    assert_eq!(text(&parse("b*t").unwrap()), "\"b*t\"");
+    assert_eq!(error("*"), "empty term");
+  }
+
+  #[test]
+  fn fields() {
+    assert_eq!(text(&parse("artist:beatles").unwrap()), "artist : \"beatles\"");
+    assert_eq!(text(&parse("ALBUM:\"abbey road\"").unwrap()), "album : \"abbey road\"");
+    // Only the first ':' separates the field
+    assert_eq!(text(&parse("title:a:b").unwrap()), "title : \"a:b\"");
+    // A ':' without a field name is text
+    assert_eq!(text(&parse(":beatles").unwrap()), "\":beatles\"");
+    assert_eq!(error("lyrics:love"), "unknown field 'lyrics'");
+    assert_eq!(error("artist:"), "missing value for artist");
+    assert_eq!(error("artist:\"\""), "missing value for artist");
+  }
+
+  #[test]
+  fn years() {
+    let query = parse("year:1969").unwrap();
+    assert_eq!(query.condition, "songs.year BETWEEN ? AND ?");
+    assert_eq!(query.values, [Value::Integer(1969), Value::Integer(1969)]);
+    assert!(!query.full_text);
+
+    let query = parse("year:1960..").unwrap();
+    assert_eq!(query.condition, "songs.year >= ?");
+    assert_eq!(query.values, [Value::Integer(1960)]);
+
+    let query = parse("beatles year:..1969").unwrap();
+    assert_eq!(query.condition, "songs_fts MATCH ? AND songs.year <= ?");
+    assert_eq!(
+      query.values,
+      [
+        Value::String("\"beatles\"".to_string()),
+        Value::Integer(1969)
+      ]
+    );
+
+    assert_eq!(error("year:"), "missing value for year");
+    assert_eq!(error("year:.."), "empty year range");
+    assert_eq!(error("year:1969..1960"), "invalid year range 1969..1960");
+    assert_eq!(error("year:sixties"), "invalid year 'sixties'");
+  }
+
+  #[test]
+  fn negation() {
+    assert_eq!(text(&parse("beatles -live").unwrap()), "(\"beatles\") NOT (\"live\")");
+    assert_eq!(
+      text(&parse("beatles -live -album:\"let it be\"").unwrap()),
+      "(\"beatles\") NOT (\"live\" OR album : \"let it be\")"
+    );
+
+    let query = parse("-live").unwrap();
+    assert_eq!(
+      query.condition,
+      "songs.rowid NOT IN (SELECT rowid FROM songs_fts WHERE songs_fts MATCH ?)"
+    );
+    assert_eq!(query.values, [Value::String("\"live\"".to_string())]);
+    assert!(!query.full_text);
+
+    let query = parse("-year:..1960").unwrap();
+    assert_eq!(query.condition, "(songs.year IS NULL OR NOT (songs.year <= ?))");
+    assert_eq!(query.values, [Value::Integer(1960)]);
+
+    assert_eq!(
+      text(&parse("beatles -\"let it be\"").unwrap()),
+      "(\"beatles\") NOT (\"let it be\")"
+    );
+    assert_eq!(text(&parse("beatles -live*").unwrap()), "(\"beatles\") NOT (\"live\" *)");
+
+    let query = parse("-live -year:1970").unwrap();
+    assert_eq!(
+      query.condition,
+      "songs.rowid NOT IN (SELECT rowid FROM songs_fts WHERE songs_fts MATCH ?) \
+       AND (songs.year IS NULL OR NOT (songs.year BETWEEN ? AND ?))"
+    );
+    assert_eq!(
+      query.values,
+      [
+        Value::String("\"live\"".to_string()),
+        Value::Integer(1970),
+        Value::Integer(1970)
+      ]
+    );
+
+    // A '-' inside a word is text, only the first one negates
+    assert_eq!(text(&parse("a-ha").unwrap()), "\"a-ha\"");
+    assert_eq!(text(&parse("--live").unwrap()), "\"-live\"");
+    assert_eq!(error("-"), "missing term after '-'");
+    assert_eq!(error("beatles - live"), "missing term after '-'");
+  }
+
+  #[test]
+  fn unbalanced() {
+    assert_eq!(error("\"abbey road"), "unterminated quote");
+    assert_eq!(error("album:\"abbey"), "unterminated quote");
+    assert_eq!(error("abbey\""), "unexpected character after 'abbey'");
+    assert_eq!(error("\"abbey\"road"), "unexpected character after 'abbey'");
+    assert_eq!(error("\"abbey\"\"road\""), "unexpected character after 'abbey'");
+  }
+
+  // The titles of the songs found by the query among a few songs, some of them
+  // without artist or year
+  fn search(input: &str) -> Vec<String> {
+    let connection = sqlite::Connection::open(":memory:").unwrap();
+    connection
+      .execute(
+        r#"
+        CREATE TABLE songs (id TEXT, title TEXT, artist TEXT, album TEXT, genre TEXT,
+          album_artist TEXT, composer TEXT, comment TEXT, label TEXT, year INTEGER);
+        INSERT INTO songs (id, title, artist, year) VALUES
+          ('1', 'Help', 'The Beatles', 1965),
+          ('2', 'Live Forever', 'Oasis', NULL),
+          ('3', 'Untitled', NULL, 1950),
+          ('4', 'Live and Let Die', 'Wings', 1973);
+        CREATE VIRTUAL TABLE songs_fts USING fts5(title, artist, album, genre, album_artist,
+          composer, comment, label, content='songs', content_rowid='rowid');
+        INSERT INTO songs_fts(songs_fts) VALUES ('rebuild');
+        "#,
+      )
+      .unwrap();
+    let query = parse(input).unwrap();
+    let join =
+      if query.full_text { "JOIN songs_fts ON songs_fts.rowid = songs.rowid " } else { "" };
+    let sql =
+      format!("SELECT songs.title FROM songs {}WHERE {} ORDER BY songs.id;", join, query.condition);
+    crate::execute_query(&connection, &sql, &query.values)
+      .unwrap()
+      .iter()
+      .map(|row| row["title"].clone())
+      .collect()
+  }
+
+  #[test]
+  fn null_columns() {
+    assert_eq!(search("year:1960.."), ["Help", "Live and Let Die"]);
+    // Songs without a year or an artist are not excluded by a negated filter
+    assert_eq!(search("-year:..1960"), ["Help", "Live Forever", "Live and Let Die"]);
+    assert_eq!(search("-year:1960..1969"), ["Live Forever", "Untitled", "Live and Let Die"]);
+    assert_eq!(search("-artist:oasis"), ["Help", "Untitled", "Live and Let Die"]);
+    assert_eq!(search("live -artist:wings"), ["Live Forever"]);
+    assert_eq!(search("live -year:1970.."), ["Live Forever"]);
+  }
+
+  #[test]
+  fn empty() {
+    assert_eq!(error(""), "empty query");
+    assert_eq!(error("   "), "empty query");
+    assert_eq!(error("\"\""), "empty term");
+    assert_eq!(error("\" \""), "empty term");
+  }
+}
@@ -0,0 +1,104 @@
+// Sort names and the ordering of the lists. The sort name of an artist or an
+// album comes from the sort tags when there is one, otherwise it is the name
+// without its leading article, so that "The Beatles" sorts under B.
+//
+// Each list has its own keys to sort by, the artist and album lists share
+// SortBy.
+use serde::Deserialize;
+
+pub const DEFAULT_ARTICLES: [&str; 3] = ["The", "A", "An"];
+
+// The name without its leading article, case insensitively. A name which is
+// only an article is kept as is.
+pub fn sort_name(name: &str, articles: &[String]) -> String {
+  let name = name.trim();
+  for article in articles.iter().filter(|a| !a.is_empty()) {
+    let rest = name
+      .get(..article.len())
+      .filter(|prefix| prefix.eq_ignore_ascii_case(article))
+      .map(|_| &name[article.len()..]);
+    if let Some(rest) = rest.filter(|rest| rest.starts_with(char::is_whitespace)) {
+      if !rest.trim().is_empty() {
+        return rest.trim_start().to_string();
+      }
+    }
+  }
+  name.to_string()
+}
+
+// What a list can be sorted by
+pub trait SortKey: Copy + Default {
+  // Names are compared case insensitively
+  fn is_name(self) -> bool;
+  // Whether some items have no value, such as artists without a year
+  fn is_optional(self) -> bool {
+    false
+  }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortBy {
+  Name,
+  #[default]
+  SortName,
+  Year,
+  // When the songs were first scanned
+  Added,
+  SongCount,
+}
+
+impl SortKey for SortBy {
+  fn is_name(self) -> bool {
+    matches!(self, SortBy::Name | SortBy::SortName)
+  }
+
+  fn is_optional(self) -> bool {
+    self == SortBy::Year
+  }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+  #[default]
+  Asc,
+  Desc,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SortParams<S = SortBy> {
+  #[serde(default)]
+  pub sort: S,
+  #[serde(default)]
+  pub order: Order,
+}
+
+impl<S: SortKey> SortParams<S> {
+  // The ORDER BY clause on `column(sort)`, with missing values last whatever
+  // the order, then on `tie_breaker` and on the unique `key` for a stable order
+  // across pages. Every term has the same direction and none is repeated, so
+  // that an index on these columns serves it without sorting.
+  pub fn order_by(&self, column: impl Fn(S) -> &'static str, tie_breaker: S, key: &str) -> String {
+    let direction = match self.order {
+      Order::Asc => "ASC",
+      Order::Desc => "DESC",
+    };
+    let mut columns = Vec::new();
+    let mut terms = Vec::new();
+    if self.sort.is_optional() {
+      terms.push(format!("{} IS NULL", column(self.sort)));
+    }
+    for sort in [self.sort, tie_breaker] {
+      if !columns.contains(&column(sort)) {
+        let collation = if sort.is_name() { " COLLATE NOCASE" } else { "" };
+        terms.push(format!("{}{} {}", column(sort), collation, direction));
+        columns.push(column(sort));
+      }
+    }
+    if !columns.contains(&key) {
+      terms.push(format!("{} {}", key, direction));
+    }
+    terms.join(", ")
+  }
+}
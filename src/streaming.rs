@@ -0,0 +1,212 @@
+// Serves files from disk without loading them in memory, with support for HTTP
+// range requests (RFC 9110 section 14) so that the browser can seek in a song
+// without downloading it again from the start.
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+  body::Body,
+  http::{header, HeaderMap, HeaderValue, StatusCode},
+  response::{IntoResponse, Response},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+// A byte range, both ends included
+#[derive(Debug, PartialEq)]
+struct ByteRange {
+  start: u64,
+  end: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum RangeRequest {
+  // No Range header, or one we chose to ignore (multiple ranges, unknown unit)
+  Full,
+  Partial(ByteRange),
+  Unsatisfiable,
+}
+
+// Parse a Range header value against a resource of `len` bytes. Only single
+// ranges are honored. Multipart responses are not worth the trouble for audio
+// elements, which never ask for them, so we answer with the whole file instead
+// as allowed by the RFC.
+fn parse_range(value: &str, len: u64) -> RangeRequest {
+  let Some(spec) = value.trim().strip_prefix("bytes=") else {
+    return RangeRequest::Full;
+  };
+  if spec.contains(',') {
+    return RangeRequest::Full;
+  }
+  let Some((start, end)) = spec.trim().split_once('-') else {
+    return RangeRequest::Full;
+  };
+  let (start, end) = (start.trim(), end.trim());
+  let range = if start.is_empty() {
+    // Suffix range: the last `end` bytes
+    match end.parse::<u64>() {
+      Ok(0) => return RangeRequest::Unsatisfiable,
+      Ok(suffix) => ByteRange {
+        start: len.saturating_sub(suffix),
+        end: len.saturating_sub(1),
+      },
+      Err(_) => return RangeRequest::Full,
+    }
+  } else {
+    let Ok(start) = start.parse::<u64>() else {
+      return RangeRequest::Full;
+    };
+    let end = if end.is_empty() {
+      len.saturating_sub(1)
+    } else {
+      match end.parse::<u64>() {
+        Ok(end) if end >= start => std::cmp::min(end, len.saturating_sub(1)),
+        _ => return RangeRequest::Full,
+      }
+    };
+    ByteRange { start, end }
+  };
+  if len == 0 || range.start >= len {
+    return RangeRequest::Unsatisfiable;
+  }
+  RangeRequest::Partial(range)
+}
+
+// The entity tag is derived from the size and modification date of the file,
+// which is what we can afford without reading it.
+fn entity_tag(len: u64, modified: Option<SystemTime>) -> String {
+  let nanos = modified
+    .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+    .map_or(0, |d| d.as_nanos());
+  format!("\"{:x}-{:x}\"", len, nanos)
+}
+
+// Is the date `a` the same as `b` to the second, the precision of HTTP dates
+fn same_http_date(a: SystemTime, b: SystemTime) -> bool {
+  let seconds = |t: SystemTime| t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+  seconds(a) == seconds(b)
+}
+
+// Does the If-None-Match header value matches our entity tag
+pub fn etag_matches(value: &str, etag: &str) -> bool {
+  value
+    .split(',')
+    .map(|tag| tag.trim())
+    .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// If-Range contains either an entity tag or a date. The range is only honored
+// if the validator still matches the file, otherwise the client's partial copy
+// is stale and it needs the whole file.
+fn if_range_matches(value: &str, etag: &str, modified: Option<SystemTime>) -> bool {
+  let value = value.trim();
+  if value.starts_with('"') {
+    // Weak tags are not allowed in If-Range
+    value == etag
+  } else if value.starts_with("W/") {
+    false
+  } else {
+    match (httpdate::parse_http_date(value), modified) {
+      (Ok(date), Some(modified)) => same_http_date(date, modified),
+      _ => false,
+    }
+  }
+}
+
+pub fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+  headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+// Stream the file at `path` honoring the conditional and range headers of the
+// request.
+pub async fn serve_file(path: &Path, request_headers: &HeaderMap) -> Response {
+  let mut file = match tokio::fs::File::open(path).await {
+    Ok(file) => file,
+    // The file was removed or moved since the last scan
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+      tracing::warn!("{} not found", path.display());
+      return StatusCode::NOT_FOUND.into_response();
+    }
+    Err(e) => {
+      tracing::error!("cannot open {}: {}", path.display(), e);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+  let metadata = match file.metadata().await {
+    Ok(metadata) => metadata,
+    Err(e) => {
+      tracing::error!("cannot stat {}: {}", path.display(), e);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+  let len = metadata.len();
+  let modified = metadata.modified().ok();
+  let etag = entity_tag(len, modified);
+  let mime = mime_guess::from_path(path).first_or_octet_stream();
+
+  let mut headers = HeaderMap::new();
+  headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+  if let Ok(value) = HeaderValue::from_str(&etag) {
+    headers.insert(header::ETAG, value);
+  }
+  if let Some(modified) = modified {
+    if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(modified)) {
+      headers.insert(header::LAST_MODIFIED, value);
+    }
+  }
+
+  // Conditional GET. If-None-Match takes precedence over If-Modified-Since.
+  let not_modified = if let Some(value) = header_str(request_headers, header::IF_NONE_MATCH) {
+    etag_matches(value, &etag)
+  } else if let Some(value) = header_str(request_headers, header::IF_MODIFIED_SINCE) {
+    match (httpdate::parse_http_date(value), modified) {
+      (Ok(since), Some(modified)) => same_http_date(since, modified) || modified <= since,
+      _ => false,
+    }
+  } else {
+    false
+  };
+  if not_modified {
+    return (StatusCode::NOT_MODIFIED, headers).into_response();
+  }
+
+  let range = match header_str(request_headers, header::RANGE) {
+    Some(range) => match header_str(request_headers, header::IF_RANGE) {
+      Some(if_range) if !if_range_matches(if_range, &etag, modified) => RangeRequest::Full,
+      _ => parse_range(range, len),
+    },
+    None => RangeRequest::Full,
+  };
+
+  let (status, start, length) = match range {
+    RangeRequest::Full => (StatusCode::OK, 0, len),
+    RangeRequest::Partial(ByteRange { start, end }) => {
+      let content_range = format!("bytes {}-{}/{}", start, end, len);
+      if let Ok(value) = HeaderValue::from_str(&content_range) {
+        headers.insert(header::CONTENT_RANGE, value);
+      }
+      (StatusCode::PARTIAL_CONTENT, start, end - start + 1)
+    }
+    RangeRequest::Unsatisfiable => {
+      if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", len)) {
+        headers.insert(header::CONTENT_RANGE, value);
+      }
+      return (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response();
+    }
+  };
+
+  if start > 0 {
+    if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+      tracing::error!("cannot seek {} to {}: {}", path.display(), start, e);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  }
+  if let Ok(value) = HeaderValue::from_str(mime.as_ref()) {
+    headers.insert(header::CONTENT_TYPE, value);
+  }
+  headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+
+  let body = Body::from_stream(ReaderStream::new(file.take(length)));
+  (status, headers, body).into_response()
+}
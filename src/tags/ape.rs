@@ -0,0 +1,104 @@
+// APEv2 tags, found at the end of Monkey's Audio, Musepack, WavPack and some
+// MPEG files, possibly followed by an ID3v1 tag.
+// https://wiki.hydrogenaud.io/index.php?title=APEv2_specification
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::{tags_from_pairs, u32_le, TagReader, Tags};
+
+const FOOTER_SIZE: u64 = 32;
+const ID3V1_SIZE: u64 = 128;
+// Flag of items whose value is binary or an external reference, as opposed to
+// UTF-8 text.
+pub(super) const ITEM_NOT_TEXT: u32 = 0b10;
+
+pub struct ApeReader;
+
+// Locate the APE footer and return the items part of the tag
+pub(super) fn read_items(file: &mut fs::File) -> Result<Option<(u32, Vec<u8>)>> {
+  let len = file.metadata()?.len();
+  for trailer in [0, ID3V1_SIZE] {
+    let Some(footer_position) = len.checked_sub(FOOTER_SIZE + trailer) else {
+      continue;
+    };
+    let mut footer = [0u8; FOOTER_SIZE as usize];
+    file.seek(SeekFrom::Start(footer_position))?;
+    file.read_exact(&mut footer)?;
+    if &footer[0..8] != b"APETAGEX" {
+      continue;
+    }
+    // The size includes the footer but not the optional header
+    let size = u32_le(&footer, 12)? as u64;
+    let count = u32_le(&footer, 16)?;
+    let items_size = size
+      .checked_sub(FOOTER_SIZE)
+      .filter(|s| *s <= footer_position)
+      .ok_or_else(|| anyhow::anyhow!("invalid APE tag size {}", size))?;
+    let mut items = vec![0u8; items_size as usize];
+    file.seek(SeekFrom::Start(footer_position - items_size))?;
+    file.read_exact(&mut items)?;
+    return Ok(Some((count, items)));
+  }
+  Ok(None)
+}
+
+// Split the items of the tag into their key, flags and raw value
+pub(super) fn raw_items(count: u32, data: &[u8]) -> Result<Vec<(String, u32, &[u8])>> {
+  let mut items = Vec::new();
+  let mut offset = 0;
+  for _ in 0..count {
+    let size = u32_le(data, offset)? as usize;
+    let flags = u32_le(data, offset + 4)?;
+    offset += 8;
+    let key_length = data
+      .get(offset..)
+      .and_then(|rest| rest.iter().position(|&b| b == 0))
+      .ok_or_else(|| anyhow::anyhow!("unterminated APE item key"))?;
+    let key = String::from_utf8_lossy(&data[offset..offset + key_length]).to_string();
+    offset += key_length + 1;
+    let value = data
+      .get(offset..offset + size)
+      .ok_or_else(|| anyhow::anyhow!("truncated APE item {}", key))?;
+    offset += size;
+    items.push((key, flags, value));
+  }
+  Ok(items)
+}
+
+// Parse the items of the tag into key/value pairs, ignoring the binary ones
+fn parse_items(count: u32, data: &[u8]) -> Result<Vec<(String, String)>> {
+  let mut items = Vec::new();
+  for (key, flags, value) in raw_items(count, data)? {
+    if flags & ITEM_NOT_TEXT == 0 {
+      // Multiple values are separated by \0
+      for value in String::from_utf8_lossy(value).split('\0') {
+        items.push((key.clone(), value.to_string()));
+      }
+    }
+  }
+  Ok(items)
+}
+
+impl TagReader for ApeReader {
+  fn name(&self) -> &'static str {
+    "ape"
+  }
+
+  // The tag is at the end of the file, only the extension can give a hint
+  fn accepts(&self, _header: &[u8], path: &Path) -> bool {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    matches!(extension.as_deref(), Some("ape" | "mpc" | "wv" | "mp3"))
+  }
+
+  fn read(&self, path: &Path) -> Result<Tags> {
+    let mut file = fs::File::open(path)?;
+    let Some((count, data)) = read_items(&mut file)? else {
+      anyhow::bail!("no APE tag");
+    };
+    let items = parse_items(count, &data)?;
+    Ok(tags_from_pairs(items.iter().map(|(k, v)| (k.as_str(), v.as_str()))))
+  }
+}
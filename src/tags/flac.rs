@@ -0,0 +1,56 @@
+// FLAC files store Vorbis comments in a metadata block
+// https://xiph.org/flac/format.html#metadata_block
+use std::fs;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::{tags_from_pairs, vorbis, TagReader, Tags};
+
+const VORBIS_COMMENT: u8 = 4;
+
+pub struct FlacReader;
+
+// A metadata block header: is it the last block, its type and its length
+pub(super) fn read_block_header(reader: &mut impl Read) -> Result<(bool, u8, u32)> {
+  let mut header = [0u8; 4];
+  reader.read_exact(&mut header)?;
+  let length = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+  Ok((header[0] & 0x80 != 0, header[0] & 0x7f, length))
+}
+
+impl TagReader for FlacReader {
+  fn name(&self) -> &'static str {
+    "flac"
+  }
+
+  fn accepts(&self, header: &[u8], _path: &Path) -> bool {
+    header.starts_with(b"fLaC")
+  }
+
+  fn read(&self, path: &Path) -> Result<Tags> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    reader.seek(SeekFrom::Start(4))?;
+    loop {
+      let (last, block_type, length) = read_block_header(&mut reader)?;
+      match block_type {
+        VORBIS_COMMENT => {
+          let mut data = vec![0u8; length as usize];
+          reader.read_exact(&mut data)?;
+          let comments = vorbis::parse_comments(&data)?;
+          return Ok(tags_from_pairs(comments.iter().map(|(k, v)| (k.as_str(), v.as_str()))));
+        }
+        // An invalid block type is the sign we are not reading metadata anymore
+        127 => anyhow::bail!("invalid metadata block"),
+        _ => {
+          reader.seek_relative(length as i64)?;
+        }
+      }
+      if last {
+        // A FLAC file without comments is still a valid song
+        return Ok(Tags::default());
+      }
+    }
+  }
+}
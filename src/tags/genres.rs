@@ -0,0 +1,250 @@
+// Genres are often stored as a reference to the ID3v1 list of genres, as a
+// number ("17") or in the ID3v2 TCON syntax ("(17)", "(17)Rock", "(RX)").
+// https://id3.org/id3v2.3.0#TCON
+
+// The ID3v1 genres with the Winamp extensions
+const GENRES: [&str; 192] = [
+  "Blues",
+  "Classic Rock",
+  "Country",
+  "Dance",
+  "Disco",
+  "Funk",
+  "Grunge",
+  "Hip-Hop",
+  "Jazz",
+  "Metal",
+  "New Age",
+  "Oldies",
+  "Other",
+  "Pop",
+  "R&B",
+  "Rap",
+  "Reggae",
+  "Rock",
+  "Techno",
+  "Industrial",
+  "Alternative",
+  "Ska",
+  "Death Metal",
+  "Pranks",
+  "Soundtrack",
+  "Euro-Techno",
+  "Ambient",
+  "Trip-Hop",
+  "Vocal",
+  "Jazz+Funk",
+  "Fusion",
+  "Trance",
+  "Classical",
+  "Instrumental",
+  "Acid",
+  "House",
+  "Game",
+  "Sound Clip",
+  "Gospel",
+  "Noise",
+  "Alternative Rock",
+  "Bass",
+  "Soul",
+  "Punk",
+  "Space",
+  "Meditative",
+  "Instrumental Pop",
+  "Instrumental Rock",
+  "Ethnic",
+  "Gothic",
+  "Darkwave",
+  "Techno-Industrial",
+  "Electronic",
+  "Pop-Folk",
+  "Eurodance",
+  "Dream",
+  "Southern Rock",
+  "Comedy",
+  "Cult",
+  "Gangsta",
+  "Top 40",
+  "Christian Rap",
+  "Pop/Funk",
+  "Jungle",
+  "Native American",
+  "Cabaret",
+  "New Wave",
+  "Psychedelic",
+  "Rave",
+  "Showtunes",
+  "Trailer",
+  "Lo-Fi",
+  "Tribal",
+  "Acid Punk",
+  "Acid Jazz",
+  "Polka",
+  "Retro",
+  "Musical",
+  "Rock & Roll",
+  "Hard Rock",
+  "Folk",
+  "Folk-Rock",
+  "National Folk",
+  "Swing",
+  "Fast Fusion",
+  "Bebop",
+  "Latin",
+  "Revival",
+  "Celtic",
+  "Bluegrass",
+  "Avantgarde",
+  "Gothic Rock",
+  "Progressive Rock",
+  "Psychedelic Rock",
+  "Symphonic Rock",
+  "Slow Rock",
+  "Big Band",
+  "Chorus",
+  "Easy Listening",
+  "Acoustic",
+  "Humour",
+  "Speech",
+  "Chanson",
+  "Opera",
+  "Chamber Music",
+  "Sonata",
+  "Symphony",
+  "Booty Bass",
+  "Primus",
+  "Porn Groove",
+  "Satire",
+  "Slow Jam",
+  "Club",
+  "Tango",
+  "Samba",
+  "Folklore",
+  "Ballad",
+  "Power Ballad",
+  "Rhythmic Soul",
+  "Freestyle",
+  "Duet",
+  "Punk Rock",
+  "Drum Solo",
+  "A Cappella",
+  "Euro-House",
+  "Dance Hall",
+  "Goa",
+  "Drum & Bass",
+  "Club-House",
+  "Hardcore Techno",
+  "Terror",
+  "Indie",
+  "BritPop",
+  "Afro-Punk",
+  "Polsk Punk",
+  "Beat",
+  "Christian Gangsta Rap",
+  "Heavy Metal",
+  "Black Metal",
+  "Crossover",
+  "Contemporary Christian",
+  "Christian Rock",
+  "Merengue",
+  "Salsa",
+  "Thrash Metal",
+  "Anime",
+  "JPop",
+  "Synthpop",
+  "Abstract",
+  "Art Rock",
+  "Baroque",
+  "Bhangra",
+  "Big Beat",
+  "Breakbeat",
+  "Chillout",
+  "Downtempo",
+  "Dub",
+  "EBM",
+  "Eclectic",
+  "Electro",
+  "Electroclash",
+  "Emo",
+  "Experimental",
+  "Garage",
+  "Global",
+  "IDM",
+  "Illbient",
+  "Industro-Goth",
+  "Jam Band",
+  "Krautrock",
+  "Leftfield",
+  "Lounge",
+  "Math Rock",
+  "New Romantic",
+  "Nu-Breakz",
+  "Post-Punk",
+  "Post-Rock",
+  "Psytrance",
+  "Shoegaze",
+  "Space Rock",
+  "Trop Rock",
+  "World Music",
+  "Neoclassical",
+  "Audiobook",
+  "Audio Theatre",
+  "Neue Deutsche Welle",
+  "Podcast",
+  "Indie Rock",
+  "G-Funk",
+  "Dubstep",
+  "Garage Rock",
+  "Psybient",
+];
+
+// The name of an ID3v1 genre
+pub fn genre_name(index: usize) -> Option<&'static str> {
+  GENRES.get(index).copied()
+}
+
+// Resolve the references to ID3v1 genres in a genre. Several genres are joined
+// with "; ". Unknown references are kept as is.
+pub fn resolve(value: &str) -> Option<String> {
+  let value = value.trim();
+  if value.is_empty() {
+    return None;
+  }
+  if value.bytes().all(|b| b.is_ascii_digit()) {
+    return Some(reference_name(value).unwrap_or(value).to_string());
+  }
+  let mut genres: Vec<&str> = Vec::new();
+  let mut rest = value;
+  // A sequence of "(reference)", possibly followed by a refinement. "((" starts
+  // a genre name beginning with a parenthesis.
+  while rest.starts_with('(') && !rest.starts_with("((") {
+    let Some(end) = rest.find(')') else {
+      break;
+    };
+    genres.push(match &rest[1..end] {
+      "RX" => "Remix",
+      "CR" => "Cover",
+      reference => reference_name(reference).unwrap_or(&rest[..=end]),
+    });
+    rest = &rest[end + 1..];
+  }
+  let rest = rest
+    .strip_prefix('(')
+    .filter(|r| r.starts_with('('))
+    .unwrap_or(rest)
+    .trim();
+  if !rest.is_empty() {
+    genres.push(rest);
+  }
+  let mut unique: Vec<&str> = Vec::new();
+  for genre in genres {
+    if !unique.iter().any(|g| g.eq_ignore_ascii_case(genre)) {
+      unique.push(genre);
+    }
+  }
+  Some(unique.join("; "))
+}
+
+fn reference_name(reference: &str) -> Option<&'static str> {
+  genre_name(reference.parse::<usize>().ok()?)
+}
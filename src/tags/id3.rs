@@ -0,0 +1,102 @@
+// ID3v2 and ID3v1 tags, found in MPEG files (and sometimes elsewhere)
+use std::path::Path;
+
+use ::id3::TagLike;
+use anyhow::Result;
+
+use super::{join_genres, parse_bpm, TagReader, Tags};
+
+// ID3v2 tags, at the start of the file
+pub struct Id3Reader;
+
+// ID3v1 tags are at the end of the file so we rely on the extension to decide
+// whether to look for them. They are limited so any other tag is preferred.
+pub struct Id3v1Reader;
+
+fn text(tag: &::id3::Tag, frame: &str) -> Option<String> {
+  tag
+    .text_for_frame_id(frame)
+    .map(|s| s.trim().to_string())
+    .filter(|s| !s.is_empty())
+}
+
+// The values of a text frame, separated by \0 in ID3v2.4
+fn text_values(tag: &::id3::Tag, frame: &str) -> Vec<String> {
+  tag
+    .get(frame)
+    .and_then(|frame| frame.content().text_values())
+    .into_iter()
+    .flatten()
+    .map(|s| s.trim().to_string())
+    .filter(|s| !s.is_empty())
+    .collect()
+}
+
+fn tags_from_id3(tag: &::id3::Tag) -> Tags {
+  // Prefer the comment without description, the others are often used by
+  // software to store their own data (iTunNORM...)
+  let comment = tag
+    .comments()
+    .find(|c| c.description.is_empty())
+    .or_else(|| tag.comments().next())
+    .map(|c| c.text.trim().to_string())
+    .filter(|s| !s.is_empty());
+  Tags {
+    title: tag.title().map(|s| s.to_string()),
+    artists: text_values(tag, "TPE1"),
+    album: tag.album().map(|s| s.to_string()),
+    // TYER is gone in ID3v2.4, which uses TDRC instead
+    year: tag.year().or_else(|| tag.date_recorded().map(|d| d.year)),
+    track: tag.track(),
+    disc: tag.disc(),
+    // TCON can contain several genres, separated by \0 in ID3v2.4
+    genre: tag.genres().and_then(join_genres),
+    album_artists: text_values(tag, "TPE2"),
+    composer: text(tag, "TCOM"),
+    comment,
+    total_tracks: tag.total_tracks(),
+    total_discs: tag.total_discs(),
+    bpm: text(tag, "TBPM").and_then(|s| parse_bpm(&s)),
+    // TDOR in ID3v2.4, TORY (a year) in ID3v2.3
+    original_date: text(tag, "TDOR").or_else(|| text(tag, "TORY")),
+    label: text(tag, "TPUB"),
+    isrc: text(tag, "TSRC"),
+    musicbrainz_album_id: tag
+      .extended_texts()
+      .find(|t| t.description.eq_ignore_ascii_case("MusicBrainz Album Id"))
+      .map(|t| t.value.trim().to_string())
+      .filter(|s| !s.is_empty()),
+    artist_sorts: text_values(tag, "TSOP"),
+    album_artist_sorts: text_values(tag, "TSO2"),
+    album_sort: text(tag, "TSOA"),
+  }
+}
+
+impl TagReader for Id3Reader {
+  fn name(&self) -> &'static str {
+    "id3"
+  }
+
+  fn accepts(&self, header: &[u8], _path: &Path) -> bool {
+    header.starts_with(b"ID3")
+  }
+
+  fn read(&self, path: &Path) -> Result<Tags> {
+    Ok(tags_from_id3(&::id3::Tag::read_from_path(path)?))
+  }
+}
+
+impl TagReader for Id3v1Reader {
+  fn name(&self) -> &'static str {
+    "id3v1"
+  }
+
+  fn accepts(&self, _header: &[u8], path: &Path) -> bool {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    matches!(extension.as_deref(), Some("mp3" | "mp2" | "mp1"))
+  }
+
+  fn read(&self, path: &Path) -> Result<Tags> {
+    Ok(tags_from_id3(&::id3::v1::Tag::read_from_path(path)?.into()))
+  }
+}
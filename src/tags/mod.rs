@@ -0,0 +1,202 @@
+// Reads the metadata of music files whatever their tag format. Each format is
+// handled by a TagReader which produces the same normalized Tags structure,
+// from which Song entries are built.
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::Result;
+
+mod ape;
+mod flac;
+mod genres;
+mod id3;
+mod mp4;
+mod ogg;
+mod payload;
+mod pictures;
+mod properties;
+mod vorbis;
+
+pub use payload::audio_ranges;
+pub use pictures::{read_picture, sniff_mime_type, Picture};
+pub use properties::{read_properties, AudioProperties};
+
+// The tags we extract from a file, independently of the tag format
+#[derive(Debug, Default)]
+pub struct Tags {
+  pub title: Option<String>,
+  // Tag formats allow several artists, the values are kept apart as found.
+  // They may still name several artists each ("A; B", "A feat. B").
+  pub artists: Vec<String>,
+  pub album: Option<String>,
+  pub year: Option<i32>,
+  pub track: Option<u32>,
+  pub disc: Option<u32>,
+  pub genre: Option<String>,
+  pub album_artists: Vec<String>,
+  pub composer: Option<String>,
+  pub comment: Option<String>,
+  pub total_tracks: Option<u32>,
+  pub total_discs: Option<u32>,
+  pub bpm: Option<u32>,
+  // As found in the tags, usually YYYY-MM-DD or YYYY
+  pub original_date: Option<String>,
+  pub label: Option<String>,
+  pub isrc: Option<String>,
+  // Identifies the release the song belongs to
+  pub musicbrainz_album_id: Option<String>,
+  // How to sort the artists, album artists and album ("Beatles, The")
+  pub artist_sorts: Vec<String>,
+  pub album_artist_sorts: Vec<String>,
+  pub album_sort: Option<String>,
+}
+
+pub trait TagReader: Sync {
+  // Name of the format, for logging purposes
+  fn name(&self) -> &'static str;
+  // Does this reader handle the file. `header` contains the first bytes of the
+  // file (it may be shorter than HEADER_SIZE for small files).
+  fn accepts(&self, header: &[u8], path: &Path) -> bool;
+  fn read(&self, path: &Path) -> Result<Tags>;
+}
+
+const HEADER_SIZE: usize = 12;
+
+// The readers are tried in that order. The first one accepting the file and
+// succeeding to read it wins.
+static READERS: &[&dyn TagReader] = &[
+  &flac::FlacReader,
+  &ogg::OggReader,
+  &mp4::Mp4Reader,
+  &id3::Id3Reader,
+  &ape::ApeReader,
+  &id3::Id3v1Reader,
+];
+
+pub fn read_from_path(path: &Path) -> Result<Tags> {
+  let mut header = Vec::with_capacity(HEADER_SIZE);
+  fs::File::open(path)?
+    .take(HEADER_SIZE as u64)
+    .read_to_end(&mut header)?;
+  let mut last_error = None;
+  for reader in READERS.iter().filter(|r| r.accepts(&header, path)) {
+    match reader.read(path) {
+      Ok(tags) => return Ok(tags),
+      Err(e) => {
+        tracing::debug!("{} reader failed on {} ({})", reader.name(), path.display(), e);
+        last_error = Some(e);
+      }
+    }
+  }
+  Err(last_error.unwrap_or_else(|| anyhow::anyhow!("unsupported file format")))
+}
+
+// Parse a number from tags which often come as "3/12" or " 03"
+fn parse_number<T: std::str::FromStr>(value: &str) -> Option<T> {
+  value.split('/').next()?.trim().parse::<T>().ok()
+}
+
+// The total of tags which come as "3/12"
+fn parse_total<T: std::str::FromStr>(value: &str) -> Option<T> {
+  value.split('/').nth(1)?.trim().parse::<T>().ok()
+}
+
+// Beats per minute are sometimes decimal
+fn parse_bpm(value: &str) -> Option<u32> {
+  value
+    .trim()
+    .parse::<f64>()
+    .ok()
+    .filter(|bpm| *bpm > 0.0 && *bpm < u32::MAX as f64)
+    .map(|bpm| bpm.round() as u32)
+}
+
+// A list of genres, each of them possibly an ID3v1 reference
+fn join_genres<'a>(values: impl IntoIterator<Item = &'a str>) -> Option<String> {
+  let genres = values
+    .into_iter()
+    .filter_map(genres::resolve)
+    .collect::<Vec<String>>();
+  (!genres.is_empty()).then(|| genres.join("; "))
+}
+
+// Extract the year from a date which can be "1999", "1999-05-12", "1999-05"...
+fn parse_year(value: &str) -> Option<i32> {
+  let value = value.trim();
+  let digits = value
+    .find(|c: char| !c.is_ascii_digit())
+    .unwrap_or(value.len());
+  if digits == 4 {
+    value[..4].parse::<i32>().ok()
+  } else {
+    None
+  }
+}
+
+// Formats like Vorbis comments or APE store key/value pairs where keys are case
+// insensitive and can appear several times. This builds the Tags from them.
+fn tags_from_pairs<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> Tags {
+  let mut tags = Tags::default();
+  let mut genres: Vec<&str> = Vec::new();
+  for (key, value) in pairs {
+    let value = value.trim();
+    if value.is_empty() {
+      continue;
+    }
+    let text = Some(value.to_string());
+    // An APE item holds several values separated by \0
+    let values = || value.split('\0').map(str::trim).filter(|v| !v.is_empty());
+    match key.to_ascii_uppercase().as_str() {
+      "TITLE" if tags.title.is_none() => tags.title = text,
+      "ARTIST" => tags.artists.extend(values().map(str::to_string)),
+      "ALBUM" if tags.album.is_none() => tags.album = text,
+      "DATE" | "YEAR" if tags.year.is_none() => tags.year = parse_year(value),
+      "TRACKNUMBER" | "TRACK" if tags.track.is_none() => {
+        tags.track = parse_number(value);
+        tags.total_tracks = tags.total_tracks.or_else(|| parse_total(value));
+      }
+      "DISCNUMBER" | "DISC" if tags.disc.is_none() => {
+        tags.disc = parse_number(value);
+        tags.total_discs = tags.total_discs.or_else(|| parse_total(value));
+      }
+      "TRACKTOTAL" | "TOTALTRACKS" => tags.total_tracks = parse_number(value),
+      "DISCTOTAL" | "TOTALDISCS" => tags.total_discs = parse_number(value),
+      "GENRE" => genres.extend(values()),
+      "ALBUMARTIST" | "ALBUM ARTIST" | "ALBUM_ARTIST" => {
+        tags.album_artists.extend(values().map(str::to_string))
+      }
+      "COMPOSER" if tags.composer.is_none() => tags.composer = text,
+      "COMMENT" | "DESCRIPTION" if tags.comment.is_none() => tags.comment = text,
+      "BPM" if tags.bpm.is_none() => tags.bpm = parse_bpm(value),
+      "ORIGINALDATE" | "ORIGINALYEAR" if tags.original_date.is_none() => tags.original_date = text,
+      "LABEL" | "ORGANIZATION" | "PUBLISHER" if tags.label.is_none() => tags.label = text,
+      "ISRC" if tags.isrc.is_none() => tags.isrc = text,
+      // The second form is the name used by Picard in MP4 and APE tags
+      "MUSICBRAINZ_ALBUMID" | "MUSICBRAINZ ALBUM ID" if tags.musicbrainz_album_id.is_none() => {
+        tags.musicbrainz_album_id = text
+      }
+      "ARTISTSORT" => tags.artist_sorts.extend(values().map(str::to_string)),
+      "ALBUMARTISTSORT" => tags.album_artist_sorts.extend(values().map(str::to_string)),
+      "ALBUMSORT" if tags.album_sort.is_none() => tags.album_sort = text,
+      _ => (),
+    }
+  }
+  tags.genre = join_genres(genres);
+  tags
+}
+
+// Little helpers to decode binary headers
+fn u32_le(bytes: &[u8], offset: usize) -> Result<u32> {
+  bytes
+    .get(offset..offset + 4)
+    .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    .ok_or_else(|| anyhow::anyhow!("truncated data at offset {}", offset))
+}
+
+fn u32_be(bytes: &[u8], offset: usize) -> Result<u32> {
+  bytes
+    .get(offset..offset + 4)
+    .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    .ok_or_else(|| anyhow::anyhow!("truncated data at offset {}", offset))
+}
@@ -0,0 +1,261 @@
+// MP4/M4A files (AAC, ALAC). Tags are stored as atoms in moov.udta.meta.ilst.
+// https://developer.apple.com/documentation/quicktime-file-format/metadata_item_list_atom
+use std::fs;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::genres::genre_name;
+use super::{join_genres, parse_year, tags_from_pairs, u32_be, TagReader, Tags};
+
+// moov contains the sample tables which grow with the length of the song and
+// possibly a cover, but never that much.
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+
+pub struct Mp4Reader;
+
+// Split a buffer into its child atoms, as (type, payload) pairs
+pub(super) fn atoms(data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+  let mut result = Vec::new();
+  let mut offset = 0;
+  while offset + 8 <= data.len() {
+    let mut size = u32_be(data, offset)? as usize;
+    let kind = [
+      data[offset + 4],
+      data[offset + 5],
+      data[offset + 6],
+      data[offset + 7],
+    ];
+    let mut header = 8;
+    if size == 1 {
+      let large = data
+        .get(offset + 8..offset + 16)
+        .ok_or_else(|| anyhow::anyhow!("truncated atom"))?;
+      size = usize::try_from(u64::from_be_bytes(large.try_into()?)).unwrap_or(usize::MAX);
+      header = 16;
+    } else if size == 0 {
+      size = data.len() - offset;
+    }
+    // The size comes from the file and may be anything
+    let end = offset
+      .checked_add(size)
+      .filter(|end| size >= header && *end <= data.len());
+    let Some(end) = end else {
+      anyhow::bail!("invalid atom size {}", size);
+    };
+    result.push((kind, &data[offset + header..end]));
+    offset = end;
+  }
+  Ok(result)
+}
+
+pub(super) fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Result<Option<&'a [u8]>> {
+  Ok(
+    atoms(data)?
+      .into_iter()
+      .find(|(k, _)| k == kind)
+      .map(|(_, payload)| payload),
+  )
+}
+
+// Find the moov atom among the top level atoms of the file and load it
+pub(super) fn read_moov(reader: &mut (impl Read + Seek)) -> Result<Vec<u8>> {
+  let mut position = reader.stream_position()?;
+  let len = reader.seek(SeekFrom::End(0))?;
+  reader.seek(SeekFrom::Start(position))?;
+  loop {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    let mut size = u32_be(&header, 0)? as u64;
+    let mut header_size = 8;
+    if size == 1 {
+      let mut large = [0u8; 8];
+      reader.read_exact(&mut large)?;
+      size = u64::from_be_bytes(large);
+      header_size = 16;
+    } else if size == 0 {
+      anyhow::bail!("no moov atom");
+    }
+    // The size comes from the file and may be anything
+    let end = position
+      .checked_add(size)
+      .filter(|end| size >= header_size && *end <= len);
+    let Some(payload_size) = end.map(|_| size - header_size) else {
+      anyhow::bail!("invalid atom size {}", size);
+    };
+    if &header[4..8] == b"moov" {
+      if payload_size > MAX_MOOV_SIZE {
+        anyhow::bail!("moov atom too large ({} bytes)", payload_size);
+      }
+      let mut moov = vec![0u8; payload_size as usize];
+      reader.read_exact(&mut moov)?;
+      return Ok(moov);
+    }
+    let next = reader.seek(SeekFrom::Current(i64::try_from(payload_size)?))?;
+    if next <= position {
+      anyhow::bail!("atom at {} does not move forward", position);
+    }
+    position = next;
+  }
+}
+
+// The list of metadata items of moov, if any
+pub(super) fn ilst(moov: &[u8]) -> Result<Option<&[u8]>> {
+  let Some(meta) = child(moov, b"udta")?
+    .map(|udta| child(udta, b"meta"))
+    .transpose()?
+    .flatten()
+  else {
+    return Ok(None);
+  };
+  // meta is a full atom with a version and flags before its children, except
+  // in some QuickTime files.
+  let meta = if meta.get(4..8) == Some(b"hdlr") { meta } else { meta.get(4..).unwrap_or(&[]) };
+  child(meta, b"ilst")
+}
+
+// The value of an ilst item is in its data atom, after the type and locale
+fn item_data(item: &[u8]) -> Result<Option<&[u8]>> {
+  Ok(child(item, b"data")?.and_then(|data| data.get(8..)))
+}
+
+fn item_string(item: &[u8]) -> Result<Option<String>> {
+  Ok(
+    item_data(item)?
+      .map(|data| String::from_utf8_lossy(data).trim().to_string())
+      .filter(|s| !s.is_empty()),
+  )
+}
+
+// A big endian 16 bits integer at the given offset of an item's value
+fn item_u16(item: &[u8], offset: usize) -> Result<Option<u32>> {
+  Ok(
+    item_data(item)?
+      .and_then(|data| data.get(offset..offset + 2))
+      .map(|n| u16::from_be_bytes([n[0], n[1]]) as u32)
+      .filter(|n| *n > 0),
+  )
+}
+
+// trkn and disk items are binary: 2 reserved bytes, the number and the total
+fn item_index(item: &[u8]) -> Result<(Option<u32>, Option<u32>)> {
+  Ok((item_u16(item, 2)?, item_u16(item, 4)?))
+}
+
+// Freeform items ("----") are identified by a name, in the namespace of the
+// application which created them. Returns the name and the value.
+fn freeform_item(item: &[u8]) -> Result<Option<(String, String)>> {
+  let Some(name) = child(item, b"name")?.and_then(|name| name.get(4..)) else {
+    return Ok(None);
+  };
+  let name = String::from_utf8_lossy(name).to_string();
+  Ok(item_string(item)?.map(|value| (name, value)))
+}
+
+impl TagReader for Mp4Reader {
+  fn name(&self) -> &'static str {
+    "mp4"
+  }
+
+  fn accepts(&self, header: &[u8], _path: &Path) -> bool {
+    header.get(4..8) == Some(b"ftyp")
+  }
+
+  fn read(&self, path: &Path) -> Result<Tags> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let moov = read_moov(&mut reader)?;
+    let Some(ilst) = ilst(&moov)? else {
+      return Ok(Tags::default());
+    };
+    let mut tags = Tags::default();
+    let mut freeform = Vec::new();
+    for (kind, item) in atoms(ilst)? {
+      match &kind {
+        b"\xa9nam" => tags.title = item_string(item)?,
+        b"\xa9ART" => tags.artists.extend(item_string(item)?),
+        b"\xa9alb" => tags.album = item_string(item)?,
+        b"\xa9day" => tags.year = item_string(item)?.and_then(|s| parse_year(&s)),
+        b"trkn" => (tags.track, tags.total_tracks) = item_index(item)?,
+        b"disk" => (tags.disc, tags.total_discs) = item_index(item)?,
+        b"\xa9gen" => tags.genre = item_string(item)?.and_then(|s| join_genres([s.as_str()])),
+        // The index of an ID3v1 genre, plus one
+        b"gnre" if tags.genre.is_none() => {
+          tags.genre = item_u16(item, 0)?
+            .and_then(|index| genre_name(index as usize - 1))
+            .map(|s| s.to_string())
+        }
+        b"aART" => tags.album_artists.extend(item_string(item)?),
+        b"\xa9wrt" => tags.composer = item_string(item)?,
+        b"\xa9cmt" => tags.comment = item_string(item)?,
+        b"tmpo" => tags.bpm = item_u16(item, 0)?,
+        b"soar" => tags.artist_sorts.extend(item_string(item)?),
+        b"soaa" => tags.album_artist_sorts.extend(item_string(item)?),
+        b"soal" => tags.album_sort = item_string(item)?,
+        b"----" => freeform.extend(freeform_item(item)?),
+        _ => (),
+      }
+    }
+    // The fields without a dedicated item are stored as freeform items, with
+    // the same names as Vorbis comments
+    let extra = tags_from_pairs(freeform.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+    tags.original_date = extra.original_date;
+    tags.label = extra.label;
+    tags.isrc = extra.isrc;
+    tags.musicbrainz_album_id = extra.musicbrainz_album_id;
+    Ok(tags)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use super::*;
+
+  fn atom(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut atom = (payload.len() as u32 + 8).to_be_bytes().to_vec();
+    atom.extend_from_slice(kind);
+    atom.extend_from_slice(payload);
+    atom
+  }
+
+  #[test]
+  fn moov_after_other_atoms() {
+    let mut file = atom(b"ftyp", b"M4A ");
+    // A 64 bits size
+    file.extend_from_slice(&1u32.to_be_bytes());
+    file.extend_from_slice(b"free");
+    file.extend_from_slice(&20u64.to_be_bytes());
+    file.extend_from_slice(b"1234");
+    file.extend(atom(b"moov", &atom(b"udta", b"")));
+    assert_eq!(read_moov(&mut Cursor::new(file)).unwrap(), atom(b"udta", b""));
+  }
+
+  #[test]
+  fn no_moov() {
+    let file = [atom(b"ftyp", b"M4A "), atom(b"mdat", b"audio")].concat();
+    assert!(read_moov(&mut Cursor::new(file)).is_err());
+  }
+
+  #[test]
+  fn atom_sizes_out_of_the_file() {
+    for size in [u64::MAX, u64::MAX - 15, i64::MAX as u64 + 1, 1000, 15] {
+      let mut file = atom(b"ftyp", b"M4A ");
+      file.extend_from_slice(&1u32.to_be_bytes());
+      file.extend_from_slice(b"free");
+      file.extend_from_slice(&size.to_be_bytes());
+      file.extend(atom(b"moov", b""));
+      assert!(read_moov(&mut Cursor::new(file)).is_err(), "size {}", size);
+    }
+  }
+
+  #[test]
+  fn invalid_child_atoms() {
+    assert!(atoms(&[0, 0, 0, 4, b'f', b'r', b'e', b'e']).is_err());
+    assert!(atoms(&[0, 0, 0, 9, b'f', b'r', b'e', b'e']).is_err());
+    let mut large = vec![0, 0, 0, 1, b'f', b'r', b'e', b'e'];
+    large.extend_from_slice(&u64::MAX.to_be_bytes());
+    assert!(atoms(&large).is_err());
+  }
+}
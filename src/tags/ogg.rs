@@ -0,0 +1,90 @@
+// Ogg Vorbis and Opus files. Both store Vorbis comments in the second packet of
+// the logical stream.
+// https://www.xiph.org/ogg/doc/framing.html
+// https://www.rfc-editor.org/rfc/rfc7845#section-5.2
+use std::fs;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::{tags_from_pairs, u32_le, vorbis, TagReader, Tags};
+
+// Comment packets are usually small but may contain pictures, so we allow a
+// generous size before deciding the file is bogus.
+const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+pub struct OggReader;
+
+// Read the first `count` packets of the first logical stream of the file
+pub(super) fn read_packets(reader: &mut impl Read, count: usize) -> Result<Vec<Vec<u8>>> {
+  let mut packets: Vec<Vec<u8>> = Vec::new();
+  let mut current: Vec<u8> = Vec::new();
+  let mut serial = None;
+  while packets.len() < count {
+    let mut header = [0u8; 27];
+    reader.read_exact(&mut header)?;
+    if &header[0..4] != b"OggS" {
+      anyhow::bail!("invalid ogg page");
+    }
+    let page_serial = u32_le(&header, 14)?;
+    let mut lacing = vec![0u8; header[26] as usize];
+    reader.read_exact(&mut lacing)?;
+    let mut data = vec![0u8; lacing.iter().map(|&l| l as usize).sum()];
+    reader.read_exact(&mut data)?;
+    // Skip pages from other multiplexed streams
+    if *serial.get_or_insert(page_serial) != page_serial {
+      continue;
+    }
+    let mut offset = 0;
+    for &l in lacing.iter() {
+      current.extend_from_slice(&data[offset..offset + l as usize]);
+      offset += l as usize;
+      // A lacing value below 255 terminates the packet
+      if l < 255 {
+        packets.push(std::mem::take(&mut current));
+        if packets.len() == count {
+          break;
+        }
+      }
+    }
+    if current.len() > MAX_PACKET_SIZE {
+      anyhow::bail!("ogg packet too large");
+    }
+  }
+  Ok(packets)
+}
+
+// The Vorbis comments of the stream, from its second packet
+pub(super) fn read_comments(reader: &mut impl Read) -> Result<Vec<(String, String)>> {
+  let packets = read_packets(reader, 2)?;
+  let (identification, comments) = (&packets[0], &packets[1]);
+  let comments = if identification.starts_with(b"\x01vorbis") {
+    comments
+      .strip_prefix(b"\x03vorbis")
+      .ok_or_else(|| anyhow::anyhow!("missing vorbis comment header"))?
+  } else if identification.starts_with(b"OpusHead") {
+    comments
+      .strip_prefix(b"OpusTags")
+      .ok_or_else(|| anyhow::anyhow!("missing opus tags header"))?
+  } else {
+    anyhow::bail!("unsupported ogg codec");
+  };
+  vorbis::parse_comments(comments)
+}
+
+impl TagReader for OggReader {
+  fn name(&self) -> &'static str {
+    "ogg"
+  }
+
+  fn accepts(&self, header: &[u8], _path: &Path) -> bool {
+    header.starts_with(b"OggS")
+  }
+
+  fn read(&self, path: &Path) -> Result<Tags> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let comments = read_comments(&mut reader)?;
+    Ok(tags_from_pairs(comments.iter().map(|(k, v)| (k.as_str(), v.as_str()))))
+  }
+}
@@ -0,0 +1,405 @@
+// Locates the audio data of a file, as opposed to its tags, so that songs can be
+// identified by their audio content only and keep the same id when their tags
+// are edited.
+use std::fs;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::{u32_be, u32_le};
+
+const ID3V1_SIZE: u64 = 128;
+const APE_FOOTER_SIZE: u64 = 32;
+// Flag of an APE footer telling the tag also has a header
+const APE_HAS_HEADER: u32 = 1 << 31;
+
+// The byte ranges of the file containing audio data. Unknown formats are
+// considered to be audio entirely, minus the tags found at the end of the file.
+// If no audio is found, the whole file is returned so that two different files
+// never end up with the same (empty) content.
+pub fn audio_ranges(path: &Path) -> Result<Vec<Range<u64>>> {
+  let mut reader = BufReader::new(fs::File::open(path)?);
+  let len = reader.get_ref().metadata()?.len();
+  let ranges = find_audio_ranges(&mut reader, len)?;
+  if ranges.iter().all(|r| r.is_empty()) {
+    return Ok(single(0..len));
+  }
+  Ok(ranges)
+}
+
+fn find_audio_ranges(reader: &mut (impl Read + Seek), len: u64) -> Result<Vec<Range<u64>>> {
+  let mut header = Vec::with_capacity(12);
+  reader.by_ref().take(12).read_to_end(&mut header)?;
+  reader.seek(SeekFrom::Start(0))?;
+  if header.starts_with(b"fLaC") {
+    let start = flac_audio_start(reader)?;
+    Ok(single(start..trailing_tags_start(reader, start, len)?))
+  } else if header.starts_with(b"OggS") {
+    ogg_audio_ranges(reader, len)
+  } else if header.get(4..8) == Some(b"ftyp") {
+    mp4_audio_ranges(reader, len)
+  } else {
+    let start = id3v2_end(reader, len)?;
+    Ok(single(start..trailing_tags_start(reader, start, len)?))
+  }
+}
+
+fn single(range: Range<u64>) -> Vec<Range<u64>> {
+  std::iter::once(range).collect()
+}
+
+// Skip the ID3v2 tags at the start of the file. There can be several.
+pub(super) fn id3v2_end(reader: &mut (impl Read + Seek), len: u64) -> Result<u64> {
+  let mut position = 0;
+  loop {
+    let mut header = [0u8; 10];
+    reader.seek(SeekFrom::Start(position))?;
+    if position + 10 > len || reader.read_exact(&mut header).is_err() || &header[0..3] != b"ID3" {
+      return Ok(position);
+    }
+    // The size is a 28 bits "synchsafe" integer and does not include the header
+    // nor the optional footer.
+    let size = header[6..10]
+      .iter()
+      .fold(0u64, |size, b| (size << 7) | (*b as u64 & 0x7f));
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    position = std::cmp::min(position + 10 + size + footer, len);
+  }
+}
+
+// Find where the tags appended to the file (ID3v1 and APE, in any order) start
+pub(super) fn trailing_tags_start(
+  reader: &mut (impl Read + Seek),
+  start: u64,
+  len: u64,
+) -> Result<u64> {
+  let mut end = len;
+  loop {
+    if end >= start + ID3V1_SIZE {
+      let mut marker = [0u8; 3];
+      reader.seek(SeekFrom::Start(end - ID3V1_SIZE))?;
+      reader.read_exact(&mut marker)?;
+      if &marker == b"TAG" {
+        end -= ID3V1_SIZE;
+        continue;
+      }
+    }
+    if end >= start + APE_FOOTER_SIZE {
+      let mut footer = [0u8; APE_FOOTER_SIZE as usize];
+      reader.seek(SeekFrom::Start(end - APE_FOOTER_SIZE))?;
+      reader.read_exact(&mut footer)?;
+      if &footer[0..8] == b"APETAGEX" {
+        // The size includes the footer but not the optional header, a smaller
+        // one is not a valid tag
+        let size = u32_le(&footer, 12)? as u64;
+        let header = if u32_le(&footer, 20)? & APE_HAS_HEADER != 0 { APE_FOOTER_SIZE } else { 0 };
+        let tag_start = end.saturating_sub(size + header).max(start);
+        if size >= APE_FOOTER_SIZE && tag_start < end {
+          end = tag_start;
+          continue;
+        }
+      }
+    }
+    return Ok(end);
+  }
+}
+
+// Audio frames start after the last metadata block
+pub(super) fn flac_audio_start(reader: &mut (impl Read + Seek)) -> Result<u64> {
+  let mut position = 4;
+  loop {
+    let mut header = [0u8; 4];
+    reader.seek(SeekFrom::Start(position))?;
+    reader.read_exact(&mut header)?;
+    position += 4 + u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+    if header[0] & 0x80 != 0 {
+      return Ok(position);
+    }
+  }
+}
+
+// The number of header packets (identification, comments...) of an Ogg stream,
+// given its first packet. None for codecs we do not know.
+fn ogg_header_packets(first_packet: &[u8]) -> Option<usize> {
+  if first_packet.starts_with(b"\x01vorbis") {
+    Some(3)
+  } else if first_packet.starts_with(b"OpusHead") {
+    Some(2)
+  } else if first_packet.starts_with(b"\x7fFLAC") {
+    // The mapping header tells how many metadata packets follow
+    first_packet
+      .get(7..9)
+      .map(|n| 1 + u16::from_be_bytes([n[0], n[1]]) as usize)
+  } else {
+    None
+  }
+}
+
+// Tags are in the header packets of the stream, and rewriting them can change
+// the pagination and thus the page headers of the whole file. So we only keep
+// the content of the pages following the header packets, without their header.
+// Audio packets always start on a fresh page.
+fn ogg_audio_ranges(reader: &mut (impl Read + Seek), len: u64) -> Result<Vec<Range<u64>>> {
+  let mut ranges = Vec::new();
+  let mut position = 0;
+  let mut serial = None;
+  let mut first_packet: Vec<u8> = Vec::new();
+  let mut header_packets = None;
+  let mut packets = 0;
+  while position + 27 <= len {
+    let mut header = [0u8; 27];
+    reader.seek(SeekFrom::Start(position))?;
+    reader.read_exact(&mut header)?;
+    if &header[0..4] != b"OggS" {
+      anyhow::bail!("invalid ogg page at {}", position);
+    }
+    let page_serial = u32_le(&header, 14)?;
+    let mut lacing = vec![0u8; header[26] as usize];
+    reader.read_exact(&mut lacing)?;
+    let data_start = position + 27 + lacing.len() as u64;
+    let data_length = lacing.iter().map(|&l| l as u64).sum::<u64>();
+    position = data_start + data_length;
+    if *serial.get_or_insert(page_serial) != page_serial {
+      continue;
+    }
+    match header_packets {
+      Some(count) if packets >= count => ranges.push(data_start..std::cmp::min(position, len)),
+      _ => {
+        if packets == 0 {
+          // We need the beginning of the first packet to know the codec
+          let mut data = vec![0u8; data_length as usize];
+          reader.read_exact(&mut data)?;
+          first_packet.extend_from_slice(&data);
+        }
+        packets += lacing.iter().filter(|&&l| l < 255).count();
+        if header_packets.is_none() && packets > 0 {
+          match ogg_header_packets(&first_packet) {
+            Some(count) => header_packets = Some(count),
+            None => return Ok(single(0..len)),
+          }
+        }
+      }
+    }
+  }
+  Ok(ranges)
+}
+
+// The audio is in the mdat atoms, the tags in the moov one
+fn mp4_audio_ranges(reader: &mut (impl Read + Seek), len: u64) -> Result<Vec<Range<u64>>> {
+  let mut ranges = Vec::new();
+  let mut position = 0;
+  while len.saturating_sub(position) >= 8 {
+    let mut header = [0u8; 16];
+    reader.seek(SeekFrom::Start(position))?;
+    reader.read_exact(&mut header[0..8])?;
+    let mut size = u32_be(&header, 0)? as u64;
+    let mut header_size = 8;
+    if size == 1 {
+      reader.read_exact(&mut header[8..16])?;
+      size = u64::from_be_bytes(header[8..16].try_into()?);
+      header_size = 16;
+    } else if size == 0 {
+      size = len - position;
+    }
+    // The size comes from the file and may be anything
+    let end = position.checked_add(size).filter(|_| size >= header_size);
+    let Some(end) = end else {
+      anyhow::bail!("invalid atom size {}", size);
+    };
+    if &header[4..8] == b"mdat" {
+      ranges.push(position + header_size..std::cmp::min(end, len));
+    }
+    position = end;
+  }
+  Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use super::*;
+
+  fn ranges(file: &[u8]) -> Result<Vec<Range<u64>>> {
+    find_audio_ranges(&mut Cursor::new(file), file.len() as u64)
+  }
+
+  // The bytes of the file which are hashed into its id
+  fn audio(file: &[u8]) -> Vec<u8> {
+    ranges(file)
+      .unwrap()
+      .iter()
+      .flat_map(|range| file[range.start as usize..range.end as usize].to_vec())
+      .collect()
+  }
+
+  fn id3v2(size: usize) -> Vec<u8> {
+    let mut tag = b"ID3\x04\x00\x00".to_vec();
+    tag.extend([0, 0, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f]);
+    tag.resize(10 + size, 0);
+    tag
+  }
+
+  fn id3v1() -> Vec<u8> {
+    let mut tag = b"TAG".to_vec();
+    tag.resize(ID3V1_SIZE as usize, b' ');
+    tag
+  }
+
+  fn ape_footer(size: u32, flags: u32) -> Vec<u8> {
+    let mut footer = b"APETAGEX".to_vec();
+    footer.extend(2000u32.to_le_bytes());
+    footer.extend(size.to_le_bytes());
+    footer.extend(0u32.to_le_bytes());
+    footer.extend(flags.to_le_bytes());
+    footer.resize(APE_FOOTER_SIZE as usize, 0);
+    footer
+  }
+
+  fn ape(items: &[u8]) -> Vec<u8> {
+    let size = items.len() as u32 + APE_FOOTER_SIZE as u32;
+    let mut tag = ape_footer(size, APE_HAS_HEADER | 1 << 29);
+    tag.extend(items);
+    tag.extend(ape_footer(size, APE_HAS_HEADER));
+    tag
+  }
+
+  // A STREAMINFO block, then a padding block, the last one
+  fn flac(padding: usize, audio: &[u8]) -> Vec<u8> {
+    let mut file = b"fLaC".to_vec();
+    file.extend([0, 0, 0, 34]);
+    file.extend([0; 34]);
+    file.extend([0x81, 0, (padding >> 8) as u8, padding as u8]);
+    file.extend(vec![0; padding]);
+    file.extend(audio);
+    file
+  }
+
+  fn ogg_page(packets: &[&[u8]]) -> Vec<u8> {
+    let mut lacing = Vec::new();
+    for packet in packets {
+      lacing.extend(vec![255; packet.len() / 255]);
+      lacing.push((packet.len() % 255) as u8);
+    }
+    // Version, header type and granule position, then the serial number,
+    // sequence number and checksum
+    let mut page = b"OggS".to_vec();
+    page.extend([0; 10]);
+    page.extend(1234u32.to_le_bytes());
+    page.extend([0; 8]);
+    page.push(lacing.len() as u8);
+    page.extend(lacing);
+    for packet in packets {
+      page.extend(*packet);
+    }
+    page
+  }
+
+  fn vorbis(comments: &[u8]) -> Vec<u8> {
+    [
+      ogg_page(&[b"\x01vorbis identification"]),
+      ogg_page(&[comments, b"\x05vorbis setup"]),
+      ogg_page(&[b"first audio packet"]),
+      ogg_page(&[&[7; 300]]),
+    ]
+    .concat()
+  }
+
+  fn atom(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut atom = (payload.len() as u32 + 8).to_be_bytes().to_vec();
+    atom.extend(kind);
+    atom.extend(payload);
+    atom
+  }
+
+  #[test]
+  fn mpeg_tags() {
+    let file = [id3v2(20), b"audio".to_vec(), ape(b"items"), id3v1()].concat();
+    assert_eq!(ranges(&file).unwrap(), single(30..35));
+    // Several ID3v2 tags, ID3v1 before APE
+    let file = [id3v2(20), id3v2(300), b"audio".to_vec(), id3v1(), ape(b"")].concat();
+    assert_eq!(audio(&file), b"audio");
+  }
+
+  #[test]
+  fn untagged_file() {
+    assert_eq!(ranges(b"just audio").unwrap(), single(0..10));
+  }
+
+  #[test]
+  fn flac_metadata() {
+    let file = [flac(10, b"frames"), id3v1()].concat();
+    assert_eq!(audio(&file), b"frames");
+    assert_eq!(audio(&flac(1000, b"frames")), b"frames");
+  }
+
+  #[test]
+  fn ape_footer_without_items() {
+    // A footer whose size does not even cover itself is not a tag
+    for size in [0, 31] {
+      let file = [flac(10, b"frames"), ape_footer(size, 0)].concat();
+      assert_eq!(audio(&file), [b"frames".to_vec(), ape_footer(size, 0)].concat());
+    }
+    let file = [flac(10, b"frames"), ape_footer(32, 0)].concat();
+    assert_eq!(audio(&file), b"frames");
+  }
+
+  #[test]
+  fn ape_larger_than_the_file() {
+    let file = [flac(10, b"frames"), ape_footer(u32::MAX, APE_HAS_HEADER)].concat();
+    let start = file.len() as u64 - 6 - APE_FOOTER_SIZE;
+    assert_eq!(ranges(&file).unwrap(), single(start..start));
+  }
+
+  #[test]
+  fn ogg_headers() {
+    let file = vorbis(b"\x03vorbis comments");
+    assert_eq!(audio(&file), [b"first audio packet".to_vec(), vec![7; 300]].concat());
+    // Editing the comments does not change the audio
+    let comments = [b"\x03vorbis".to_vec(), vec![b'x'; 5000]].concat();
+    assert_eq!(audio(&vorbis(&comments)), audio(&file));
+  }
+
+  #[test]
+  fn ogg_unknown_codec() {
+    let file = [ogg_page(&[b"unknown"]), ogg_page(&[b"audio"])].concat();
+    assert_eq!(ranges(&file).unwrap(), single(0..file.len() as u64));
+  }
+
+  #[test]
+  fn mp4_atoms() {
+    let file = [
+      atom(b"ftyp", b"M4A "),
+      atom(b"mdat", b"audio"),
+      atom(b"moov", b"tags"),
+      atom(b"mdat", b"more"),
+    ]
+    .concat();
+    assert_eq!(audio(&file), b"audiomore");
+    // A 64 bits size
+    let mut file = atom(b"ftyp", b"M4A ");
+    file.extend(1u32.to_be_bytes());
+    file.extend(b"mdat");
+    file.extend(21u64.to_be_bytes());
+    file.extend(b"audio");
+    assert_eq!(audio(&file), b"audio");
+  }
+
+  #[test]
+  fn mp4_invalid_atom_sizes() {
+    let file = |size: u64| {
+      let mut file = atom(b"ftyp", b"M4A ");
+      file.extend(1u32.to_be_bytes());
+      file.extend(b"free");
+      file.extend(size.to_be_bytes());
+      file.extend(atom(b"mdat", b"audio"));
+      file
+    };
+    // An atom ending past the end of the file is the last one
+    assert_eq!(ranges(&file(u64::MAX - 15)).unwrap(), []);
+    assert!(ranges(&file(u64::MAX)).is_err());
+    assert!(ranges(&file(4)).is_err());
+  }
+}
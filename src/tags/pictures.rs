@@ -0,0 +1,210 @@
+// Pictures embedded in the tags (cover art). Files often contain several of
+// them (front and back cover, artist, booklet...), the front cover is preferred.
+use std::fs;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use ::id3::frame::PictureType;
+use anyhow::Result;
+use base64ct::{Base64, Encoding};
+
+use super::ape::{raw_items, read_items, ITEM_NOT_TEXT};
+use super::flac::read_block_header;
+use super::mp4::{atoms, child, ilst, read_moov};
+use super::ogg::read_comments;
+use super::u32_be;
+
+const FLAC_PICTURE: u8 = 6;
+// The picture type of front covers in ID3 APIC frames and FLAC picture blocks
+const FRONT_COVER: u32 = 3;
+
+#[derive(Debug)]
+pub struct Picture {
+  pub mime_type: String,
+  pub data: Vec<u8>,
+}
+
+// The MIME type of an image, from its signature
+pub fn sniff_mime_type(data: &[u8]) -> Option<&'static str> {
+  if data.starts_with(&[0xff, 0xd8, 0xff]) {
+    Some("image/jpeg")
+  } else if data.starts_with(b"\x89PNG") {
+    Some("image/png")
+  } else if data.starts_with(b"GIF8") {
+    Some("image/gif")
+  } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+    Some("image/webp")
+  } else if data.starts_with(b"BM") {
+    Some("image/bmp")
+  } else {
+    None
+  }
+}
+
+impl Picture {
+  // The MIME type declared in tags is often wrong ("image/jpg", "JPG" in
+  // ID3v2.2...) so the signature of the image wins. Anything which is not an
+  // image, like the links to external pictures ID3 allows, is ignored.
+  fn new(declared_mime_type: &str, data: Vec<u8>) -> Option<Picture> {
+    let mime_type = match sniff_mime_type(&data) {
+      Some(mime_type) => mime_type.to_string(),
+      None if declared_mime_type.starts_with("image/") => declared_mime_type.to_string(),
+      None => return None,
+    };
+    Some(Picture { mime_type, data })
+  }
+}
+
+// The front cover if there is one, the first picture otherwise
+fn choose(pictures: impl IntoIterator<Item = (bool, Picture)>) -> Option<Picture> {
+  let mut first = None;
+  for (front, picture) in pictures {
+    if front {
+      return Some(picture);
+    }
+    first = first.or(Some(picture));
+  }
+  first
+}
+
+pub fn read_picture(path: &Path) -> Result<Option<Picture>> {
+  let mut reader = BufReader::new(fs::File::open(path)?);
+  let mut header = Vec::with_capacity(12);
+  reader.by_ref().take(12).read_to_end(&mut header)?;
+  reader.seek(SeekFrom::Start(0))?;
+  if header.starts_with(b"fLaC") {
+    flac_picture(&mut reader)
+  } else if header.starts_with(b"OggS") {
+    ogg_picture(&mut reader)
+  } else if header.get(4..8) == Some(b"ftyp") {
+    mp4_picture(&mut reader)
+  } else {
+    let picture = if header.starts_with(b"ID3") { id3_picture(path)? } else { None };
+    // MPEG files sometimes have an APE tag, other formats only have that
+    match picture {
+      Some(picture) => Ok(Some(picture)),
+      None => ape_picture(path),
+    }
+  }
+}
+
+fn id3_picture(path: &Path) -> Result<Option<Picture>> {
+  let tag = ::id3::Tag::read_from_path(path)?;
+  Ok(choose(tag.pictures().filter_map(|picture| {
+    let front = picture.picture_type == PictureType::CoverFront;
+    Some((front, Picture::new(&picture.mime_type, picture.data.clone())?))
+  })))
+}
+
+// A FLAC picture block, also found base64 encoded in the METADATA_BLOCK_PICTURE
+// comment of Ogg files. Returns the picture type and the picture.
+// https://xiph.org/flac/format.html#metadata_block_picture
+fn parse_picture_block(data: &[u8]) -> Result<Option<(u32, Picture)>> {
+  let truncated = || anyhow::anyhow!("truncated picture block");
+  let picture_type = u32_be(data, 0)?;
+  let mime_length = u32_be(data, 4)? as usize;
+  let mime_type = data.get(8..8 + mime_length).ok_or_else(truncated)?;
+  let description_length = u32_be(data, 8 + mime_length)? as usize;
+  // Width, height, color depth and number of colors follow the description
+  let offset = 8 + mime_length + 4 + description_length + 16;
+  let length = u32_be(data, offset)? as usize;
+  let image = data
+    .get(offset + 4..offset + 4 + length)
+    .ok_or_else(truncated)?;
+  let picture = Picture::new(&String::from_utf8_lossy(mime_type), image.to_vec());
+  Ok(picture.map(|picture| (picture_type, picture)))
+}
+
+fn flac_picture(reader: &mut (impl Read + Seek)) -> Result<Option<Picture>> {
+  reader.seek(SeekFrom::Start(4))?;
+  let mut pictures = Vec::new();
+  loop {
+    let (last, block_type, length) = read_block_header(reader)?;
+    match block_type {
+      FLAC_PICTURE => {
+        let mut data = vec![0u8; length as usize];
+        reader.read_exact(&mut data)?;
+        pictures.extend(parse_picture_block(&data)?);
+      }
+      127 => anyhow::bail!("invalid metadata block"),
+      _ => {
+        reader.seek(SeekFrom::Current(length as i64))?;
+      }
+    }
+    if last {
+      break;
+    }
+  }
+  Ok(choose(
+    pictures
+      .into_iter()
+      .map(|(kind, picture)| (kind == FRONT_COVER, picture)),
+  ))
+}
+
+fn ogg_picture(reader: &mut impl Read) -> Result<Option<Picture>> {
+  let comments = read_comments(reader)?;
+  let mut pictures = Vec::new();
+  for (key, value) in comments.iter() {
+    // COVERART is the unofficial way used before METADATA_BLOCK_PICTURE, with
+    // the image itself base64 encoded
+    let key = key.to_ascii_uppercase();
+    if key != "METADATA_BLOCK_PICTURE" && key != "COVERART" {
+      continue;
+    }
+    let data = match Base64::decode_vec(value.trim()) {
+      Ok(data) => data,
+      Err(e) => {
+        tracing::debug!("invalid {} comment ({})", key, e);
+        continue;
+      }
+    };
+    if key == "COVERART" {
+      pictures.extend(Picture::new("", data).map(|picture| (false, picture)));
+    } else {
+      pictures
+        .extend(parse_picture_block(&data)?.map(|(kind, picture)| (kind == FRONT_COVER, picture)));
+    }
+  }
+  Ok(choose(pictures))
+}
+
+// The covr item has a data atom per picture, without picture types
+fn mp4_picture(reader: &mut (impl Read + Seek)) -> Result<Option<Picture>> {
+  let moov = read_moov(reader)?;
+  let Some(covr) = ilst(&moov)?
+    .map(|ilst| child(ilst, b"covr"))
+    .transpose()?
+    .flatten()
+  else {
+    return Ok(None);
+  };
+  Ok(choose(
+    atoms(covr)?
+      .into_iter()
+      .filter(|(kind, _)| kind == b"data")
+      // The image follows the type and locale of the data atom
+      .filter_map(|(_, data)| Picture::new("", data.get(8..)?.to_vec()))
+      .map(|picture| (false, picture)),
+  ))
+}
+
+// Binary items named "Cover Art (...)" hold a file name, \0 and the image
+fn ape_picture(path: &Path) -> Result<Option<Picture>> {
+  let mut file = fs::File::open(path)?;
+  let Some((count, data)) = read_items(&mut file)? else {
+    return Ok(None);
+  };
+  Ok(choose(
+    raw_items(count, &data)?
+      .into_iter()
+      .filter_map(|(key, flags, value)| {
+        let key = key.to_ascii_lowercase();
+        if flags & ITEM_NOT_TEXT == 0 || !key.starts_with("cover art") {
+          return None;
+        }
+        let image = &value[value.iter().position(|&b| b == 0)? + 1..];
+        Some((key == "cover art (front)", Picture::new("", image.to_vec())?))
+      }),
+  ))
+}
@@ -0,0 +1,450 @@
+// The properties of the audio stream of a file (duration, bitrate...), read
+// from the stream headers without decoding the audio.
+use std::fs;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::mp4::{atoms, child, read_moov};
+use super::ogg::read_packets;
+use super::payload::{flac_audio_start, id3v2_end, trailing_tags_start};
+use super::{u32_be, u32_le};
+
+#[derive(Debug, Default)]
+pub struct AudioProperties {
+  pub duration_ms: Option<u64>,
+  // Average bitrate in kbit/s
+  pub bitrate: Option<u32>,
+  pub sample_rate: Option<u32>,
+  pub channels: Option<u32>,
+  pub codec: Option<String>,
+}
+
+// How far we look for the first MPEG frame after the ID3v2 tag
+const MAX_MPEG_SYNC_SEARCH: u64 = 64 * 1024;
+// The end of an Ogg file is read to find the last page
+const OGG_TAIL_SIZE: u64 = 64 * 1024;
+
+pub fn read_properties(path: &Path) -> Result<AudioProperties> {
+  let mut reader = BufReader::new(fs::File::open(path)?);
+  let len = reader.get_ref().metadata()?.len();
+  let mut header = Vec::with_capacity(12);
+  reader.by_ref().take(12).read_to_end(&mut header)?;
+  reader.seek(SeekFrom::Start(0))?;
+  if header.starts_with(b"fLaC") {
+    flac_properties(&mut reader, len)
+  } else if header.starts_with(b"OggS") {
+    ogg_properties(&mut reader, len)
+  } else if header.get(4..8) == Some(b"ftyp") {
+    mp4_properties(&mut reader, len)
+  } else {
+    mpeg_properties(&mut reader, len)
+  }
+}
+
+// Average bitrate in kbit/s of `bytes` of audio lasting `duration_ms`
+fn average_bitrate(bytes: u64, duration_ms: u64) -> Option<u32> {
+  (duration_ms > 0).then(|| (bytes * 8 / duration_ms) as u32)
+}
+
+fn duration_ms(samples: u64, sample_rate: u32) -> Option<u64> {
+  (sample_rate > 0).then(|| samples * 1000 / sample_rate as u64)
+}
+
+// The STREAMINFO block of FLAC: sample rate, channels and total samples
+// https://xiph.org/flac/format.html#metadata_block_streaminfo
+fn parse_streaminfo(data: &[u8]) -> Result<(u32, u32, u64)> {
+  let bytes = data
+    .get(10..18)
+    .ok_or_else(|| anyhow::anyhow!("truncated STREAMINFO"))?;
+  let bits = u64::from_be_bytes(bytes.try_into()?);
+  let sample_rate = (bits >> 44) as u32;
+  let channels = ((bits >> 41) & 0x7) as u32 + 1;
+  let samples = bits & 0xf_ffff_ffff;
+  Ok((sample_rate, channels, samples))
+}
+
+fn flac_properties(reader: &mut (impl Read + Seek), len: u64) -> Result<AudioProperties> {
+  // STREAMINFO is always the first block
+  let mut streaminfo = [0u8; 4 + 4 + 34];
+  reader.read_exact(&mut streaminfo)?;
+  let (sample_rate, channels, samples) = parse_streaminfo(&streaminfo[8..])?;
+  let start = flac_audio_start(reader)?;
+  let end = trailing_tags_start(reader, start, len)?;
+  // The total number of samples is unknown when 0
+  let duration_ms = duration_ms(samples, sample_rate).filter(|d| *d > 0);
+  Ok(AudioProperties {
+    duration_ms,
+    // With corrupt block lengths, the tags may seem to start before the audio
+    bitrate: duration_ms.and_then(|d| average_bitrate(end.checked_sub(start)?, d)),
+    // A sample rate of 0 is invalid for audio streams
+    sample_rate: Some(sample_rate).filter(|r| *r > 0),
+    channels: Some(channels),
+    codec: Some("FLAC".to_string()),
+  })
+}
+
+// The granule position of the last page of the stream, the number of samples
+// of the stream for audio codecs
+fn ogg_last_granule(reader: &mut (impl Read + Seek), len: u64, serial: u32) -> Result<Option<u64>> {
+  let start = len.saturating_sub(OGG_TAIL_SIZE);
+  reader.seek(SeekFrom::Start(start))?;
+  let mut tail = Vec::new();
+  reader.read_to_end(&mut tail)?;
+  let mut granule = None;
+  let mut offset = 0;
+  while let Some(position) = tail[offset..].windows(4).position(|w| w == b"OggS") {
+    let page = &tail[offset + position..];
+    offset += position + 4;
+    if page.len() < 27 || u32_le(page, 14)? != serial {
+      continue;
+    }
+    let value = u64::from_le_bytes(page[6..14].try_into()?);
+    // -1 means no packet ends on the page
+    if value != u64::MAX {
+      granule = Some(value);
+    }
+  }
+  Ok(granule)
+}
+
+fn ogg_properties(reader: &mut (impl Read + Seek), len: u64) -> Result<AudioProperties> {
+  let mut header = [0u8; 27];
+  reader.read_exact(&mut header)?;
+  let serial = u32_le(&header, 14)?;
+  reader.seek(SeekFrom::Start(0))?;
+  let packet = read_packets(reader, 1)?.remove(0);
+  // The rate of the granule positions, the number of samples to skip at the
+  // start, and the properties
+  let (granule_rate, pre_skip, sample_rate, channels, codec) = if packet.starts_with(b"\x01vorbis")
+  {
+    // https://xiph.org/vorbis/doc/Vorbis_I_spec.html#x1-630004.2.2
+    let channels = *packet
+      .get(11)
+      .ok_or_else(|| anyhow::anyhow!("truncated vorbis header"))? as u32;
+    let sample_rate = u32_le(&packet, 12)?;
+    (sample_rate, 0, sample_rate, channels, "Vorbis")
+  } else if packet.starts_with(b"OpusHead") {
+    // Opus always uses 48kHz granule positions, the sample rate in the header
+    // is the one of the original input
+    let channels = *packet
+      .get(9)
+      .ok_or_else(|| anyhow::anyhow!("truncated opus header"))? as u32;
+    let pre_skip = packet
+      .get(10..12)
+      .map_or(0, |b| u16::from_le_bytes([b[0], b[1]]) as u64);
+    let input_rate = u32_le(&packet, 12)?;
+    (48000, pre_skip, if input_rate > 0 { input_rate } else { 48000 }, channels, "Opus")
+  } else if packet.starts_with(b"\x7fFLAC") {
+    // The mapping header is followed by "fLaC" and the STREAMINFO block
+    let streaminfo = packet
+      .get(17..)
+      .ok_or_else(|| anyhow::anyhow!("truncated ogg flac header"))?;
+    let (sample_rate, channels, _) = parse_streaminfo(streaminfo)?;
+    (sample_rate, 0, sample_rate, channels, "FLAC")
+  } else {
+    anyhow::bail!("unknown ogg codec");
+  };
+  let duration_ms = ogg_last_granule(reader, len, serial)?
+    .and_then(|granule| duration_ms(granule.saturating_sub(pre_skip), granule_rate));
+  Ok(AudioProperties {
+    duration_ms,
+    bitrate: duration_ms.and_then(|d| average_bitrate(len, d)),
+    sample_rate: Some(sample_rate),
+    channels: Some(channels),
+    codec: Some(codec.to_string()),
+  })
+}
+
+// The timescale and duration of a movie or media header
+fn mp4_duration(header: &[u8]) -> Result<Option<u64>> {
+  let (timescale, duration) = if header.first() == Some(&1) {
+    let duration = header
+      .get(24..32)
+      .ok_or_else(|| anyhow::anyhow!("truncated mvhd"))?;
+    (u32_be(header, 20)?, u64::from_be_bytes(duration.try_into()?))
+  } else {
+    (u32_be(header, 12)?, u32_be(header, 16)? as u64)
+  };
+  Ok((timescale > 0).then(|| duration * 1000 / timescale as u64))
+}
+
+// The first sample entry of the sound track: its type, channels and sample rate
+fn mp4_sound_entry(moov: &[u8]) -> Result<Option<([u8; 4], u32, u32)>> {
+  for (kind, trak) in atoms(moov)? {
+    if &kind != b"trak" {
+      continue;
+    }
+    let Some(mdia) = child(trak, b"mdia")? else {
+      continue;
+    };
+    // The handler type follows the version, flags and pre-defined fields
+    let is_sound = child(mdia, b"hdlr")?.and_then(|hdlr| hdlr.get(8..12)) == Some(b"soun");
+    if !is_sound {
+      continue;
+    }
+    let stsd = child(mdia, b"minf")?
+      .map(|minf| child(minf, b"stbl"))
+      .transpose()?
+      .flatten()
+      .map(|stbl| child(stbl, b"stsd"))
+      .transpose()?
+      .flatten();
+    // stsd has a version, flags and entry count before its entries
+    let Some((entry_kind, entry)) = stsd
+      .and_then(|stsd| stsd.get(8..))
+      .map(atoms)
+      .transpose()?
+      .and_then(|e| e.into_iter().next())
+    else {
+      continue;
+    };
+    // Reserved and data reference index, then the sound description
+    let channels = entry
+      .get(16..18)
+      .map_or(0, |c| u16::from_be_bytes([c[0], c[1]]) as u32);
+    // 16.16 fixed point
+    let sample_rate = u32_be(entry, 24)? >> 16;
+    return Ok(Some((entry_kind, channels, sample_rate)));
+  }
+  Ok(None)
+}
+
+fn mp4_properties(reader: &mut (impl Read + Seek), len: u64) -> Result<AudioProperties> {
+  let moov = read_moov(reader)?;
+  let duration_ms = child(&moov, b"mvhd")?
+    .map(mp4_duration)
+    .transpose()?
+    .flatten();
+  let mut properties = AudioProperties {
+    duration_ms,
+    bitrate: duration_ms.and_then(|d| average_bitrate(len - moov.len() as u64, d)),
+    ..Default::default()
+  };
+  if let Some((kind, channels, sample_rate)) = mp4_sound_entry(&moov)? {
+    properties.channels = Some(channels).filter(|c| *c > 0);
+    properties.sample_rate = Some(sample_rate).filter(|r| *r > 0);
+    properties.codec = Some(match &kind {
+      b"mp4a" => "AAC".to_string(),
+      b"alac" => "ALAC".to_string(),
+      b"ac-3" => "AC-3".to_string(),
+      b"ec-3" => "E-AC-3".to_string(),
+      b"fLaC" => "FLAC".to_string(),
+      b"Opus" => "Opus".to_string(),
+      _ => String::from_utf8_lossy(&kind).trim().to_string(),
+    });
+  }
+  Ok(properties)
+}
+
+// An MPEG audio frame header
+// http://www.mp3-tech.org/programmer/frame_header.html
+struct MpegFrame {
+  // 1 for MPEG 1, 2 for MPEG 2 and 2.5
+  version: u8,
+  layer: u8,
+  // kbit/s
+  bitrate: u32,
+  sample_rate: u32,
+  channels: u32,
+  length: u64,
+  samples: u64,
+}
+
+const MPEG_BITRATES: [[u32; 15]; 5] = [
+  // MPEG 1, layer 1, 2 and 3
+  [
+    0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+  ],
+  [
+    0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+  ],
+  [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+  ],
+  // MPEG 2 and 2.5, layer 1, then layer 2 and 3
+  [
+    0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+  ],
+  [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+fn parse_mpeg_frame(header: [u8; 4]) -> Option<MpegFrame> {
+  let bits = u32::from_be_bytes(header);
+  if bits >> 21 != 0x7ff {
+    return None;
+  }
+  let (version, base_rate) = match (bits >> 19) & 0x3 {
+    0 => (2, 11025),
+    2 => (2, 22050),
+    3 => (1, 44100),
+    _ => return None,
+  };
+  let layer = match (bits >> 17) & 0x3 {
+    1 => 3,
+    2 => 2,
+    3 => 1,
+    _ => return None,
+  };
+  let bitrate_index = ((bits >> 12) & 0xf) as usize;
+  let sample_rate = match (bits >> 10) & 0x3 {
+    0 => base_rate,
+    1 => base_rate * 48000 / 44100,
+    2 => base_rate * 32000 / 44100,
+    _ => return None,
+  };
+  // Free format (index 0) is not supported
+  let table = match (version, layer) {
+    (1, layer) => layer as usize - 1,
+    (_, 1) => 3,
+    _ => 4,
+  };
+  let bitrate = *MPEG_BITRATES[table]
+    .get(bitrate_index)
+    .filter(|b| **b > 0)?;
+  let padding = ((bits >> 9) & 0x1) as u64;
+  let channels = if (bits >> 6) & 0x3 == 3 { 1 } else { 2 };
+  let samples: u64 = match (version, layer) {
+    (_, 1) => 384,
+    (1, _) | (_, 2) => 1152,
+    _ => 576,
+  };
+  let length = if layer == 1 {
+    (12 * bitrate as u64 * 1000 / sample_rate as u64 + padding) * 4
+  } else {
+    samples / 8 * bitrate as u64 * 1000 / sample_rate as u64 + padding
+  };
+  Some(MpegFrame {
+    version,
+    layer,
+    bitrate,
+    sample_rate,
+    channels,
+    length,
+    samples,
+  })
+}
+
+fn read_frame_header(reader: &mut (impl Read + Seek), position: u64) -> Result<Option<MpegFrame>> {
+  let mut header = [0u8; 4];
+  reader.seek(SeekFrom::Start(position))?;
+  Ok(match reader.read_exact(&mut header) {
+    Ok(()) => parse_mpeg_frame(header),
+    Err(_) => None,
+  })
+}
+
+// Find the first frame of the stream, checking that another frame follows to
+// avoid false synchronizations in junk data
+fn find_first_frame(
+  reader: &mut (impl Read + Seek),
+  start: u64,
+  end: u64,
+) -> Result<Option<(u64, MpegFrame)>> {
+  let limit = std::cmp::min(end, start + MAX_MPEG_SYNC_SEARCH);
+  reader.seek(SeekFrom::Start(start))?;
+  let mut data = vec![0u8; limit.saturating_sub(start) as usize];
+  reader.read_exact(&mut data)?;
+  for offset in 0..data.len().saturating_sub(3) {
+    if data[offset] != 0xff {
+      continue;
+    }
+    let Some(frame) = parse_mpeg_frame([
+      data[offset],
+      data[offset + 1],
+      data[offset + 2],
+      data[offset + 3],
+    ]) else {
+      continue;
+    };
+    let position = start + offset as u64;
+    let next = position + frame.length;
+    if next + 4 > end || read_frame_header(reader, next)?.is_some() {
+      return Ok(Some((position, frame)));
+    }
+  }
+  Ok(None)
+}
+
+// The number of frames and bytes of a VBR stream, and the number of samples
+// added by the encoder at the start and the end, from the Xing (or Info) or the
+// VBRI header in the first frame
+fn vbr_header(frame: &MpegFrame, data: &[u8]) -> Option<(u64, Option<u64>, u64)> {
+  // The Xing header follows the side information
+  let side_information = match (frame.version, frame.channels) {
+    (1, 1) => 17,
+    (1, _) => 32,
+    (_, 1) => 9,
+    _ => 17,
+  };
+  let xing = data.get(4 + side_information..)?;
+  if xing.starts_with(b"Xing") || xing.starts_with(b"Info") {
+    let flags = u32_be(xing, 4).ok()?;
+    let mut offset = 8;
+    let mut frames = None;
+    let mut bytes = None;
+    if flags & 0x1 != 0 {
+      frames = u32_be(xing, offset).ok();
+      offset += 4;
+    }
+    if flags & 0x2 != 0 {
+      bytes = u32_be(xing, offset).ok().map(|b| b as u64);
+      offset += 4;
+    }
+    if flags & 0x4 != 0 {
+      offset += 100;
+    }
+    if flags & 0x8 != 0 {
+      offset += 4;
+    }
+    // The LAME extension tells the encoder delay and padding
+    // http://gabriel.mp3-tech.org/mp3infotag.html
+    let delay = xing
+      .get(offset + 21..offset + 24)
+      .filter(|_| xing.get(offset..offset + 4) == Some(b"LAME"))
+      .map_or(0, |d| {
+        let delay = ((d[0] as u64) << 4) | (d[1] as u64 >> 4);
+        let padding = ((d[1] as u64 & 0xf) << 8) | d[2] as u64;
+        delay + padding
+      });
+    return Some((frames? as u64, bytes, delay));
+  }
+  // The VBRI header is at a fixed position
+  let vbri = data.get(4 + 32..)?;
+  if vbri.starts_with(b"VBRI") {
+    let bytes = u32_be(vbri, 10).ok()? as u64;
+    let frames = u32_be(vbri, 14).ok()? as u64;
+    return Some((frames, Some(bytes), 0));
+  }
+  None
+}
+
+fn mpeg_properties(reader: &mut (impl Read + Seek), len: u64) -> Result<AudioProperties> {
+  let start = id3v2_end(reader, len)?;
+  let end = trailing_tags_start(reader, start, len)?;
+  let Some((position, frame)) = find_first_frame(reader, start, end)? else {
+    anyhow::bail!("no MPEG audio frame found");
+  };
+  let mut data = vec![0u8; std::cmp::min(frame.length, end - position) as usize];
+  reader.seek(SeekFrom::Start(position))?;
+  reader.read_exact(&mut data)?;
+  let audio_bytes = end - position;
+  let (duration_ms, bitrate) = match vbr_header(&frame, &data) {
+    Some((frames, bytes, delay)) => {
+      let samples = (frames * frame.samples).saturating_sub(delay);
+      let duration_ms = duration_ms(samples, frame.sample_rate);
+      let bytes = bytes.unwrap_or(audio_bytes);
+      (duration_ms, duration_ms.and_then(|d| average_bitrate(bytes, d)))
+    }
+    // Constant bitrate
+    None => (Some(audio_bytes * 8 / frame.bitrate as u64), Some(frame.bitrate)),
+  };
+  Ok(AudioProperties {
+    duration_ms,
+    bitrate,
+    sample_rate: Some(frame.sample_rate),
+    channels: Some(frame.channels),
+    codec: Some(format!("MP{}", frame.layer)),
+  })
+}
@@ -0,0 +1,28 @@
+// Vorbis comments, the tag format of FLAC, Ogg Vorbis and Opus files
+// https://www.xiph.org/vorbis/doc/v-comment.html
+use anyhow::Result;
+
+use super::u32_le;
+
+// Parse a comment block (without any codec specific prefix) into key/value
+// pairs. Malformed entries (without '=') are skipped.
+pub fn parse_comments(data: &[u8]) -> Result<Vec<(String, String)>> {
+  let vendor_length = u32_le(data, 0)? as usize;
+  let mut offset = 4 + vendor_length;
+  let count = u32_le(data, offset)?;
+  offset += 4;
+  let mut comments = Vec::new();
+  for _ in 0..count {
+    let length = u32_le(data, offset)? as usize;
+    offset += 4;
+    let comment = data
+      .get(offset..offset + length)
+      .ok_or_else(|| anyhow::anyhow!("truncated vorbis comment"))?;
+    offset += length;
+    let comment = String::from_utf8_lossy(comment);
+    if let Some((key, value)) = comment.split_once('=') {
+      comments.push((key.to_string(), value.to_string()));
+    }
+  }
+  Ok(comments)
+}
@@ -0,0 +1,183 @@
+// Thumbnails of the covers, generated on demand and cached on disk. A cover
+// never changes, its id being the hash of its content, so a cached thumbnail is
+// valid as long as its cover exists. The least recently used thumbnails are
+// removed when the cache grows above its maximum size.
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde::Deserialize;
+
+use crate::covers::Cover;
+
+// Requested sizes are rounded up to one of these to bound the number of
+// thumbnails of a cover
+const SIZES: [u32; 5] = [64, 128, 256, 512, 1024];
+const JPEG_QUALITY: u8 = 85;
+
+// To give unique names to the files being written
+static TEMPORARY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// WebP is lossless, the pure Rust encoder does not do lossy compression, so
+// JPEG is smaller for photos
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+  #[default]
+  Jpeg,
+  Webp,
+}
+
+impl Format {
+  fn extension(&self) -> &'static str {
+    match self {
+      Format::Jpeg => "jpg",
+      Format::Webp => "webp",
+    }
+  }
+
+  pub fn mime_type(&self) -> &'static str {
+    match self {
+      Format::Jpeg => "image/jpeg",
+      Format::Webp => "image/webp",
+    }
+  }
+}
+
+// The size of the thumbnail actually generated for a requested size
+pub fn thumbnail_size(requested: u32) -> u32 {
+  SIZES
+    .iter()
+    .copied()
+    .find(|size| *size >= requested)
+    .unwrap_or(SIZES[SIZES.len() - 1])
+}
+
+// The name of a thumbnail, also used as its entity tag
+pub fn thumbnail_name(cover_id: &str, size: u32, format: Format) -> String {
+  format!("{}-{}.{}", cover_id, size, format.extension())
+}
+
+// Resize the image so that it fits in a `size` pixels square, never enlarging it
+fn generate(data: &[u8], size: u32, format: Format) -> Result<Vec<u8>> {
+  let mut image = image::load_from_memory(data)?;
+  if image.width() > size || image.height() > size {
+    image = image.resize(size, size, FilterType::Lanczos3);
+  }
+  let mut output = Vec::new();
+  match format {
+    // JPEG has no transparency
+    Format::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+      .write_with_encoder(JpegEncoder::new_with_quality(&mut output, JPEG_QUALITY))?,
+    Format::Webp => DynamicImage::ImageRgba8(image.to_rgba8())
+      .write_with_encoder(WebPEncoder::new_lossless(&mut output))?,
+  }
+  Ok(output)
+}
+
+pub struct Cache {
+  folder: PathBuf,
+  // In bytes
+  max_size: u64,
+}
+
+impl Cache {
+  pub fn new(folder: PathBuf, max_size: u64) -> Cache {
+    Cache { folder, max_size }
+  }
+
+  // The thumbnail of the cover `cover_id` if it was already generated
+  pub fn cached(&self, cover_id: &str, size: u32, format: Format) -> Result<Option<Vec<u8>>> {
+    let path = self.folder.join(thumbnail_name(cover_id, size, format));
+    match fs::read(&path) {
+      Ok(data) => {
+        // The modification time tells which thumbnails were used recently
+        let touched = fs::File::options()
+          .append(true)
+          .open(&path)
+          .and_then(|file| file.set_modified(SystemTime::now()));
+        if let Err(e) = touched {
+          tracing::debug!("cannot touch {} ({})", path.display(), e);
+        }
+        Ok(Some(data))
+      }
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  // The thumbnail of `cover`, from the cache if it was already generated
+  pub fn thumbnail(&self, cover: &Cover, size: u32, format: Format) -> Result<Vec<u8>> {
+    if let Some(data) = self.cached(&cover.id, size, format)? {
+      return Ok(data);
+    }
+    let path = self.folder.join(thumbnail_name(&cover.id, size, format));
+    let data = generate(&cover.data, size, format)?;
+    fs::create_dir_all(&self.folder)?;
+    // Concurrent requests must never read a partially written thumbnail
+    let counter = TEMPORARY_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let temporary = path.with_extension(format!("{}.tmp", counter));
+    fs::write(&temporary, &data)?;
+    fs::rename(&temporary, &path)?;
+    if let Err(e) = self.prune(|_| true) {
+      tracing::warn!("cannot prune the thumbnail cache ({:#})", e);
+    }
+    Ok(data)
+  }
+
+  // Remove the thumbnails of the covers for which `keep` is false, then the
+  // least recently used ones until the cache fits in its maximum size. Returns
+  // the number of thumbnails removed and the number of bytes freed.
+  pub fn prune(&self, keep: impl Fn(&str) -> bool) -> Result<(usize, u64)> {
+    let entries = match fs::read_dir(&self.folder) {
+      Ok(entries) => entries,
+      // Nothing was cached yet
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, 0)),
+      Err(e) => return Err(e.into()),
+    };
+    let mut thumbnails = Vec::new();
+    for entry in entries {
+      let entry = entry?;
+      let name = entry.file_name().to_string_lossy().to_string();
+      // Files being written end with .tmp and are left alone
+      let Some((cover_id, _)) = name
+        .rsplit_once('-')
+        .filter(|_| name.ends_with(".jpg") || name.ends_with(".webp"))
+      else {
+        continue;
+      };
+      // Another request may be pruning the cache at the same time
+      let Ok(metadata) = entry.metadata() else {
+        continue;
+      };
+      let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+      thumbnails.push((keep(cover_id), modified, metadata.len(), entry.path()));
+    }
+    // The ones to remove first come first
+    thumbnails.sort_by_key(|(keep, modified, _, _)| (*keep, *modified));
+    let mut total = thumbnails.iter().map(|(_, _, len, _)| len).sum::<u64>();
+    let (mut count, mut freed) = (0, 0);
+    for (keep, _, len, path) in thumbnails {
+      if keep && total <= self.max_size {
+        break;
+      }
+      match fs::remove_file(&path) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+      }
+      tracing::debug!("{} removed from the thumbnail cache", path.display());
+      total -= len;
+      count += 1;
+      freed += len;
+    }
+    Ok((count, freed))
+  }
+}
@@ -0,0 +1,255 @@
+// Browsing by era: the years and decades of the songs, with the albums having
+// songs from them. An album whose songs span several years is in each of them.
+// A decade is named after its first year, 1990 for the years 1990 to 1999.
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlite::{Connection, Value};
+
+use crate::albums::Album;
+use crate::sorting::{SortBy, SortKey, SortParams};
+use crate::{execute_query, pagination, parse_column};
+
+// The ids of the albums with songs from the years in a range, bounds included
+const PERIOD_ALBUM_IDS: &str = "SELECT album_id FROM songs \
+  WHERE year BETWEEN ? AND ? AND album_id IS NOT NULL AND missing_since IS NULL";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum YearSort {
+  #[default]
+  Year,
+  SongCount,
+  AlbumCount,
+}
+
+impl SortKey for YearSort {
+  fn is_name(self) -> bool {
+    false
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+  Year,
+  Decade,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Year {
+  pub year: i32,
+  pub song_count: u32,
+  pub album_count: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Decade {
+  pub decade: i32,
+  pub song_count: u32,
+  pub album_count: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct YearWithAlbums {
+  #[serde(flatten)]
+  pub year: Year,
+  pub albums: pagination::Page<Album>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DecadeWithAlbums {
+  #[serde(flatten)]
+  pub decade: Decade,
+  pub albums: pagination::Page<Album>,
+}
+
+impl Period {
+  // The period of the year column, as its first year
+  fn start(self) -> &'static str {
+    match self {
+      Period::Year => "year",
+      Period::Decade => "year / 10 * 10",
+    }
+  }
+
+  // The first and last years of the period starting at `start`, None if no
+  // period starts there
+  fn years(self, start: i32) -> Option<(i32, i32)> {
+    match self {
+      Period::Year => Some((start, start)),
+      Period::Decade if start % 10 == 0 => start.checked_add(9).map(|end| (start, end)),
+      Period::Decade => None,
+    }
+  }
+
+  // A page of the albums with songs from the period starting at `start`, and
+  // their total
+  pub fn albums(
+    self,
+    connection: &Connection,
+    start: i32,
+    sort: &SortParams<SortBy>,
+    offset: u32,
+    limit: u32,
+  ) -> Result<(Vec<Album>, u64)> {
+    let Some((first, last)) = self.years(start) else {
+      return Ok((Vec::new(), 0));
+    };
+    let values = [Value::Integer(first as i64), Value::Integer(last as i64)];
+    let albums = Album::get_matching_with_pagination(
+      connection,
+      PERIOD_ALBUM_IDS,
+      &values,
+      sort,
+      offset,
+      limit,
+    )?;
+    Ok((albums, Album::count_matching(connection, PERIOD_ALBUM_IDS, &values)?))
+  }
+}
+
+// A period with its song and album counts
+struct Counts {
+  start: i32,
+  song_count: u32,
+  album_count: u32,
+}
+
+impl From<Counts> for Year {
+  fn from(counts: Counts) -> Year {
+    Year {
+      year: counts.start,
+      song_count: counts.song_count,
+      album_count: counts.album_count,
+    }
+  }
+}
+
+impl From<Counts> for Decade {
+  fn from(counts: Counts) -> Decade {
+    Decade {
+      decade: counts.start,
+      song_count: counts.song_count,
+      album_count: counts.album_count,
+    }
+  }
+}
+
+impl Counts {
+  fn from_row(row: &HashMap<String, String>) -> Result<Counts> {
+    Ok(Counts {
+      start: parse_column(row, "songs", "start")?,
+      song_count: parse_column(row, "songs", "song_count")?,
+      album_count: parse_column(row, "songs", "album_count")?,
+    })
+  }
+
+  fn select(connection: &Connection, query: &str, values: &[Value]) -> Result<Vec<Counts>> {
+    execute_query(connection, query, values)?
+      .iter()
+      .map(Counts::from_row)
+      .collect()
+  }
+}
+
+fn columns(period: Period) -> String {
+  format!(
+    "{} AS start, COUNT(*) AS song_count, COUNT(DISTINCT album_id) AS album_count",
+    period.start()
+  )
+}
+
+fn get<T: From<Counts>>(connection: &Connection, period: Period, start: i32) -> Result<Option<T>> {
+  let Some((first, last)) = period.years(start) else {
+    return Ok(None);
+  };
+  let query = format!(
+    "SELECT {} FROM songs WHERE year BETWEEN ? AND ? AND missing_since IS NULL GROUP BY start;",
+    columns(period)
+  );
+  let values = [Value::Integer(first as i64), Value::Integer(last as i64)];
+  Ok(
+    Counts::select(connection, &query, &values)?
+      .pop()
+      .map(T::from),
+  )
+}
+
+fn count(connection: &Connection, period: Period) -> Result<u64> {
+  let query = format!(
+    "SELECT COUNT(DISTINCT {}) AS count FROM songs \
+     WHERE year IS NOT NULL AND missing_since IS NULL;",
+    period.start()
+  );
+  pagination::count(connection, &query, &[])
+}
+
+fn get_all_with_pagination<T: From<Counts>>(
+  connection: &Connection,
+  period: Period,
+  sort: &SortParams<YearSort>,
+  offset: u32,
+  limit: u32,
+) -> Result<Vec<T>> {
+  let order_by = sort.order_by(
+    |sort| match sort {
+      YearSort::Year => "start",
+      YearSort::SongCount => "song_count",
+      YearSort::AlbumCount => "album_count",
+    },
+    YearSort::Year,
+    "start",
+  );
+  let query = format!(
+    "SELECT {} FROM songs WHERE year IS NOT NULL AND missing_since IS NULL \
+     GROUP BY start ORDER BY {} LIMIT ? OFFSET ?;",
+    columns(period),
+    order_by
+  );
+  let values = [Value::Integer(limit as i64), Value::Integer(offset as i64)];
+  Ok(
+    Counts::select(connection, &query, &values)?
+      .into_iter()
+      .map(T::from)
+      .collect(),
+  )
+}
+
+impl Year {
+  pub fn get(connection: &Connection, year: i32) -> Result<Option<Year>> {
+    get(connection, Period::Year, year)
+  }
+
+  pub fn count(connection: &Connection) -> Result<u64> {
+    count(connection, Period::Year)
+  }
+
+  pub fn get_all_with_pagination(
+    connection: &Connection,
+    sort: &SortParams<YearSort>,
+    offset: u32,
+    limit: u32,
+  ) -> Result<Vec<Year>> {
+    get_all_with_pagination(connection, Period::Year, sort, offset, limit)
+  }
+}
+
+impl Decade {
+  pub fn get(connection: &Connection, decade: i32) -> Result<Option<Decade>> {
+    get(connection, Period::Decade, decade)
+  }
+
+  pub fn count(connection: &Connection) -> Result<u64> {
+    count(connection, Period::Decade)
+  }
+
+  pub fn get_all_with_pagination(
+    connection: &Connection,
+    sort: &SortParams<YearSort>,
+    offset: u32,
+    limit: u32,
+  ) -> Result<Vec<Decade>> {
+    get_all_with_pagination(connection, Period::Decade, sort, offset, limit)
+  }
+}